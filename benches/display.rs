@@ -0,0 +1,95 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use iced::Color;
+
+// The emulator is a single binary, so the display and the span macro it uses
+// are compiled into the benchmark directly.
+#[allow(dead_code)]
+#[path = "../src/spans.rs"]
+mod spans;
+
+// Its unit tests are left out of the benchmark, along with their imports.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/display.rs"]
+mod display;
+
+use display::{Display, PixelShape};
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+// Digits of the small font, and sprites of the full 15 rows that DXYN can
+// draw. Each is drawn at every column, so that some wrap around the right
+// edge.
+const DIGITS: [&[u8]; 4] = [
+    &[0xF0, 0x90, 0x90, 0x90, 0xF0],
+    &[0x20, 0x60, 0x20, 0x20, 0x70],
+    &[0xF0, 0x10, 0xF0, 0x80, 0xF0],
+    &[0xF0, 0x10, 0xF0, 0x10, 0xF0],
+];
+const TALL: [&[u8]; 2] = [&[0xAA; 15], &[0x3C; 15]];
+
+// The framebuffer as it was before the bitset: one bool per pixel, drawn
+// one pixel at a time.
+struct BoolMatrix {
+    at: [[bool; WIDTH]; HEIGHT],
+}
+
+impl BoolMatrix {
+    fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+        let mut collision = false;
+        for (offset_y, line) in sprite.iter().enumerate() {
+            let wrapped_y = (y as usize + offset_y) % HEIGHT;
+            for offset_x in 0..8 {
+                let wrapped_x = (x as usize + offset_x) % WIDTH;
+                let old = self.at[wrapped_y][wrapped_x];
+                let new = (line >> (7 - offset_x)) % 2 == 1;
+                self.at[wrapped_y][wrapped_x] = old ^ new;
+                if old && new {
+                    collision = true;
+                }
+            }
+        }
+        collision
+    }
+}
+
+fn draw_sprites(c: &mut Criterion, name: &str, sprites: &[&[u8]]) {
+    let mut group = c.benchmark_group(name);
+
+    group.bench_function("bool_matrix", |b| {
+        let mut matrix = BoolMatrix {
+            at: [[false; WIDTH]; HEIGHT],
+        };
+        b.iter(|| {
+            for x in 0..WIDTH as u8 {
+                for (index, sprite) in sprites.iter().enumerate() {
+                    black_box(matrix.draw_sprite(x, index as u8 * 8, black_box(sprite)));
+                }
+            }
+        })
+    });
+
+    group.bench_function("bitset", |b| {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        b.iter(|| {
+            for x in 0..WIDTH as u8 {
+                for (index, sprite) in sprites.iter().enumerate() {
+                    black_box(display.draw_sprite(x, index as u8 * 8, black_box(sprite)));
+                }
+            }
+        })
+    });
+
+    group.finish();
+}
+
+fn draw_digits(c: &mut Criterion) {
+    draw_sprites(c, "draw_digits", &DIGITS);
+}
+
+fn draw_tall_sprites(c: &mut Criterion) {
+    draw_sprites(c, "draw_tall_sprites", &TALL);
+}
+
+criterion_group!(benches, draw_digits, draw_tall_sprites);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+-- Taps the keys 1, 2, ..., F, 0 in turn, holding each for half a second.
+-- Try it with: cargo run --features scripting -- --demo keypad --script scripts/autopress.lua
+
+local frames = 0
+local key = 0
+
+function on_frame()
+    frames = frames + 1
+    if frames % 30 == 0 then
+        release(key)
+        key = (key + 1) % 16
+        press(key)
+    end
+end
@@ -0,0 +1,399 @@
+use crate::instruction::{decode, decode_xochip, Instruction};
+use crate::memory::MEMORY_SIZE;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+const PROGRAM_START: u16 = 0x200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    SchipOpcodes(Vec<u16>),
+    XoChipOpcodes(Vec<u16>),
+    ShiftAfterLoad { addr: u16, opcode: u16 },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::SchipOpcodes(opcodes) => write!(
+                f,
+                "uses SCHIP opcodes ({}) - run with --profile superchip",
+                join_opcodes(opcodes)
+            ),
+            Finding::XoChipOpcodes(opcodes) => write!(
+                f,
+                "uses XO-CHIP opcodes ({}) - run with --profile xochip",
+                join_opcodes(opcodes)
+            ),
+            Finding::ShiftAfterLoad { addr, opcode } => write!(
+                f,
+                "uses {:04X} at {:04X} right after loading VY - likely expects the original shift quirk of --quirk-shift",
+                opcode, addr
+            ),
+        }
+    }
+}
+
+fn join_opcodes(opcodes: &[u16]) -> String {
+    let names: Vec<_> = opcodes
+        .iter()
+        .map(|opcode| format!("{:04X}", opcode))
+        .collect();
+    names.join(", ")
+}
+
+// Walks the instructions reachable from the entry point, following both
+// sides of every skip. Jumps through BNNN cannot be resolved statically, so
+// the walk stops there.
+pub fn analyze(rom: &[u8]) -> Vec<Finding> {
+    let fetch = |addr: u16| {
+        let offset = addr.checked_sub(PROGRAM_START)? as usize;
+        let bytes = rom.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    let mut schip = Vec::new();
+    let mut xochip = Vec::new();
+    let mut shifts = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(PROGRAM_START, None)];
+    while let Some((addr, previous)) = pending.pop() {
+        if !visited.insert(addr) {
+            continue;
+        }
+        let opcode = match fetch(addr) {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        let next = addr.wrapping_add(2);
+        // The XO-CHIP decoder knows every variant, so nothing reachable is
+        // mistaken for data.
+        let instruction = match opcode {
+            0xF000 => fetch(next).map(Instruction::LdILong),
+            _ => decode_xochip(opcode),
+        };
+        let successors = match instruction {
+            Some(Instruction::Ret | Instruction::Exit | Instruction::JpV0(_)) => vec![],
+            Some(Instruction::Jp(target)) => vec![target],
+            Some(Instruction::Call(target)) => vec![target, next],
+            Some(
+                Instruction::SeVx(..)
+                | Instruction::SneVx(..)
+                | Instruction::SeVxVy(..)
+                | Instruction::SneVxVy(..)
+                | Instruction::Skp(_)
+                | Instruction::Sknp(_),
+            ) => {
+                // Skips hop over the whole of XO-CHIP's four-byte F000 NNNN.
+                let length = if fetch(next) == Some(0xF000) { 4 } else { 2 };
+                vec![next, next.wrapping_add(length)]
+            }
+            Some(Instruction::LdILong(_)) => vec![next.wrapping_add(2)],
+            _ => vec![next],
+        };
+
+        match (extension_of(opcode), instruction) {
+            (Some(Extension::Schip), _) => schip.push(opcode),
+            (Some(Extension::XoChip), _) => xochip.push(opcode),
+            (None, Some(Instruction::Shr(x, y) | Instruction::Shl(x, y)))
+                if x != y && previous.is_some_and(|p| loads(p, y)) =>
+            {
+                shifts.push((addr, opcode))
+            }
+            _ => {}
+        }
+
+        for successor in successors {
+            pending.push((successor, instruction));
+        }
+    }
+
+    let mut findings = Vec::new();
+    if !schip.is_empty() {
+        findings.push(Finding::SchipOpcodes(dedup_sorted(schip)));
+    }
+    if !xochip.is_empty() {
+        findings.push(Finding::XoChipOpcodes(dedup_sorted(xochip)));
+    }
+    shifts.sort_unstable();
+    findings.extend(
+        shifts
+            .into_iter()
+            .map(|(addr, opcode)| Finding::ShiftAfterLoad { addr, opcode }),
+    );
+    findings
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Extension {
+    Schip,
+    XoChip,
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extension::Schip => write!(f, "SCHIP"),
+            Extension::XoChip => write!(f, "XO-CHIP"),
+        }
+    }
+}
+
+// Some extension opcodes fall into CHIP-8 families (00FF into SYS, DXY0 into
+// DRW), so they are told apart by the XO-CHIP decoder.
+fn extension_of(opcode: u16) -> Option<Extension> {
+    match decode_xochip(opcode) {
+        Some(
+            Instruction::Exit
+            | Instruction::Low
+            | Instruction::High
+            | Instruction::Scd(_)
+            | Instruction::Scr
+            | Instruction::Scl
+            | Instruction::LdRVx(_)
+            | Instruction::LdVxR(_)
+            | Instruction::LdHf(_)
+            | Instruction::Drw(_, _, 0),
+        ) => Some(Extension::Schip),
+        Some(
+            Instruction::Plane(_)
+            | Instruction::Audio
+            | Instruction::Pitch(_)
+            | Instruction::Scu(_),
+        ) => Some(Extension::XoChip),
+        Some(_) => None,
+        // F000 takes its operand from the next word, and the register range
+        // stores 5XY2 and 5XY3 are not run by this emulator.
+        None if opcode == 0xF000 || opcode & 0xF00E == 0x5002 => Some(Extension::XoChip),
+        None => None,
+    }
+}
+
+// Every aligned word of the ROM as if it were code. Data decodes as
+// anything, so the report is a list of suspicions rather than errors.
+pub struct Validation {
+    recognized: BTreeMap<&'static str, usize>,
+    unknown: Vec<(u16, u16, Option<Extension>)>,
+    stray_targets: Vec<(u16, Instruction)>,
+}
+
+impl Validation {
+    pub fn has_unknown(&self) -> bool {
+        !self.unknown.is_empty()
+    }
+}
+
+pub fn validate(rom: &[u8]) -> Validation {
+    let end = PROGRAM_START as usize + rom.len();
+    let mut validation = Validation {
+        recognized: BTreeMap::new(),
+        unknown: Vec::new(),
+        stray_targets: Vec::new(),
+    };
+    for (index, word) in rom.chunks_exact(2).enumerate() {
+        let addr = PROGRAM_START + 2 * index as u16;
+        let opcode = u16::from_be_bytes([word[0], word[1]]);
+        let instruction = match (extension_of(opcode), decode(opcode)) {
+            (None, Some(instruction)) => instruction,
+            (extension, _) => {
+                validation.unknown.push((addr, opcode, extension));
+                continue;
+            }
+        };
+        *validation
+            .recognized
+            .entry(instruction.family())
+            .or_insert(0) += 1;
+
+        // BNNN can reach up to 0xFF past NNN, which may run off the end of
+        // memory.
+        let outside = match instruction {
+            Instruction::Jp(target) | Instruction::Call(target) => {
+                target < PROGRAM_START || target as usize >= end
+            }
+            Instruction::JpV0(target) => target as usize + 0xFF >= MEMORY_SIZE,
+            _ => false,
+        };
+        if outside {
+            validation.stray_targets.push((addr, instruction));
+        }
+    }
+    validation
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recognized: usize = self.recognized.values().sum();
+        writeln!(
+            f,
+            "{} words: {} recognized, {} unknown (data decodes as anything, so these are only warnings)",
+            recognized + self.unknown.len(),
+            recognized,
+            self.unknown.len()
+        )?;
+        for (family, count) in &self.recognized {
+            writeln!(f, "{} {}", family, count)?;
+        }
+        for (addr, opcode, extension) in &self.unknown {
+            match extension {
+                Some(extension) => writeln!(
+                    f,
+                    "warning: {} opcode {:04X} at {:04X}",
+                    extension, opcode, addr
+                )?,
+                None => writeln!(f, "warning: unknown opcode {:04X} at {:04X}", opcode, addr)?,
+            }
+        }
+        for (addr, instruction) in &self.stray_targets {
+            writeln!(
+                f,
+                "warning: {} at {:04X} points outside the ROM",
+                instruction, addr
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn loads(instruction: Instruction, y: u8) -> bool {
+    matches!(
+        instruction,
+        Instruction::LdVx(x, _)
+            | Instruction::AddVx(x, _)
+            | Instruction::Rnd(x, _)
+            | Instruction::LdVxVy(x, _) if x == y
+    )
+}
+
+fn dedup_sorted(mut opcodes: Vec<u16>) -> Vec<u16> {
+    opcodes.sort_unstable();
+    opcodes.dedup();
+    opcodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo::DEMOS;
+
+    // Written for these tests, each needing one variant or quirk.
+    //
+    // scroller.ch8 draws a 16x16 sprite in 128x64 and scrolls it right:
+    //
+    //   0200: HIGH           0206: DRW V0 V0 0
+    //   0202: LD I 020C      0208: SCR
+    //   0204: LD V0 0        020A: JP 0208
+    //   020C: 16x16 sprite
+    //
+    // planes.ch8 draws a two-plane sprite from a long address and scrolls it
+    // up:
+    //
+    //   0200: PLANE 3              0208: DRW V0 V0 8
+    //   0202: LD I LONG 020E       020A: SCU 4
+    //   0206: LD V0 8              020C: JP 020A
+    //   020E: 8x8 sprite per plane
+    //
+    // halves.ch8 halves a constant with the original SHR, which shifts VY:
+    //
+    //   0200: LD V1 129      0204: LD F V0       0208: JP 0208
+    //   0202: SHR V0 V1      0206: DRW V2 V2 5
+    const SCROLLER: &[u8] = include_bytes!("../tests/fixtures/scroller.ch8");
+    const PLANES: &[u8] = include_bytes!("../tests/fixtures/planes.ch8");
+    const HALVES: &[u8] = include_bytes!("../tests/fixtures/halves.ch8");
+
+    #[test]
+    fn demos_need_nothing_special() {
+        for (name, rom) in DEMOS {
+            assert_eq!(analyze(rom), vec![], "{}", name);
+        }
+    }
+
+    #[test]
+    fn detect_schip() {
+        let findings = analyze(SCROLLER);
+
+        assert_eq!(
+            findings,
+            vec![Finding::SchipOpcodes(vec![0x00FB, 0x00FF, 0xD000])]
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "uses SCHIP opcodes (00FB, 00FF, D000) - run with --profile superchip"
+        );
+    }
+
+    #[test]
+    fn detect_xochip() {
+        let findings = analyze(PLANES);
+
+        assert_eq!(
+            findings,
+            vec![Finding::XoChipOpcodes(vec![0x00D4, 0xF000, 0xF301])]
+        );
+        assert_eq!(
+            findings[0].to_string(),
+            "uses XO-CHIP opcodes (00D4, F000, F301) - run with --profile xochip"
+        );
+    }
+
+    #[test]
+    fn detect_shift_after_load() {
+        assert_eq!(
+            analyze(HALVES),
+            vec![Finding::ShiftAfterLoad {
+                addr: 0x202,
+                opcode: 0x8016
+            }]
+        );
+    }
+
+    #[test]
+    fn skip_over_long_load() {
+        // 0200: SE V0 0, 0202: LD I LONG 00FF, 0206: JP 0206
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x00, 0xFF, 0x12, 0x06];
+
+        assert_eq!(analyze(&rom), vec![Finding::XoChipOpcodes(vec![0xF000])]);
+    }
+
+    #[test]
+    fn unreachable_data_ignored() {
+        // 0200: JP 0204, 0202: (data) 00FF, 0204: JP 0204
+        let rom = [0x12, 0x04, 0x00, 0xFF, 0x12, 0x04];
+
+        assert_eq!(analyze(&rom), vec![]);
+    }
+
+    #[test]
+    fn validate_every_word() {
+        // 0200: CLS, 0202: HIGH, 0204: SAVE VA-VB, 0206: CALL 0100,
+        // 0208: JP V0 0F80, 020A: (data) E000, 020C: JP 020C
+        let rom = [
+            0x00, 0xE0, 0x00, 0xFF, 0x5A, 0xB2, 0x21, 0x00, 0xBF, 0x80, 0xE0, 0x00, 0x12, 0x0C,
+        ];
+        let validation = validate(&rom);
+
+        assert!(validation.has_unknown());
+        assert_eq!(
+            validation.to_string(),
+            "7 words: 4 recognized, 3 unknown (data decodes as anything, so these are only warnings)
+00E0 1
+1NNN 1
+2NNN 1
+BNNN 1
+warning: SCHIP opcode 00FF at 0202
+warning: XO-CHIP opcode 5AB2 at 0204
+warning: unknown opcode E000 at 020A
+warning: CALL 0100 at 0206 points outside the ROM
+warning: JP V0 0F80 at 0208 points outside the ROM
+"
+        );
+    }
+
+    #[test]
+    fn demos_validate_cleanly() {
+        for (name, rom) in DEMOS {
+            assert!(!validate(rom).has_unknown(), "{}", name);
+        }
+    }
+}
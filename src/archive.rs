@@ -0,0 +1,150 @@
+use crate::memory::{check_rom_size, MAX_ROM_SIZE};
+use flate2::read::GzDecoder;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zip::ZipArchive;
+
+const ROM_EXTENSIONS: [&str; 3] = ["ch8", "c8b", "rom"];
+
+pub fn extract(file_name: &str, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    match extension_of(file_name).as_deref() {
+        Some("zip") => extract_zip(file_name, bytes),
+        Some("gz") => extract_gzip(file_name, &bytes),
+        _ => Ok(bytes),
+    }
+}
+
+fn extract_zip(file_name: &str, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    let corrupt =
+        |err: zip::result::ZipError| format!("Corrupt zip archive {}: {}", file_name, err);
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(corrupt)?;
+
+    let mut entries: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(String::from)
+        .collect();
+    entries.sort();
+    let roms: Vec<String> = entries
+        .iter()
+        .filter(|name| is_rom(name))
+        .cloned()
+        .collect();
+    let entry = match (roms.as_slice(), entries.as_slice()) {
+        ([rom], _) => rom.clone(),
+        ([], [only]) => only.clone(),
+        ([], []) => return Err(format!("No ROM found in {}", file_name)),
+        ([], candidates) | (candidates, _) => {
+            return Err(format!(
+                "Ambiguous ROM in {}, candidates: {}",
+                file_name,
+                candidates.join(", ")
+            ))
+        }
+    };
+
+    let entry = archive.by_name(&entry).map_err(corrupt)?;
+    decompress(file_name, "zip", entry)
+}
+
+fn extract_gzip(file_name: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    decompress(file_name, "gzip", GzDecoder::new(bytes))
+}
+
+// Stops one byte past the largest ROM, so that a huge entry fails the size
+// check without being inflated in full.
+fn decompress(file_name: &str, format: &str, reader: impl Read) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::new();
+    reader
+        .take(MAX_ROM_SIZE as u64 + 1)
+        .read_to_end(&mut rom)
+        .map_err(|err| format!("Corrupt {} archive {}: {}", format, file_name, err))?;
+    check_rom_size(file_name, &rom)?;
+    Ok(rom)
+}
+
+fn is_rom(name: &str) -> bool {
+    extension_of(name).is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.as_str()))
+}
+
+fn extension_of(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn gzip_of(content: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn plain_file_passes_through() {
+        let rom = extract("pong.ch8", vec![0x00, 0xE0]).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn zip_picks_rom_entry() {
+        let bytes = zip_of(&[("README.txt", b"Pong"), ("PONG.CH8", &[0x00, 0xE0])]);
+
+        let rom = extract("pong.zip", bytes).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn zip_picks_only_entry() {
+        let bytes = zip_of(&[("pong", &[0x00, 0xE0])]);
+
+        let rom = extract("pong.zip", bytes).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn reject_ambiguous_zip() {
+        let bytes = zip_of(&[("pong.ch8", &[0x00, 0xE0]), ("tetris.ch8", &[0x12, 0x00])]);
+
+        let err = extract("games.zip", bytes).unwrap_err();
+        assert!(err.contains("pong.ch8, tetris.ch8"));
+    }
+
+    #[test]
+    fn gzip_decompresses() {
+        let rom = extract("pong.ch8.gz", gzip_of(&[0x00, 0xE0])).unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn reject_oversized_entries() {
+        let bytes = zip_of(&[("huge.ch8", &[0x00; 64 * 1024])]);
+        let err = extract("huge.zip", bytes).unwrap_err();
+        assert!(err.starts_with("ROM too large: huge.zip"));
+
+        assert!(extract("huge.ch8.gz", gzip_of(&[0x00; MAX_ROM_SIZE + 1])).is_err());
+        assert!(extract("full.ch8.gz", gzip_of(&[0x00; MAX_ROM_SIZE])).is_ok());
+    }
+
+    #[test]
+    fn reject_corrupt_archives() {
+        assert!(extract("pong.zip", vec![0x50, 0x4B, 0x00]).is_err());
+        assert!(extract("pong.ch8.gz", vec![0x1F, 0x8B, 0x00]).is_err());
+    }
+}
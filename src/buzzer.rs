@@ -1,18 +1,52 @@
 use cpal::platform::Stream;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{default_host, Device, OutputCallbackInfo, Sample, SampleFormat, StreamConfig};
+use log::{debug, error, warn};
 use std::f32::consts::PI;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::Duration;
+
+const VOLUME: f32 = 0.2;
+const EVENT_QUEUE_SIZE: usize = 256;
+// How far the audio timeline runs behind the emulation, to absorb the bursts
+// in which the UI delivers timer ticks.
+const AUDIO_LATENCY: Duration = Duration::from_millis(40);
+// Transitions scheduled further ahead than this mean the emulated and the
+// audio clocks drifted apart, so the timeline is anchored again.
+const MAX_LEAD: Duration = Duration::from_millis(200);
+// XO-CHIP plays its 128-bit pattern at 4000 bits per second at pitch 64,
+// doubling every 48 steps.
+const DEFAULT_PITCH: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    at: Duration,
+    is_on: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneChange {
+    Pattern([u8; 16]),
+    Pitch(u8),
+}
 
 pub struct Buzzer {
-    _stream: Stream,
-    volume: Sender<f32>,
+    _stream: Option<Stream>,
+    events: Option<SyncSender<Transition>>,
+    tones: Option<SyncSender<ToneChange>>,
+    is_on: bool,
 }
 
 impl Buzzer {
     pub fn new() -> Self {
         let host = default_host();
-        let device = host.default_output_device().unwrap();
+        let device = match host.default_output_device() {
+            Some(device) => device,
+            None => {
+                warn!("No audio output device is available, running without sound");
+                return Buzzer::silent();
+            }
+        };
 
         let mut supported_configs_range = device.supported_output_configs().unwrap();
         let supported_config = supported_configs_range
@@ -22,42 +56,209 @@ impl Buzzer {
 
         let sample_format = supported_config.sample_format();
         let config: StreamConfig = supported_config.into();
+        debug!(
+            "Using the audio device {:?} with {:?} ({:?})",
+            device.name().unwrap_or_default(),
+            config,
+            sample_format
+        );
 
-        let (stream, send_volume) = match sample_format {
-            SampleFormat::F32 => run_stream::<f32>(device, config),
-            SampleFormat::I16 => run_stream::<i16>(device, config),
-            SampleFormat::U16 => run_stream::<u16>(device, config),
+        let (mut buzzer, events, tones) = Buzzer::connected();
+        let stream = match sample_format {
+            SampleFormat::F32 => run_stream::<f32>(device, config, events, tones),
+            SampleFormat::I16 => run_stream::<i16>(device, config, events, tones),
+            SampleFormat::U16 => run_stream::<u16>(device, config, events, tones),
         };
+        buzzer._stream = Some(stream);
+        buzzer
+    }
 
+    pub fn silent() -> Self {
         Buzzer {
-            _stream: stream,
-            volume: send_volume,
+            _stream: None,
+            events: None,
+            tones: None,
+            is_on: false,
+        }
+    }
+
+    // Without a stream, so that anything can stand in for the audio callback
+    // on the receiving ends.
+    pub fn connected() -> (Self, Receiver<Transition>, Receiver<ToneChange>) {
+        let (send_events, recv_events) = sync_channel(EVENT_QUEUE_SIZE);
+        let (send_tones, recv_tones) = sync_channel(EVENT_QUEUE_SIZE);
+        let buzzer = Buzzer {
+            _stream: None,
+            events: Some(send_events),
+            tones: Some(send_tones),
+            is_on: false,
+        };
+        (buzzer, recv_events, recv_tones)
+    }
+
+    // Only transitions are queued, stamped with the emulated time at which
+    // they happen.
+    pub fn set(&mut self, is_on: bool, at: Duration) {
+        if self.is_on == is_on {
+            return;
+        }
+        self.is_on = is_on;
+        if let Some(events) = &self.events {
+            if let Err(err) = events.try_send(Transition { at, is_on }) {
+                warn!("Dropped a buzzer transition: {}", err);
+            }
         }
     }
 
-    pub fn on(&self) {
-        self.volume.send(0.2).unwrap();
+    // The pattern replaces the 440 Hz tone from then on, and applies at once
+    // rather than on the emulated timeline.
+    pub fn set_pattern(&mut self, pattern: [u8; 16]) {
+        self.send_tone(ToneChange::Pattern(pattern));
     }
 
-    pub fn off(&self) {
-        self.volume.send(0.0).unwrap();
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.send_tone(ToneChange::Pitch(pitch));
+    }
+
+    fn send_tone(&mut self, change: ToneChange) {
+        if let Some(tones) = &self.tones {
+            if let Err(err) = tones.try_send(change) {
+                warn!("Dropped a buzzer tone change: {}", err);
+            }
+        }
     }
 }
 
-fn run_stream<T: Sample>(device: Device, config: StreamConfig) -> (Stream, Sender<f32>) {
-    let sample_rate = config.sample_rate.0 as f32;
-    let mut sample_clock = 0.0;
-    let mut volume = 0.0;
+// Generates the samples while the buzzer is on: a sine wave until a pattern
+// is loaded, then the pattern bits looped at the rate of the pitch.
+struct Voice {
+    tones: Receiver<ToneChange>,
+    sample_rate: f32,
+    sample_clock: f32,
+    pattern: Option<[u8; 16]>,
+    pitch: u8,
+    position: f32,
+}
 
-    let (send_volume, recv_volume): (Sender<f32>, Receiver<f32>) = channel();
+impl Voice {
+    fn new(tones: Receiver<ToneChange>, sample_rate: u32) -> Self {
+        Voice {
+            tones,
+            sample_rate: sample_rate as f32,
+            sample_clock: 0.0,
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+            position: 0.0,
+        }
+    }
 
-    let mut next_value = move || {
-        sample_clock = (sample_clock + 1.0) % sample_rate;
-        if let Ok(vol) = recv_volume.try_recv() {
-            volume = vol;
+    fn next_value(&mut self, is_on: bool) -> f32 {
+        while let Ok(change) = self.tones.try_recv() {
+            match change {
+                ToneChange::Pattern(pattern) => self.pattern = Some(pattern),
+                ToneChange::Pitch(pitch) => self.pitch = pitch,
+            }
         }
-        (sample_clock * 440.0 * 2.0 * PI / sample_rate).sin() * volume
-    };
+        self.sample_clock = (self.sample_clock + 1.0) % self.sample_rate;
+        if !is_on {
+            return 0.0;
+        }
+        match self.pattern {
+            None => (self.sample_clock * 440.0 * 2.0 * PI / self.sample_rate).sin() * VOLUME,
+            Some(pattern) => {
+                let bit = self.position as usize;
+                let rate = 4000.0 * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.0);
+                self.position = (self.position + rate / self.sample_rate) % 128.0;
+                if pattern[bit / 8] >> (7 - bit % 8) & 1 == 1 {
+                    VOLUME
+                } else {
+                    -VOLUME
+                }
+            }
+        }
+    }
+}
+
+// Converts the emulated timestamps of the transitions into sample offsets on
+// the audio clock. When the queue runs dry, the current level is held.
+struct Timeline {
+    events: Receiver<Transition>,
+    sample_rate: f64,
+    latency: u64,
+    max_lead: u64,
+    position: u64,
+    anchor: Option<(Duration, u64)>,
+    next: Option<(u64, bool)>,
+    is_on: bool,
+}
+
+impl Timeline {
+    fn new(events: Receiver<Transition>, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+        Timeline {
+            events,
+            sample_rate,
+            latency: (AUDIO_LATENCY.as_secs_f64() * sample_rate).round() as u64,
+            max_lead: (MAX_LEAD.as_secs_f64() * sample_rate).round() as u64,
+            position: 0,
+            anchor: None,
+            next: None,
+            is_on: false,
+        }
+    }
+
+    fn next_sample_is_on(&mut self) -> bool {
+        loop {
+            if self.next.is_none() {
+                match self.events.try_recv() {
+                    Ok(transition) => self.next = Some(self.schedule(transition)),
+                    Err(_) => break,
+                }
+            }
+            match self.next {
+                Some((sample, is_on)) if sample <= self.position => {
+                    self.is_on = is_on;
+                    self.next = None;
+                }
+                _ => break,
+            }
+        }
+        self.position += 1;
+        self.is_on
+    }
+
+    // A transition that would land in the past or too far ahead anchors the
+    // timeline again, one latency ahead of the current sample.
+    fn schedule(&mut self, transition: Transition) -> (u64, bool) {
+        let position = self.position;
+        let max_lead = self.max_lead;
+        let sample_rate = self.sample_rate;
+        let sample = self
+            .anchor
+            .and_then(|(at, sample)| {
+                let offset = transition.at.checked_sub(at)?;
+                Some(sample + (offset.as_secs_f64() * sample_rate).round() as u64)
+            })
+            .filter(|&sample| position <= sample && sample <= position + max_lead)
+            .unwrap_or_else(|| {
+                let sample = position + self.latency;
+                self.anchor = Some((transition.at, sample));
+                sample
+            });
+        (sample, transition.is_on)
+    }
+}
+
+fn run_stream<T: Sample>(
+    device: Device,
+    config: StreamConfig,
+    events: Receiver<Transition>,
+    tones: Receiver<ToneChange>,
+) -> Stream {
+    let mut timeline = Timeline::new(events, config.sample_rate.0);
+    let mut voice = Voice::new(tones, config.sample_rate.0);
+
+    let mut next_value = move || voice.next_value(timeline.next_sample_is_on());
 
     let data_callback = move |output: &mut [T], _: &OutputCallbackInfo| {
         for frame in output.chunks_mut(config.channels as usize) {
@@ -67,12 +268,99 @@ fn run_stream<T: Sample>(device: Device, config: StreamConfig) -> (Stream, Sende
             }
         }
     };
-    let err_callback = |error| panic!("{:?}", error);
+    let err_callback = |err| error!("Audio stream error: {:?}", err);
 
     let stream = device
         .build_output_stream(&config, data_callback, err_callback)
         .unwrap();
 
     stream.play().unwrap();
-    (stream, send_volume)
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline() -> (SyncSender<Transition>, Timeline) {
+        let (events, recv_events) = sync_channel(EVENT_QUEUE_SIZE);
+        (events, Timeline::new(recv_events, 1000))
+    }
+
+    fn transition(millis: u64, is_on: bool) -> Transition {
+        Transition {
+            at: Duration::from_millis(millis),
+            is_on,
+        }
+    }
+
+    fn levels(timeline: &mut Timeline, samples: usize) -> Vec<bool> {
+        (0..samples).map(|_| timeline.next_sample_is_on()).collect()
+    }
+
+    #[test]
+    fn transitions_land_on_exact_samples() {
+        let (events, mut timeline) = timeline();
+        events.send(transition(1000, true)).unwrap();
+        events.send(transition(1050, false)).unwrap();
+
+        let levels = levels(&mut timeline, 200);
+        assert_eq!(levels.iter().position(|&is_on| is_on), Some(40));
+        assert_eq!(levels.iter().filter(|&&is_on| is_on).count(), 50);
+    }
+
+    #[test]
+    fn level_held_when_queue_runs_dry() {
+        let (events, mut timeline) = timeline();
+        events.send(transition(0, true)).unwrap();
+        assert!(levels(&mut timeline, 1000)[40..].iter().all(|&is_on| is_on));
+
+        events.send(transition(1020, false)).unwrap();
+        let levels = levels(&mut timeline, 100);
+        assert_eq!(levels.iter().position(|&is_on| !is_on), Some(60));
+    }
+
+    #[test]
+    fn pattern_and_pitch_reach_the_voice() {
+        let (mut buzzer, _, tones) = Buzzer::connected();
+        let mut pattern = [0x00; 16];
+        pattern[0] = 0xF0;
+        buzzer.set_pattern(pattern);
+        buzzer.set_pitch(DEFAULT_PITCH + 48);
+        assert_eq!(tones.try_recv(), Ok(ToneChange::Pattern(pattern)));
+        assert_eq!(tones.try_recv(), Ok(ToneChange::Pitch(DEFAULT_PITCH + 48)));
+
+        // At 8000 bits per second, each of the 16000 samples covers half a bit.
+        let mut voice = Voice::new(tones, 16000);
+        buzzer.set_pattern(pattern);
+        buzzer.set_pitch(DEFAULT_PITCH + 48);
+        let values: Vec<_> = (0..10).map(|_| voice.next_value(true)).collect();
+        assert!(values[..8].iter().all(|&value| value == VOLUME));
+        assert!(values[8..].iter().all(|&value| value == -VOLUME));
+        assert_eq!(voice.next_value(false), 0.0);
+    }
+
+    #[test]
+    fn sine_until_a_pattern_is_loaded() {
+        let (_, _, tones) = Buzzer::connected();
+        let mut voice = Voice::new(tones, 1000);
+
+        let values: Vec<_> = (0..1000).map(|_| voice.next_value(true)).collect();
+        assert!(values.iter().any(|&value| 0.0 < value && value < VOLUME));
+        assert!(values.iter().all(|&value| value.abs() <= VOLUME));
+    }
+
+    #[test]
+    fn late_transition_reanchors() {
+        let (events, mut timeline) = timeline();
+        events.send(transition(0, true)).unwrap();
+        levels(&mut timeline, 500);
+
+        // Emulated time stood still, e.g. while catching up was capped.
+        events.send(transition(100, false)).unwrap();
+        events.send(transition(110, true)).unwrap();
+        let levels = levels(&mut timeline, 100);
+        assert_eq!(levels.iter().position(|&is_on| !is_on), Some(40));
+        assert_eq!(levels.iter().rposition(|&is_on| !is_on), Some(49));
+    }
 }
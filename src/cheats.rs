@@ -0,0 +1,206 @@
+use crate::memory::{Memory, MEMORY_SIZE};
+
+use iced::keyboard::{Event, KeyCode};
+use iced::Subscription;
+use iced_native::subscription::events_with;
+use iced_native::Event as NativeEvent;
+use log::info;
+use serde::Deserialize;
+
+const TOGGLE_KEYS: [KeyCode; 9] = [
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Cheat {
+    pub name: String,
+    pub address: u16,
+    pub value: u8,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct CheatFile {
+    cheat: Vec<Cheat>,
+}
+
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new(cheats: Vec<Cheat>) -> Self {
+        Cheats { cheats }
+    }
+
+    pub fn subscription(&self) -> Subscription<usize> {
+        events_with(|event, _status| match event {
+            NativeEvent::Keyboard(Event::KeyPressed {
+                key_code,
+                modifiers: _,
+            }) => TOGGLE_KEYS.iter().position(|key| *key == key_code),
+            _ => None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn apply(&self, memory: &mut Memory) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            memory.store(cheat.address, cheat.value);
+        }
+    }
+
+    pub fn toggle(&mut self, index: usize) {
+        if let Some(cheat) = self.cheats.get_mut(index) {
+            cheat.enabled = !cheat.enabled;
+            info!(
+                "Cheat \"{}\" {}",
+                cheat.name,
+                if cheat.enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    pub fn active_names(&self) -> Vec<&str> {
+        self.cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .map(|cheat| cheat.name.as_str())
+            .collect()
+    }
+}
+
+pub fn parse_poke(poke: &str) -> Result<Cheat, String> {
+    let (address, value) = poke
+        .split_once('=')
+        .ok_or(format!("Malformed poke (expected ADDR=VALUE): {}", poke))?;
+    let address = parse_number(address).ok_or(format!("Malformed address: {}", address))?;
+    let value = parse_number(value)
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or(format!("Malformed value: {}", value))?;
+    let cheat = Cheat {
+        name: poke.to_string(),
+        address,
+        value,
+        enabled: true,
+    };
+    validate(cheat)
+}
+
+pub fn parse_file(content: &str) -> Result<Vec<Cheat>, String> {
+    let file: CheatFile = toml::from_str(content).map_err(|err| err.to_string())?;
+    file.cheat.into_iter().map(validate).collect()
+}
+
+fn validate(cheat: Cheat) -> Result<Cheat, String> {
+    if MEMORY_SIZE <= cheat.address as usize {
+        return Err(format!(
+            "Cheat address out of memory: {:04X} ({})",
+            cheat.address, cheat.name
+        ));
+    }
+    Ok(cheat)
+}
+
+fn parse_number(number: &str) -> Option<u16> {
+    match number.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => number.parse().ok(),
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_poke() {
+        let cheat = parse_poke("0x6E3=0x05").unwrap();
+
+        assert_eq!(cheat.address, 0x6E3);
+        assert_eq!(cheat.value, 0x05);
+        assert!(cheat.enabled);
+    }
+
+    #[test]
+    fn parse_decimal_poke() {
+        let cheat = parse_poke("1763=5").unwrap();
+
+        assert_eq!(cheat.address, 0x6E3);
+        assert_eq!(cheat.value, 0x05);
+    }
+
+    #[test]
+    fn reject_malformed_pokes() {
+        assert!(parse_poke("0x6E3").is_err());
+        assert!(parse_poke("0x1000=0x05").is_err());
+        assert!(parse_poke("0x6E3=0x100").is_err());
+        assert!(parse_poke("lives=3").is_err());
+    }
+
+    #[test]
+    fn parse_cheat_file() {
+        let content = r#"
+            [[cheat]]
+            name = "Infinite lives"
+            address = 0x6E3
+            value = 5
+
+            [[cheat]]
+            name = "Max score"
+            address = 0x6E4
+            value = 0x99
+            enabled = false
+        "#;
+
+        let cheats = parse_file(content).unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats[0].name, "Infinite lives");
+        assert!(cheats[0].enabled);
+        assert_eq!(cheats[1].value, 0x99);
+        assert!(!cheats[1].enabled);
+    }
+
+    #[test]
+    fn reject_cheat_file_out_of_memory() {
+        let content = r#"
+            [[cheat]]
+            name = "Broken"
+            address = 0x1000
+            value = 1
+        "#;
+
+        assert!(parse_file(content).is_err());
+    }
+
+    #[test]
+    fn apply_only_enabled_cheats() {
+        let mut memory = Memory::with_rom(vec![], || 0x00);
+        let mut cheats = Cheats::new(vec![
+            parse_poke("0x300=0x05").unwrap(),
+            parse_poke("0x301=0x06").unwrap(),
+        ]);
+
+        cheats.toggle(1);
+        cheats.apply(&mut memory);
+        assert_eq!(memory.load(0x300), 0x05);
+        assert_eq!(memory.load(0x301), 0x00);
+        assert_eq!(cheats.active_names(), vec!["0x300=0x05"]);
+    }
+}
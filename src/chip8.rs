@@ -1,14 +1,50 @@
 use crate::buzzer::Buzzer;
-use crate::display::Display;
+use crate::cheats::{Cheat, Cheats};
+use crate::checksum::Fnv1a;
+use crate::display::{Display, PixelShape, Renderer};
+#[cfg(feature = "scripting")]
+use crate::display::{FRAME_HEIGHT, FRAME_WIDTH};
+use crate::instruction::{decode, decode_schip, decode_xochip, Instruction};
 use crate::keyboard::{Keyboard, KeyboardMessage};
-use crate::memory::Memory;
+use crate::memory::{InitPattern, Memory, MEMORY_SIZE};
+use crate::profiler::{CallProfile, CallReport, Coverage, CpuProfile, OpcodeProfile};
+use crate::quirks::Quirks;
+#[cfg(feature = "scripting")]
+use crate::ripper::write_png;
+use crate::ripper::SpriteRipper;
+use crate::rpl::{RplFlags, RPL_FLAGS};
+#[cfg(feature = "scripting")]
+use crate::script::{Hook, Script, ScriptHost};
+use crate::shutdown;
+use crate::spans::{self, span};
 
+use iced::keyboard::{Event as KeyEvent, KeyCode};
 use iced::time::every;
 use iced::{executor, Application, Clipboard, Color, Command, Element, Subscription};
-use log::{debug, trace};
-use rand::Rng;
+use iced_native::subscription::events_with;
+use iced_native::{window, Event as NativeEvent};
+use log::{debug, error, info, log_enabled, trace, warn, Level};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{stdout, ErrorKind, Stdout, Write};
+#[cfg(feature = "scripting")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 use std::time::{Duration, Instant};
 
+// The checksums and summaries that automated runs read, logged even when the
+// rest of the emulator is quiet.
+pub const REPORT_TARGET: &str = "chip8::report";
+
+const HISTORY_SIZE: usize = 64;
+const FRAME_PERIOD: Duration = Duration::from_micros(16_667);
+const TICK_RATE: f64 = 120.0;
+const NANOS_PER_KILOSEC: u128 = 1_000_000_000_000;
+
 struct Registers {
     v: [u8; 16],
     i: u16,
@@ -29,6 +65,46 @@ impl Registers {
     }
 }
 
+// What can be edited while paused, in the order Tab walks through them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+const FIELD_COUNT: usize = 21;
+const TERMINAL_LOOP_REPEATS: u32 = 4;
+
+impl Field {
+    fn at(index: usize) -> Self {
+        match index % FIELD_COUNT {
+            x @ 0..=15 => Field::V(x as u8),
+            16 => Field::I,
+            17 => Field::Pc,
+            18 => Field::Sp,
+            19 => Field::Dt,
+            _ => Field::St,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::V(x) => write!(f, "V{:X}", x),
+            Field::I => write!(f, "I"),
+            Field::Pc => write!(f, "PC"),
+            Field::Sp => write!(f, "SP"),
+            Field::Dt => write!(f, "DT"),
+            Field::St => write!(f, "ST"),
+        }
+    }
+}
+
 struct Timers {
     dt: u8,
     st: u8,
@@ -40,30 +116,309 @@ impl Timers {
     }
 }
 
+// Counts the ticks of a frequency that fell due. The frequency is kept in
+// millihertz and the budget in nanoseconds times that, so no rounding
+// accumulates and e.g. 50 ms at 60 Hz is exactly 3 ticks.
+struct Ticker {
+    millihertz: u128,
+    budget: u128,
+}
+
+impl Ticker {
+    fn new(hz: f64) -> Self {
+        Ticker {
+            millihertz: (hz * 1000.0).round() as u128,
+            budget: 0,
+        }
+    }
+
+    fn advance(&mut self, elapsed: Duration) -> u128 {
+        self.budget += elapsed.as_nanos() * self.millihertz;
+        let due = self.budget / NANOS_PER_KILOSEC;
+        self.budget %= NANOS_PER_KILOSEC;
+        due
+    }
+
+    fn restart(&mut self) {
+        self.budget = 0;
+    }
+
+    fn time_of(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((ticks as u128 * NANOS_PER_KILOSEC / self.millihertz) as u64)
+    }
+}
+
+// Each entry keeps the instruction as decoded under the quirks of the run.
+struct History {
+    entries: [(u16, u16, Option<Instruction>); HISTORY_SIZE],
+    next: usize,
+    len: usize,
+}
+
+impl History {
+    fn new() -> Self {
+        History {
+            entries: [(0x000, 0x0000, None); HISTORY_SIZE],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, pc: u16, opcode: u16, instruction: Option<Instruction>) {
+        self.entries[self.next] = (pc, opcode, instruction);
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        self.len = (self.len + 1).min(HISTORY_SIZE);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(u16, u16, Option<Instruction>)> {
+        let oldest = (self.next + HISTORY_SIZE - self.len) % HISTORY_SIZE;
+        (0..self.len).map(move |offset| &self.entries[(oldest + offset) % HISTORY_SIZE])
+    }
+}
+
+impl fmt::Display for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(pc, opcode, instruction) in self.iter() {
+            match instruction {
+                Some(instruction) => writeln!(f, "{:04X}: {:04X}  {}", pc, opcode, instruction)?,
+                None => writeln!(f, "{:04X}: {:04X}  ???", pc, opcode)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// FX0A completes only once the chosen key is released again, as on the
+// COSMAC VIP. Both states carry the destination register.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyWait {
+    Press(u8),
+    Release(u8, u8),
+}
+
+impl KeyWait {
+    fn register(&self) -> u8 {
+        match *self {
+            KeyWait::Press(x) | KeyWait::Release(x, _) => x,
+        }
+    }
+}
+
+// A backward jump seen again with the same state. Anything the loop could be
+// waiting on in between starts the count over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LoopWatch {
+    pc: u16,
+    state: u64,
+    repeats: u32,
+}
+
+// Draws, key and timer reads, random numbers and memory stores can all make
+// a loop that looks stable still go somewhere.
+fn breaks_loop_watch(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Cls
+            | Instruction::Drw(..)
+            | Instruction::Scd(_)
+            | Instruction::Scu(_)
+            | Instruction::Scr
+            | Instruction::Scl
+            | Instruction::Low
+            | Instruction::High
+            | Instruction::Skp(_)
+            | Instruction::Sknp(_)
+            | Instruction::LdVxK(_)
+            | Instruction::LdVxDt(_)
+            | Instruction::Rnd(..)
+            | Instruction::LdB(_)
+            | Instruction::LdIVx(_)
+    )
+}
+
 pub struct Chip8 {
+    rom: Vec<u8>,
+    ram_init: InitPattern,
+    reg_init: InitPattern,
     registers: Registers,
     timers: Timers,
+    history: History,
     memory: Memory,
     display: Display,
     keyboard: Keyboard,
     buzzer: Buzzer,
-    waiting_key_for: Option<u8>,
-    clock_speed: u64,
+    waiting_key: Option<KeyWait>,
+    wait_presses: u16,
+    waiting_vblank: bool,
+    last_tick: Option<Instant>,
+    max_catch_up: Duration,
+    instruction_clock: Ticker,
+    timer_clock: Ticker,
+    timer_ticks: u64,
+    key_buffer: Duration,
+    renderer: Renderer,
+    scale_factor: f64,
+    execute_with: fn(&mut Chip8, Instruction) -> Result<(), Chip8Error>,
+    cpu_profile: Option<CpuProfile>,
+    opcode_profile: Option<OpcodeProfile>,
+    opcode_profile_out: Option<PathBuf>,
+    coverage: Option<Coverage>,
+    call_profile: Option<CallProfile>,
+    sprite_ripper: Option<SpriteRipper>,
+    rng: StdRng,
+    cycles: u64,
+    checksum_every: Option<u64>,
+    max_cycles: Option<u64>,
+    frame_pipe: Option<Stdout>,
+    cheats: Cheats,
+    rpl_flags: RplFlags,
+    paranoid: bool,
+    strict_memory: bool,
+    trace_format: TraceFormat,
+    quirks: Quirks,
+    exit_on_terminal_loop: bool,
+    finished: bool,
+    loop_watch: Option<LoopWatch>,
+    ignored_sys: HashSet<u16>,
+    strict_alignment: bool,
+    misaligned_targets: HashSet<u16>,
+    wide_nibbles: HashSet<u16>,
+    stray_sprites: HashSet<u16>,
+    lint_vf: bool,
+    vf_written_at: Option<u16>,
+    vf_clobbers: HashSet<(u16, u16)>,
+    #[cfg(feature = "scripting")]
+    script: Option<Script>,
+    halted: Option<Chip8Error>,
+    paused: bool,
+    selected_field: usize,
+    break_opcode: Option<u16>,
+    exited: bool,
+}
+
+struct Snapshot {
+    v: [u8; 16],
+    i: u16,
+    sp: u8,
+    dt: u8,
+    st: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TraceFormat {
+    Full,
+    Delta,
+}
+
+// The faults that halt the machine. Each keeps the PC of the instruction
+// that caused it, which is left unexecuted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: u16, word: u16 },
+    PcOutOfRange { pc: u16 },
+    JumpOutOfRange { pc: u16, target: u16 },
+    MisalignedJump { pc: u16, target: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    MemoryOutOfRange { pc: u16, addr: u16, len: u16 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { pc, word } => {
+                write!(f, "bad opcode 0x{:04X} at 0x{:04X}", word, pc)
+            }
+            Chip8Error::PcOutOfRange { pc } => write!(f, "PC out of range: 0x{:04X}", pc),
+            Chip8Error::JumpOutOfRange { pc, target } => write!(
+                f,
+                "jump target out of range: 0x{:04X} at 0x{:04X}",
+                target, pc
+            ),
+            Chip8Error::MisalignedJump { pc, target } => write!(
+                f,
+                "misaligned jump target: 0x{:04X} at 0x{:04X}",
+                target, pc
+            ),
+            Chip8Error::StackOverflow { pc } => write!(f, "stack overflow at PC=0x{:04X}", pc),
+            Chip8Error::StackUnderflow { pc } => write!(f, "stack underflow at PC=0x{:04X}", pc),
+            Chip8Error::MemoryOutOfRange { pc, addr, len } => write!(
+                f,
+                "memory access out of range at PC=0x{:04X}: {} bytes from I=0x{:04X}",
+                pc, len, addr
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum Message {
-    Clock(Instant),
-    TickTimers(Instant),
+    Tick(Instant),
     FromDisplay,
     FromKeyboard(KeyboardMessage),
+    PipeFrame(Instant),
+    ToggleCheat(usize),
+    Reset,
+    TogglePause,
+    StepPaused,
+    SelectField(bool),
+    AdjustField(i16),
+    ClearField,
+    Exit,
+}
+
+// The emulator's own keys, kept off the keypad so that games never see them.
+fn hotkey(key_code: KeyCode, shift: bool) -> Option<Message> {
+    match key_code {
+        KeyCode::Backspace => Some(Message::Reset),
+        KeyCode::Space => Some(Message::TogglePause),
+        KeyCode::N => Some(Message::StepPaused),
+        KeyCode::Tab => Some(Message::SelectField(!shift)),
+        KeyCode::Up => Some(Message::AdjustField(1)),
+        KeyCode::Down => Some(Message::AdjustField(-1)),
+        KeyCode::PageUp => Some(Message::AdjustField(0x10)),
+        KeyCode::PageDown => Some(Message::AdjustField(-0x10)),
+        KeyCode::Delete => Some(Message::ClearField),
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
 pub struct Flags {
     pub rom: Vec<u8>,
-    pub clock_speed: u64,
+    pub clock_speed: f64,
+    pub timer_hz: u32,
+    pub key_buffer: Duration,
+    pub max_catch_up: Duration,
     pub display_color: Color,
+    pub renderer: Renderer,
+    pub pixel_shape: PixelShape,
+    pub scale_factor: f64,
+    pub cpu_profile: bool,
+    pub profile_opcodes: bool,
+    pub profile_opcodes_out: Option<PathBuf>,
+    pub coverage: bool,
+    pub profile_calls: Option<CallReport>,
+    pub rip_sprites: Option<PathBuf>,
+    pub checksum_every: Option<u64>,
+    pub max_cycles: Option<u64>,
+    pub break_opcode: Option<u16>,
+    pub paranoid: bool,
+    pub strict_memory: bool,
+    pub strict_alignment: bool,
+    pub lint_vf: bool,
+    pub trace_format: TraceFormat,
+    pub quirks: Quirks,
+    pub exit_on_terminal_loop: bool,
+    pub fast_boot: Option<u64>,
+    #[cfg(feature = "scripting")]
+    pub script: Option<String>,
+    pub pipe_frames: bool,
+    pub ram_init: InitPattern,
+    pub reg_init: InitPattern,
+    pub seed: Option<u64>,
+    pub cheats: Vec<Cheat>,
+    pub rpl_flags: Option<PathBuf>,
 }
 
 impl Application for Chip8 {
@@ -73,30 +428,59 @@ impl Application for Chip8 {
 
     fn new(flags: Self::Flags) -> (Chip8, Command<Self::Message>) {
         debug!("Initializing the emulator with flags: {:?}", flags);
-        (
-            Chip8 {
-                registers: Registers::new(),
-                timers: Timers::new(),
-                memory: Memory::with_rom(flags.rom),
-                display: Display::new(flags.display_color),
-                keyboard: Keyboard::new(),
-                buzzer: Buzzer::new(),
-                waiting_key_for: None,
-                clock_speed: flags.clock_speed,
-            },
-            Command::none(),
-        )
+        let fast_boot = flags.fast_boot;
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::new());
+        if let Some(cap) = fast_boot {
+            chip8.fast_boot(cap);
+        }
+        (chip8, Command::none())
     }
 
     fn title(&self) -> String {
-        String::from("CHIP-8 Emulator")
+        let mut title = String::from("CHIP-8 Emulator");
+        if self.waiting_key.is_some() {
+            title.push_str(" [waiting for key...]");
+        }
+        if let Some(reason) = &self.halted {
+            title.push_str(&format!(" [HALTED: {}]", reason));
+        }
+        if self.finished {
+            title.push_str(" [finished]");
+        }
+        if self.paused {
+            let field = Field::at(self.selected_field);
+            title.push_str(&format!(
+                " [paused] [{}=0x{:X}]",
+                field,
+                self.field_value(field)
+            ));
+        }
+        let active_cheats = self.cheats.active_names();
+        if !active_cheats.is_empty() {
+            title.push_str(&format!(" [cheats: {}]", active_cheats.join(", ")));
+        }
+        title
     }
 
     fn subscription(&self) -> Subscription<Message> {
         let keyboard = self.keyboard.subscription().map(Message::FromKeyboard);
-        let clock = every(Duration::from_millis(1000 / self.clock_speed)).map(Message::Clock);
-        let timer = every(Duration::from_millis(16)).map(Message::TickTimers);
-        Subscription::batch([keyboard, clock, timer])
+        let tick = every(period_of(TICK_RATE)).map(Message::Tick);
+        let window = events_with(|event, _status| match event {
+            NativeEvent::Window(window::Event::CloseRequested) => Some(Message::Exit),
+            NativeEvent::Keyboard(KeyEvent::KeyPressed {
+                key_code,
+                modifiers,
+            }) => hotkey(key_code, modifiers.shift),
+            _ => None,
+        });
+        let mut subscriptions = vec![keyboard, tick, window];
+        if self.frame_pipe.is_some() {
+            subscriptions.push(every(FRAME_PERIOD).map(Message::PipeFrame));
+        }
+        if !self.cheats.is_empty() {
+            subscriptions.push(self.cheats.subscription().map(Message::ToggleCheat));
+        }
+        Subscription::batch(subscriptions)
     }
 
     fn update(
@@ -104,356 +488,2981 @@ impl Application for Chip8 {
         message: Self::Message,
         _clipboard: &mut Clipboard,
     ) -> Command<Self::Message> {
+        span!("update");
+        if shutdown::is_requested() {
+            self.exit();
+            process::exit(shutdown::EXIT_CODE);
+        }
         match message {
-            Message::Clock(_instant) => {
-                if self.waiting_key_for.is_none() {
-                    let b1 = self.memory.load(self.registers.pc);
-                    let b2 = self.memory.load(self.registers.pc + 1);
-                    self.execute(b1 >> 4, b1 & 0x0F, b2 >> 4, b2 & 0x0F);
-                }
-            }
-            Message::TickTimers(_instant) => {
-                if self.timers.dt > 0 {
-                    self.timers.dt -= 1;
-                }
-                if self.timers.st > 0 {
-                    self.buzzer.on();
-                    self.timers.st -= 1;
-                } else {
-                    self.buzzer.off();
-                }
+            Message::Tick(instant) => {
+                span!("tick", late_us = instant.elapsed().as_micros() as u64);
+                self.tick(instant);
             }
             Message::FromDisplay => {
                 // noop
             }
             Message::FromKeyboard(message) => {
-                if let (KeyboardMessage::Press(value), Some(x)) = (message, self.waiting_key_for) {
-                    self.registers.v[x as usize] = value;
-                    self.waiting_key_for = None;
+                self.on_keyboard(message);
+            }
+            Message::PipeFrame(_instant) => {
+                self.pipe_frame();
+            }
+            Message::ToggleCheat(index) => {
+                self.cheats.toggle(index);
+            }
+            Message::Reset => {
+                self.reset();
+            }
+            Message::TogglePause => {
+                self.paused = !self.paused;
+            }
+            Message::StepPaused => {
+                if self.paused {
+                    self.step();
+                }
+            }
+            Message::SelectField(forward) => {
+                if self.paused {
+                    let offset = if forward { 1 } else { FIELD_COUNT - 1 };
+                    self.selected_field = (self.selected_field + offset) % FIELD_COUNT;
+                }
+            }
+            Message::AdjustField(delta) if self.paused => {
+                let field = Field::at(self.selected_field);
+                let edited = match self.field_value(field).checked_add_signed(delta) {
+                    Some(value) => self.set_field(field, value),
+                    None if delta < 0 => Err(format!("{} cannot go below zero", field)),
+                    None => Err(format!("{} cannot go above 0xFFFF", field)),
+                };
+                if let Err(err) = edited {
+                    warn!("{}", err);
+                }
+            }
+            Message::ClearField if self.paused => {
+                if let Err(err) = self.set_field(Field::at(self.selected_field), 0) {
+                    warn!("{}", err);
                 }
-                self.keyboard.update(message);
+            }
+            // The arrow keys may be bound to CHIP-8 keys while running.
+            Message::AdjustField(_) | Message::ClearField => {}
+            Message::Exit => {
+                self.exit();
             }
         }
         Command::none()
     }
 
-    fn view(&mut self) -> Element<Self::Message> {
-        self.display.view().map(|_| Message::FromDisplay)
+    fn view(&mut self) -> Element<'_, Self::Message> {
+        span!("view");
+        let view = match self.renderer {
+            Renderer::Canvas => self.display.view(),
+            Renderer::Image => self.display.view_image(),
+        };
+        view.map(|_| Message::FromDisplay)
+    }
+
+    fn scale_factor(&self) -> f64 {
+        self.scale_factor
+    }
+
+    fn should_exit(&self) -> bool {
+        self.exited
     }
 }
 
 impl Chip8 {
-    fn execute(&mut self, h1: u8, h2: u8, h3: u8, h4: u8) {
-        trace!(
-            "PC={:04X}, I={:04X}, v={:?}",
-            self.registers.pc,
-            self.registers.i,
-            self.registers.v,
-        );
-        match (h1, h2, h3, h4) {
-            (0x0, 0x0, 0xE, 0x0) => {
-                trace!("{:04X}: CLS", self.registers.pc);
-                self.display.clear();
-                self.registers.pc += 2;
-            }
+    fn with_buzzer(flags: Flags, buzzer: Buzzer) -> Self {
+        let mut rng = match flags.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
 
-            (0x0, 0x0, 0xE, 0xE) => {
-                trace!("{:04X}: RET", self.registers.pc);
-                self.registers.sp -= 1;
-                self.registers.pc = self.registers.stack[self.registers.sp as usize];
-                self.registers.pc += 2;
-            }
+        let mut registers = Registers::new();
+        for v in registers.v.iter_mut() {
+            *v = flags.reg_init.byte(&mut rng);
+        }
+        let memory = Memory::with_rom(flags.rom.clone(), || flags.ram_init.byte(&mut rng));
+        let mut display = Display::new(flags.display_color, flags.pixel_shape);
+        display.set_scale_factor(flags.scale_factor);
+        display.set_clip_sprites(flags.quirks.clip_sprites);
+        let sprite_ripper = flags
+            .rip_sprites
+            .map(|out_dir| SpriteRipper::new(out_dir, display.palette_rgba()));
+        let coverage = flags.coverage.then(|| Coverage::of_rom(&flags.rom));
 
-            (0x1, n1, n2, n3) => {
-                let addr = address_of(n1, n2, n3);
-                trace!("{:04X}: JP {:04X}", self.registers.pc, addr);
-                self.registers.pc = addr;
-            }
+        Chip8 {
+            rom: flags.rom,
+            ram_init: flags.ram_init,
+            reg_init: flags.reg_init,
+            registers,
+            timers: Timers::new(),
+            history: History::new(),
+            memory,
+            display,
+            keyboard: Keyboard::new(),
+            buzzer,
+            waiting_key: None,
+            wait_presses: 0,
+            waiting_vblank: false,
+            last_tick: None,
+            max_catch_up: flags.max_catch_up,
+            instruction_clock: Ticker::new(flags.clock_speed),
+            timer_clock: Ticker::new(flags.timer_hz as f64),
+            timer_ticks: 0,
+            key_buffer: flags.key_buffer,
+            renderer: flags.renderer,
+            scale_factor: flags.scale_factor,
+            execute_with: if flags.cpu_profile {
+                Chip8::execute_profiled
+            } else {
+                Chip8::execute
+            },
+            cpu_profile: flags.cpu_profile.then(CpuProfile::new),
+            opcode_profile: flags.profile_opcodes.then(OpcodeProfile::new),
+            opcode_profile_out: flags.profile_opcodes_out,
+            coverage,
+            call_profile: flags.profile_calls.map(CallProfile::new),
+            sprite_ripper,
+            rng,
+            cycles: 0,
+            checksum_every: flags.checksum_every,
+            max_cycles: flags.max_cycles,
+            frame_pipe: flags.pipe_frames.then(stdout),
+            cheats: Cheats::new(flags.cheats),
+            rpl_flags: match flags.rpl_flags {
+                Some(path) => RplFlags::open(path),
+                None => RplFlags::in_memory(),
+            },
+            paranoid: flags.paranoid,
+            strict_memory: flags.strict_memory,
+            trace_format: flags.trace_format,
+            quirks: flags.quirks,
+            exit_on_terminal_loop: flags.exit_on_terminal_loop,
+            finished: false,
+            loop_watch: None,
+            ignored_sys: HashSet::new(),
+            strict_alignment: flags.strict_alignment,
+            misaligned_targets: HashSet::new(),
+            wide_nibbles: HashSet::new(),
+            stray_sprites: HashSet::new(),
+            lint_vf: flags.lint_vf,
+            vf_written_at: None,
+            vf_clobbers: HashSet::new(),
+            #[cfg(feature = "scripting")]
+            script: flags.script.and_then(|file_name| {
+                Script::load(&file_name)
+                    .map_err(|err| error!("Failed to load the script {}: {}", file_name, err))
+                    .ok()
+            }),
+            halted: None,
+            paused: false,
+            selected_field: 0,
+            break_opcode: flags.break_opcode,
+            exited: false,
+        }
+    }
 
-            (0x2, n1, n2, n3) => {
-                let addr = address_of(n1, n2, n3);
-                trace!("{:04X}: CALL {:04X}", self.registers.pc, addr);
-                self.registers.stack[self.registers.sp as usize] = self.registers.pc;
-                self.registers.sp += 1;
-                self.registers.pc = addr
+    fn step(&mut self) {
+        span!("step");
+        if self.halted.is_some() || self.finished || self.exited {
+            return;
+        }
+        let pc = self.registers.pc;
+        if let Err(err) = self.check_pc() {
+            self.halt(err);
+            return;
+        }
+        let b1 = self.memory.load(pc);
+        let b2 = self.memory.load(pc + 1);
+        let opcode = u16::from_be_bytes([b1, b2]);
+        if self.break_opcode == Some(opcode) {
+            warn!("Breakpoint opcode hit at PC=0x{:04X}", pc);
+            self.registers.pc += 2;
+            self.paused = true;
+            return;
+        }
+        #[cfg(feature = "scripting")]
+        self.run_script(Hook::Instruction(pc, opcode));
+        let decoded = if self.quirks.xochip && opcode == 0xF000 {
+            // The address is the word after the opcode.
+            let addr = u16::from_be_bytes([self.memory.load(pc + 2), self.memory.load(pc + 3)]);
+            Some(Instruction::LdILong(addr))
+        } else if self.quirks.xochip {
+            decode_xochip(opcode)
+        } else if self.quirks.schip {
+            decode_schip(opcode)
+        } else {
+            decode(opcode)
+        };
+        self.history.push(pc, opcode, decoded);
+        let result = match decoded {
+            Some(instruction) => (self.execute_with)(self, instruction),
+            None => Err(Chip8Error::UnknownOpcode { pc, word: opcode }),
+        };
+        if let Err(err) = result {
+            self.halt(err);
+            return;
+        }
+        self.cheats.apply(&mut self.memory);
+        // Running off the end of memory halts before the next fetch rather
+        // than tripping the invariant check.
+        if let Err(err) = self.check_pc() {
+            self.halt(err);
+        } else if cfg!(debug_assertions) || self.paranoid {
+            if let Err(violation) = self.validate() {
+                panic!(
+                    "Invariant violated by {:04X} at {:04X}: {}\nInstruction history (oldest first):\n{}",
+                    opcode, pc, violation, self.history
+                );
             }
-
-            (0x3, x, k1, k2) => {
-                let value = value_of(k1, k2);
-                trace!("{:04X}: SE V{:X} {}", self.registers.pc, x, value);
-                if self.registers.v[x as usize] == value {
-                    self.registers.pc += 4;
-                } else {
-                    self.registers.pc += 2;
-                }
+        }
+        self.cycles += 1;
+        match decoded {
+            Some(Instruction::Jp(addr)) if addr < pc => self.watch_loop(pc),
+            Some(instruction) if breaks_loop_watch(instruction) => self.loop_watch = None,
+            _ => {}
+        }
+        if let Some(n) = self.checksum_every {
+            if self.cycles.is_multiple_of(n) {
+                info!(target: REPORT_TARGET, "{} {:016X}", self.cycles, self.checksum());
             }
+        }
+        if self.finished && self.exit_on_terminal_loop {
+            info!(target: REPORT_TARGET, "{} {:016X}", self.cycles, self.checksum());
+            self.exit();
+        }
+        if self.max_cycles == Some(self.cycles) {
+            info!(target: REPORT_TARGET, "{}", self.summary());
+            self.exit();
+        }
+    }
 
-            (0x4, x, k1, k2) => {
-                let value = value_of(k1, k2);
-                trace!("{:04X}: SNE V{:X} {}", self.registers.pc, x, value);
-                if self.registers.v[x as usize] != value {
-                    self.registers.pc += 4;
-                } else {
-                    self.registers.pc += 2;
-                }
-            }
+    fn validate(&self) -> Result<(), String> {
+        if 16 < self.registers.sp {
+            return Err(format!("SP out of the stack: {}", self.registers.sp));
+        }
+        if MEMORY_SIZE <= self.registers.pc as usize + 1 {
+            return Err(format!("PC out of memory: {:04X}", self.registers.pc));
+        }
+        // XO-CHIP addresses are 16 bits, wrapping around the memory for now.
+        if !self.quirks.xochip && MEMORY_SIZE <= self.registers.i as usize {
+            return Err(format!("I out of memory: {:04X}", self.registers.i));
+        }
+        if let Some(x) = self
+            .waiting_key
+            .map(|wait| wait.register())
+            .filter(|&x| 16 <= x)
+        {
+            return Err(format!("Waiting for a key into no register: V{}", x));
+        }
+        Ok(())
+    }
 
-            (0x5, x, y, 0x0) => {
-                trace!("{:04X}: SE V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                if vx == vy {
-                    self.registers.pc += 4;
-                } else {
-                    self.registers.pc += 2;
-                }
-            }
+    fn summary(&self) -> String {
+        let mut display_hash = Fnv1a::new();
+        display_hash.write(&self.display.packed());
+        format!(
+            "Stopped after {} instructions: PC={:04X}, I={:04X}, V={:02X?}, display {:016X}",
+            self.cycles,
+            self.registers.pc,
+            self.registers.i,
+            self.registers.v,
+            display_hash.finish()
+        )
+    }
 
-            (0x6, x, k1, k2) => {
-                let value = value_of(k1, k2);
-                trace!("{:04X}: LD V{:X} {}", self.registers.pc, x, value);
-                self.registers.v[x as usize] = value;
-                self.registers.pc += 2;
-            }
+    // Hash input, in this order: V0-VF, I (big-endian), PC (big-endian), SP,
+    // the 16 stack slots (big-endian), DT, ST, then the packed framebuffer.
+    // A loop that keeps jumping back from the same address with the same
+    // registers, timers and display has nothing left to do.
+    fn watch_loop(&mut self, pc: u16) {
+        let state = self.checksum();
+        let repeats = match self.loop_watch {
+            Some(watch) if watch.pc == pc && watch.state == state => watch.repeats + 1,
+            _ => 1,
+        };
+        self.loop_watch = Some(LoopWatch { pc, state, repeats });
+        if repeats == TERMINAL_LOOP_REPEATS {
+            info!(
+                "The ROM finished in a stable loop jumping back from {:04X}",
+                pc
+            );
+            self.finished = true;
+        }
+    }
 
-            (0x7, x, k1, k2) => {
-                let value = value_of(k1, k2);
-                trace!("{:04X}: ADD V{:X} {}", self.registers.pc, x, value);
-                let old = self.registers.v[x as usize];
-                self.registers.v[x as usize] = old.wrapping_add(value);
-                self.registers.pc += 2;
-            }
+    fn checksum(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&self.registers.v);
+        hasher.write(&self.registers.i.to_be_bytes());
+        hasher.write(&self.registers.pc.to_be_bytes());
+        hasher.write(&[self.registers.sp]);
+        for slot in self.registers.stack {
+            hasher.write(&slot.to_be_bytes());
+        }
+        hasher.write(&[self.timers.dt, self.timers.st]);
+        hasher.write(&self.display.packed());
+        hasher.finish()
+    }
 
-            (0x8, x, y, 0x0) => {
-                trace!("{:04X}: LD V{:X} V{:X}", self.registers.pc, x, y);
-                let vy = self.registers.v[y as usize];
-                self.registers.v[x as usize] = vy;
-                self.registers.pc += 2;
-            }
+    // Runs the instructions and timer ticks that fell due since the previous
+    // tick, so their rates follow the delivered instants rather than the
+    // subscription period.
+    fn tick(&mut self, now: Instant) {
+        let elapsed = match self.last_tick.replace(now) {
+            Some(last) => now.saturating_duration_since(last).min(self.max_catch_up),
+            None => return,
+        };
+        if self.paused {
+            return;
+        }
 
-            (0x8, x, y, 0x1) => {
-                trace!("{:04X}: OR V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                self.registers.v[x as usize] = vx | vy;
-                self.registers.pc += 2;
-            }
+        for _ in 0..self.timer_clock.advance(elapsed) {
+            self.tick_timers();
+        }
 
-            (0x8, x, y, 0x2) => {
-                trace!("{:04X}: AND V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                self.registers.v[x as usize] = vx & vy;
-                self.registers.pc += 2;
+        self.resolve_key_wait();
+        for _ in 0..self.instruction_clock.advance(elapsed) {
+            if self.waiting_key.is_some()
+                || self.waiting_vblank
+                || self.halted.is_some()
+                || self.finished
+                || self.exited
+                || self.paused
+            {
+                self.instruction_clock.restart();
+                break;
             }
+            self.step();
+        }
+    }
 
-            (0x8, x, y, 0x3) => {
-                trace!("{:04X}: XOR V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                self.registers.v[x as usize] = vx ^ vy;
-                self.registers.pc += 2;
+    // Runs instructions back to back, with the timers following emulated
+    // time, until the ROM first draws, waits for a key, or hits the cap.
+    fn fast_boot(&mut self, cap: u64) {
+        let step_time = self.instruction_clock.time_of(1);
+        while self.cycles < cap
+            && !self.display.has_drawn()
+            && self.waiting_key.is_none()
+            && self.halted.is_none()
+            && !self.finished
+            && !self.exited
+        {
+            self.step();
+            for _ in 0..self.timer_clock.advance(step_time) {
+                self.tick_timers();
             }
+        }
+        info!("Fast boot ran {} instructions", self.cycles);
+    }
 
-            (0x8, x, y, 0x4) => {
-                trace!("{:04X}: ADD V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                let (result, carry) = vx.overflowing_add(vy);
-                self.registers.v[x as usize] = result;
-                self.registers.v[0xF] = if carry { 0x01 } else { 0x00 };
-                self.registers.pc += 2;
-            }
+    fn tick_timers(&mut self) {
+        span!("tick_timers");
+        #[cfg(feature = "scripting")]
+        self.run_script(Hook::Frame);
+        self.waiting_vblank = false;
+        if self.timers.dt > 0 {
+            self.timers.dt -= 1;
+        }
+        let now = self.timer_clock.time_of(self.timer_ticks);
+        self.buzzer.set(self.timers.st > 0, now);
+        if self.timers.st > 0 {
+            self.timers.st -= 1;
+        }
+        self.timer_ticks += 1;
+    }
 
-            (0x8, x, y, 0x5) => {
-                trace!("{:04X}: SUB V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                let (result, bollow) = vx.overflowing_sub(vy);
-                self.registers.v[x as usize] = result;
-                self.registers.v[0xF] = if !bollow { 0x01 } else { 0x00 };
-                self.registers.pc += 2;
+    // A failing script is reported once and then switched off, so the
+    // emulator keeps running without it.
+    #[cfg(feature = "scripting")]
+    fn run_script(&mut self, hook: Hook) {
+        if let Some(script) = self.script.take() {
+            match script.run(self, hook) {
+                Ok(()) => self.script = Some(script),
+                Err(err) => error!("Disabled the script after an error: {}", err),
             }
+        }
+    }
 
-            (0x8, x, _y, 0x6) => {
-                trace!("{:04X}: SHR V{:X} {{V{:X}}}", self.registers.pc, x, _y);
-                let vx = self.registers.v[x as usize];
-                self.registers.v[0xF] = if vx % 2 == 1 { 0x01 } else { 0x00 };
-                self.registers.v[x as usize] = vx >> 1;
-                self.registers.pc += 2;
-            }
+    // The original interpreter shifted VY into VX, later ones shift VX in
+    // place. Either way VF receives the shifted-out bit last.
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        let source = if self.quirks.shift_from_vy { y } else { x };
+        self.registers.v[source as usize]
+    }
 
-            (0x8, x, y, 0x7) => {
-                trace!("{:04X}: SUBN V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                let (result, bollow) = vy.overflowing_sub(vx);
-                self.registers.v[x as usize] = result;
-                self.registers.v[0xF] = if !bollow { 0x01 } else { 0x00 };
-                self.registers.pc += 2;
-            }
+    // FX55 and FX65 leave I past the last register on the original
+    // interpreter, on the last one on CHIP-48, and alone on SCHIP.
+    fn i_increment(&self, x: u8) -> u16 {
+        if self.quirks.memory_increments_i {
+            x as u16 + 1
+        } else if self.quirks.memory_increments_i_by_x {
+            x as u16
+        } else {
+            0
+        }
+    }
 
-            (0x8, x, _y, 0xE) => {
-                trace!("{:04X}: SHL V{:X} {{V{:X}}}", self.registers.pc, x, _y);
-                let vx = self.registers.v[x as usize];
-                self.registers.v[0xF] = if (vx >> 7) % 2 == 1 { 0x01 } else { 0x00 };
-                self.registers.v[x as usize] = vx << 1;
-                self.registers.pc += 2;
-            }
+    // I wraps at 12 bits like the original interpreter's address arithmetic,
+    // or at 16 bits on XO-CHIP.
+    fn wrapped_i(&self, addr: u16) -> u16 {
+        if self.quirks.xochip {
+            addr
+        } else {
+            addr & 0x0FFF
+        }
+    }
 
-            (0x9, x, y, 0x0) => {
-                trace!("{:04X}: SNE V{:X} V{:X}", self.registers.pc, x, y);
-                let vx = self.registers.v[x as usize];
-                let vy = self.registers.v[y as usize];
-                if vx != vy {
-                    self.registers.pc += 4;
-                } else {
-                    self.registers.pc += 2;
-                }
-            }
+    fn skip_length(&self) -> u16 {
+        if self.quirks.long_skips {
+            skip_distance(&self.memory, self.registers.pc)
+        } else {
+            2
+        }
+    }
 
-            (0xA, n1, n2, n3) => {
-                let addr = address_of(n1, n2, n3);
-                trace!("{:04X}: LD I {:04X}", self.registers.pc, addr);
-                self.registers.i = addr;
-                self.registers.pc += 2;
-            }
+    fn field_value(&self, field: Field) -> u16 {
+        match field {
+            Field::V(x) => self.registers.v[x as usize] as u16,
+            Field::I => self.registers.i,
+            Field::Pc => self.registers.pc,
+            Field::Sp => self.registers.sp as u16,
+            Field::Dt => self.timers.dt as u16,
+            Field::St => self.timers.st as u16,
+        }
+    }
 
-            (0xB, n1, n2, n3) => {
-                let addr = address_of(n1, n2, n3);
-                trace!("{:04X}: JP V0 {:04X}", self.registers.pc, addr);
-                let v0 = self.registers.v[0x00];
-                self.registers.pc = addr + v0 as u16;
-            }
+    // Edits are only taken while paused, and are held to the same bounds
+    // as the invariant check so that resuming cannot trip it.
+    fn set_field(&mut self, field: Field, value: u16) -> Result<(), String> {
+        if !self.paused {
+            return Err(format!("Cannot edit {} while running", field));
+        }
+        let limit = match field {
+            Field::V(_) | Field::Dt | Field::St => 0xFF,
+            // XO-CHIP's I takes any 16-bit address, as F000 NNNN does.
+            Field::I if self.quirks.xochip => 0xFFFF,
+            Field::I => MEMORY_SIZE as u16 - 1,
+            Field::Pc => MEMORY_SIZE as u16 - 2,
+            Field::Sp => self.registers.stack.len() as u16,
+        };
+        if limit < value {
+            return Err(format!(
+                "{} out of range: 0x{:X} (at most 0x{:X})",
+                field, value, limit
+            ));
+        }
+        debug!("Setting {} to 0x{:X}", field, value);
+        match field {
+            Field::V(x) => self.registers.v[x as usize] = value as u8,
+            Field::I => self.registers.i = value,
+            Field::Pc => self.registers.pc = value,
+            Field::Sp => self.registers.sp = value as u8,
+            Field::Dt => self.timers.dt = value as u8,
+            Field::St => self.timers.st = value as u8,
+        }
+        Ok(())
+    }
 
-            (0xC, x, k1, k2) => {
-                let value = value_of(k1, k2);
-                trace!("{:04X}: RND V{:X} {}", self.registers.pc, x, value);
-                let mut rng = rand::thread_rng();
-                let random: u8 = rng.gen_range(0..0xFF);
-                self.registers.v[x as usize] = random & value;
-                self.registers.pc += 2;
+    // A value the ROM keeps in VF is lost when an arithmetic or DRW
+    // instruction writes its flag there before anything has read it.
+    fn check_vf_use(&mut self, instruction: Instruction) {
+        let pc = self.registers.pc;
+        if instruction.reads_v(0xF) {
+            self.vf_written_at = None;
+        }
+        if instruction.sets_flag() {
+            if let Some(written_at) = self.vf_written_at.take() {
+                if self.vf_clobbers.insert((written_at, pc)) {
+                    warn!(
+                        "VF set at 0x{:04X} is overwritten by the flag of {} at 0x{:04X} before being read",
+                        written_at, instruction, pc
+                    );
+                }
             }
+        } else if instruction.writes_v(0xF) {
+            self.vf_written_at = Some(pc);
+        }
+    }
 
-            (0xD, x, y, n) => {
-                let from = self.registers.i;
-                let sprite = &self.memory.load_sprite(from, n);
+    // Keys and font digits only go up to 0xF, and interpreters look at the
+    // low nibble of VX alone. Each PC that relies on it is reported once.
+    fn nibble_of(&mut self, x: u8) -> u8 {
+        let value = self.registers.v[x as usize];
+        if value > 0xF && self.wide_nibbles.insert(self.registers.pc) {
+            warn!(
+                "Using only the low nibble of V{:X}=0x{:02X} at 0x{:04X}",
+                x, value, self.registers.pc
+            );
+        }
+        value & 0xF
+    }
+
+    // The COSMAC VIP clobbered VF in OR, AND and XOR, even when VF was the
+    // destination.
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.registers.v[0xF] = 0x00;
+        }
+    }
+
+    // An instruction is two bytes, so the last one starts at 0x0FFE.
+    fn check_pc(&self) -> Result<(), Chip8Error> {
+        let pc = self.registers.pc;
+        if MEMORY_SIZE <= pc as usize + 1 {
+            return Err(Chip8Error::PcOutOfRange { pc });
+        }
+        Ok(())
+    }
+
+    // Odd targets are mostly bugs, though some ROMs jump into the middle of
+    // an instruction on purpose, so each one is only reported once.
+    fn check_target(&mut self, target: u16) -> Result<(), Chip8Error> {
+        let pc = self.registers.pc;
+        if MEMORY_SIZE <= target as usize + 1 {
+            return Err(Chip8Error::JumpOutOfRange { pc, target });
+        }
+        if target.is_multiple_of(2) {
+            return Ok(());
+        }
+        if self.strict_alignment {
+            return Err(Chip8Error::MisalignedJump { pc, target });
+        }
+        if self.misaligned_targets.insert(target) {
+            warn!(
+                "Jumping to the odd address 0x{:04X} at 0x{:04X}",
+                target, pc
+            );
+        }
+        Ok(())
+    }
+
+    // Outside strict memory, I-relative accesses wrap at the end of memory
+    // like the other loads and stores.
+    fn check_i_range(&self, len: u16) -> Result<(), Chip8Error> {
+        let addr = self.registers.i;
+        if self.strict_memory && MEMORY_SIZE < addr as usize + len as usize {
+            return Err(Chip8Error::MemoryOutOfRange {
+                pc: self.registers.pc,
+                addr,
+                len,
+            });
+        }
+        Ok(())
+    }
+
+    // Sprites past the end of memory wrap to its start, or are cut short
+    // in strict memory, so a bad LD I still draws what it can. Each PC that
+    // draws one, or reads past the font into the interpreter area, is
+    // reported once.
+    fn sprite_rows(&mut self, n: u8) -> u8 {
+        let i = self.registers.i;
+        let past_end = MEMORY_SIZE < i as usize + n as usize;
+        let stray = past_end || Memory::overlaps_interpreter_area(i, n as u16);
+        if stray && self.stray_sprites.insert(self.registers.pc) {
+            warn!(
+                "Drawing {} sprite bytes from I=0x{:04X} at 0x{:04X}, which reads {}",
+                n,
+                i,
+                self.registers.pc,
+                if past_end {
+                    "past the end of memory"
+                } else {
+                    "the interpreter area"
+                }
+            );
+        }
+        if past_end && self.strict_memory {
+            MEMORY_SIZE.saturating_sub(i as usize).min(n as usize) as u8
+        } else {
+            n
+        }
+    }
+
+    // SCHIP 1.1 always scrolled the 128x64 screen, which moves 64x32
+    // pixels by half the distance. An odd half pixel is dropped.
+    fn scroll_distance(&self, n: u8) -> usize {
+        if self.quirks.half_scroll_in_lores && self.display.width() == 64 {
+            n as usize / 2
+        } else {
+            n as usize
+        }
+    }
+
+    // Stops the clock without touching the machine state, so the faulting
+    // instruction can be inspected and the ROM reset.
+    fn halt(&mut self, err: Chip8Error) {
+        error!("Halted on {}", err);
+        error!("Instruction history (oldest first):\n{}", self.history);
+        self.halted = Some(err);
+        self.report_opcode_profile();
+    }
+
+    fn report_opcode_profile(&self) {
+        let profile = match &self.opcode_profile {
+            Some(profile) => profile,
+            None => return,
+        };
+        match &self.opcode_profile_out {
+            Some(path) => {
+                if let Err(err) = fs::write(path, profile.to_string()) {
+                    error!("Failed to write the opcode profile: {}", err);
+                }
+            }
+            None => eprint!("{}", profile),
+        }
+    }
+
+    // Restarts the ROM from power-on, keeping the settings, cheats and
+    // output files of the session.
+    fn reset(&mut self) {
+        info!("Resetting the emulator");
+        let rng = &mut self.rng;
+        self.registers = Registers::new();
+        for v in self.registers.v.iter_mut() {
+            *v = self.reg_init.byte(rng);
+        }
+        self.memory = Memory::with_rom(self.rom.clone(), || self.ram_init.byte(rng));
+        self.timers = Timers::new();
+        self.history = History::new();
+        self.display.set_hires(false);
+        self.display.select_planes(0b01);
+        self.waiting_key = None;
+        self.wait_presses = 0;
+        self.waiting_vblank = false;
+        self.instruction_clock.restart();
+        self.timer_clock.restart();
+        self.cycles = 0;
+        self.finished = false;
+        self.loop_watch = None;
+        self.paused = false;
+        self.vf_written_at = None;
+        self.halted = None;
+    }
+
+    fn on_keyboard(&mut self, message: KeyboardMessage) {
+        match (message, self.waiting_key) {
+            (KeyboardMessage::Press(value), Some(KeyWait::Press(_))) => {
+                self.wait_presses |= 1 << value;
+            }
+            (KeyboardMessage::Press(value), None) => {
+                self.keyboard.buffer_press(value, Instant::now());
+            }
+            (KeyboardMessage::Release(value), Some(KeyWait::Release(x, key))) if value == key => {
+                self.finish_key_wait(x, key);
+            }
+            _ => {}
+        }
+        self.keyboard.update(message);
+    }
+
+    // Presses delivered between two clock ticks are resolved together, and
+    // the lowest CHIP-8 digit wins regardless of their arrival order.
+    fn resolve_key_wait(&mut self) {
+        if let (Some(KeyWait::Press(x)), true) = (self.waiting_key, self.wait_presses != 0) {
+            let key = self.wait_presses.trailing_zeros() as u8;
+            self.wait_presses = 0;
+            self.wait_for_release(x, key);
+        }
+    }
+
+    fn wait_for_release(&mut self, x: u8, key: u8) {
+        if self.keyboard.is_pressed(key) {
+            debug!("Waiting for the key {:X} to be released", key);
+            self.waiting_key = Some(KeyWait::Release(x, key));
+        } else {
+            self.finish_key_wait(x, key);
+        }
+    }
+
+    fn finish_key_wait(&mut self, x: u8, key: u8) {
+        self.registers.v[x as usize] = key & 0xF;
+        self.waiting_key = None;
+    }
+
+    fn pipe_frame(&mut self) {
+        let frame = self.display.to_rgba();
+        let written = match &mut self.frame_pipe {
+            Some(pipe) => pipe.write_all(&frame).and_then(|_| pipe.flush()),
+            None => return,
+        };
+        match written {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                info!("The frame pipe was closed by the reader");
+                self.exit();
+            }
+            Err(err) => {
+                error!("Failed to write a frame: {}", err);
+                self.exit();
+            }
+        }
+    }
+
+    fn exit(&mut self) {
+        if let Some(profile) = &self.cpu_profile {
+            eprint!("{}", profile);
+        }
+        if self.halted.is_none() {
+            self.report_opcode_profile();
+        }
+        if let Some(coverage) = &self.coverage {
+            eprint!("{}", coverage);
+        }
+        if let Some(profile) = &self.call_profile {
+            eprint!("{}", profile);
+        }
+        spans::finish();
+        if let Some(ripper) = &self.sprite_ripper {
+            if let Err(err) = ripper.write() {
+                error!("Failed to write the ripped sprites: {}", err);
+            }
+        }
+        info!("Executed {} instructions", self.cycles);
+        self.exited = true;
+    }
+
+    fn execute_profiled(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        let started = Instant::now();
+        let result = self.execute(instruction);
+        let elapsed = started.elapsed();
+        if let Some(profile) = &mut self.cpu_profile {
+            profile.record(instruction.family(), elapsed);
+        }
+        result
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        if self.lint_vf {
+            self.check_vf_use(instruction);
+        }
+        if let Some(profile) = &mut self.opcode_profile {
+            profile.record(instruction.family(), self.registers.pc);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(instruction.family());
+        }
+        if let Some(profile) = &mut self.call_profile {
+            profile.record_instruction();
+        }
+        match self.trace_format {
+            TraceFormat::Full => {
                 trace!(
-                    "{:04X}: DRW V{:X} V{:X} {:X} (sprite: {:?})",
+                    "PC={:04X}, I={:04X}, v={:?}",
                     self.registers.pc,
-                    x,
-                    y,
-                    n,
-                    sprite
+                    self.registers.i,
+                    self.registers.v,
                 );
+                trace!("{:04X}: {}", self.registers.pc, instruction);
+                self.apply(instruction)
+            }
+            TraceFormat::Delta if log_enabled!(Level::Trace) => {
+                let line = self.apply_with_delta(instruction)?;
+                trace!("{}", line);
+                Ok(())
+            }
+            TraceFormat::Delta => self.apply(instruction),
+        }
+    }
+
+    // Executes the instruction and describes it along with the registers
+    // and timers it changed, e.g. "0212: ADD V3 7  ; V3: 0x10 -> 0x17".
+    fn apply_with_delta(&mut self, instruction: Instruction) -> Result<String, Chip8Error> {
+        let pc = self.registers.pc;
+        let before = self.snapshot();
+        self.apply(instruction)?;
+        let after = self.snapshot();
+
+        let mut changes = Vec::new();
+        for (x, (old, new)) in before.v.iter().zip(after.v).enumerate() {
+            if *old != new {
+                changes.push(format!("V{:X}: 0x{:02X} -> 0x{:02X}", x, old, new));
+            }
+        }
+        if before.i != after.i {
+            changes.push(format!("I: 0x{:04X} -> 0x{:04X}", before.i, after.i));
+        }
+        if before.sp != after.sp {
+            changes.push(format!("SP: {} -> {}", before.sp, after.sp));
+        }
+        if before.dt != after.dt {
+            changes.push(format!("DT: 0x{:02X} -> 0x{:02X}", before.dt, after.dt));
+        }
+        if before.st != after.st {
+            changes.push(format!("ST: 0x{:02X} -> 0x{:02X}", before.st, after.st));
+        }
+
+        let line = format!("{:04X}: {}", pc, instruction);
+        if changes.is_empty() {
+            Ok(line)
+        } else {
+            Ok(format!("{}  ; {}", line, changes.join(", ")))
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            v: self.registers.v,
+            i: self.registers.i,
+            sp: self.registers.sp,
+            dt: self.timers.dt,
+            st: self.timers.st,
+        }
+    }
+
+    fn apply(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        match instruction {
+            Instruction::Cls => {
+                self.display.clear();
+                self.registers.pc += 2;
+            }
+
+            Instruction::Ret => {
+                if self.registers.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow {
+                        pc: self.registers.pc,
+                    });
+                }
+                self.registers.sp -= 1;
+                if let Some(profile) = &mut self.call_profile {
+                    profile.record_return(self.registers.sp as usize);
+                }
+                self.registers.pc = self.registers.stack[self.registers.sp as usize];
+                self.registers.pc += 2;
+            }
+
+            Instruction::Sys(addr) => {
+                // Some ROMs call SYS in a loop, so each address is reported once.
+                if self.ignored_sys.insert(addr) {
+                    warn!("Ignoring SYS 0x{:04X}", addr);
+                }
+                self.registers.pc += 2;
+            }
+
+            Instruction::Jp(addr) => {
+                self.check_target(addr)?;
+                // Nothing can break out of a jump to itself, so this is how
+                // most ROMs end.
+                if addr == self.registers.pc {
+                    info!("The ROM finished with a jump to itself at {:04X}", addr);
+                    self.finished = true;
+                }
+                self.registers.pc = addr;
+            }
+
+            Instruction::Call(addr) => {
+                self.check_target(addr)?;
+                if self.registers.sp as usize == self.registers.stack.len() {
+                    error!("Stack at the overflow: {:04X?}", self.registers.stack);
+                    return Err(Chip8Error::StackOverflow {
+                        pc: self.registers.pc,
+                    });
+                }
+                self.registers.stack[self.registers.sp as usize] = self.registers.pc;
+                self.registers.sp += 1;
+                if let Some(profile) = &mut self.call_profile {
+                    profile.record_call(addr, self.registers.sp as usize);
+                }
+                self.registers.pc = addr
+            }
+
+            Instruction::SeVx(x, value) => {
+                if self.registers.v[x as usize] == value {
+                    self.registers.pc += 2 + self.skip_length();
+                } else {
+                    self.registers.pc += 2;
+                }
+            }
+
+            Instruction::SneVx(x, value) => {
+                if self.registers.v[x as usize] != value {
+                    self.registers.pc += 2 + self.skip_length();
+                } else {
+                    self.registers.pc += 2;
+                }
+            }
+
+            Instruction::SeVxVy(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                if vx == vy {
+                    self.registers.pc += 2 + self.skip_length();
+                } else {
+                    self.registers.pc += 2;
+                }
+            }
+
+            Instruction::LdVx(x, value) => {
+                self.registers.v[x as usize] = value;
+                self.registers.pc += 2;
+            }
+
+            Instruction::AddVx(x, value) => {
+                let old = self.registers.v[x as usize];
+                self.registers.v[x as usize] = old.wrapping_add(value);
+                self.registers.pc += 2;
+            }
+
+            Instruction::LdVxVy(x, y) => {
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vy;
+                self.registers.pc += 2;
+            }
+
+            Instruction::Or(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vx | vy;
+                self.reset_vf_after_logic();
+                self.registers.pc += 2;
+            }
+
+            Instruction::And(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vx & vy;
+                self.reset_vf_after_logic();
+                self.registers.pc += 2;
+            }
+
+            Instruction::Xor(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                self.registers.v[x as usize] = vx ^ vy;
+                self.reset_vf_after_logic();
+                self.registers.pc += 2;
+            }
+
+            Instruction::AddVxVy(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                let (result, carry) = vx.overflowing_add(vy);
+                self.registers.v[x as usize] = result;
+                self.registers.v[0xF] = if carry { 0x01 } else { 0x00 };
+                self.registers.pc += 2;
+            }
+
+            Instruction::Sub(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                let (result, bollow) = vx.overflowing_sub(vy);
+                self.registers.v[x as usize] = result;
+                self.registers.v[0xF] = if !bollow { 0x01 } else { 0x00 };
+                self.registers.pc += 2;
+            }
+
+            Instruction::Shr(x, y) => {
+                let source = self.shift_source(x, y);
+                self.registers.v[x as usize] = source >> 1;
+                self.registers.v[0xF] = source & 0x01;
+                self.registers.pc += 2;
+            }
+
+            Instruction::Subn(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                let (result, bollow) = vy.overflowing_sub(vx);
+                self.registers.v[x as usize] = result;
+                self.registers.v[0xF] = if !bollow { 0x01 } else { 0x00 };
+                self.registers.pc += 2;
+            }
+
+            Instruction::Shl(x, y) => {
+                let source = self.shift_source(x, y);
+                self.registers.v[x as usize] = source << 1;
+                self.registers.v[0xF] = source >> 7;
+                self.registers.pc += 2;
+            }
+
+            Instruction::SneVxVy(x, y) => {
+                let vx = self.registers.v[x as usize];
+                let vy = self.registers.v[y as usize];
+                if vx != vy {
+                    self.registers.pc += 2 + self.skip_length();
+                } else {
+                    self.registers.pc += 2;
+                }
+            }
+
+            Instruction::LdI(addr) => {
+                self.registers.i = addr;
+                self.registers.pc += 2;
+            }
+
+            Instruction::LdILong(addr) => {
+                self.registers.i = addr;
+                self.registers.pc += 4;
+            }
+
+            Instruction::Plane(planes) => {
+                self.display.select_planes(planes);
+                self.registers.pc += 2;
+            }
+
+            Instruction::Audio => {
+                let mut pattern = [0; 16];
+                pattern.copy_from_slice(&self.memory.load_sprite(self.registers.i, 16));
+                self.buzzer.set_pattern(pattern);
+                self.registers.pc += 2;
+            }
+
+            Instruction::Pitch(x) => {
+                self.buzzer.set_pitch(self.registers.v[x as usize]);
+                self.registers.pc += 2;
+            }
 
+            Instruction::JpV0(addr) => {
+                // BXNN reads the register from the top nibble of the address.
+                let x = if self.quirks.jump_with_vx {
+                    (addr >> 8) as u8
+                } else {
+                    0x0
+                };
+                let target = addr + self.registers.v[x as usize] as u16;
+                self.check_target(target)?;
+                self.registers.pc = target;
+            }
+
+            Instruction::Rnd(x, value) => {
+                let random: u8 = self.rng.gen();
+                self.registers.v[x as usize] = random & value;
+                self.registers.pc += 2;
+            }
+
+            Instruction::Drw(x, y, n) => {
+                let from = self.registers.i;
                 let corner_x = self.registers.v[x as usize];
                 let corner_y = self.registers.v[y as usize];
 
-                let collision = self.display.draw_sprite(corner_x, corner_y, sprite);
-                self.registers.v[0xF] = if collision { 0x01 } else { 0x00 };
+                // Each selected XO-CHIP plane takes a sprite of its own.
+                let planes = self.display.selected_plane_count() as u8;
+                let collided_rows = if n == 0 && self.quirks.schip {
+                    // DXY0 draws a 16x16 sprite of 32 bytes.
+                    let rows = self.sprite_rows(32 * planes) / 2;
+                    let sprite = &self.memory.load_sprite16(from, rows);
+                    trace!("Sprite: {:?}", sprite);
+                    self.display.draw_sprite16(corner_x, corner_y, sprite)
+                } else {
+                    let rows = self.sprite_rows(n * planes);
+                    let sprite = &self.memory.load_sprite(from, rows);
+                    trace!("Sprite: {:?}", sprite);
+                    if let Some(ripper) = &mut self.sprite_ripper {
+                        ripper.record(from, sprite);
+                    }
+                    self.display.draw_sprite(corner_x, corner_y, sprite)
+                };
+                #[cfg(feature = "scripting")]
+                self.run_script(Hook::Draw(corner_x, corner_y, n));
+                // SCHIP 1.1 counts the collided rows in the high resolution.
+                self.registers.v[0xF] =
+                    if self.quirks.collision_counts_rows && self.display.width() == 128 {
+                        collided_rows as u8
+                    } else {
+                        (collided_rows > 0) as u8
+                    };
+                // The COSMAC VIP drew at most one sprite per display refresh.
+                self.waiting_vblank = self.quirks.display_wait;
                 self.registers.pc += 2;
             }
 
-            (0xE, x, 0x9, 0xE) => {
-                trace!("{:04X}: SKP V{:X}", self.registers.pc, x);
-                let value = self.registers.v[x as usize];
+            Instruction::Skp(x) => {
+                let value = self.nibble_of(x);
                 if self.keyboard.is_pressed(value) {
-                    self.registers.pc += 4;
+                    self.registers.pc += 2 + self.skip_length();
                 } else {
                     self.registers.pc += 2;
                 }
             }
 
-            (0xE, x, 0xA, 0x1) => {
-                trace!("{:04X}: SKNP V{:X}", self.registers.pc, x);
-                let value = self.registers.v[x as usize];
+            Instruction::Sknp(x) => {
+                let value = self.nibble_of(x);
                 if !self.keyboard.is_pressed(value) {
-                    self.registers.pc += 4;
+                    self.registers.pc += 2 + self.skip_length();
                 } else {
                     self.registers.pc += 2;
                 }
             }
 
-            (0xF, x, 0x0, 0x7) => {
-                trace!("{:04X}: LD V{:X} DT", self.registers.pc, x);
+            Instruction::LdVxDt(x) => {
                 self.registers.v[x as usize] = self.timers.dt;
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x0, 0xA) => {
-                trace!("{:04X}: LD V{:X} K", self.registers.pc, x);
-                debug!("Waiting keyboard input for the register V{:X}", x);
-                self.waiting_key_for = Some(x);
+            Instruction::LdVxK(x) => {
+                match self
+                    .keyboard
+                    .take_buffered_press(Instant::now(), self.key_buffer)
+                {
+                    Some(value) => self.wait_for_release(x, value),
+                    None => {
+                        debug!("Waiting keyboard input for the register V{:X}", x);
+                        self.waiting_key = Some(KeyWait::Press(x));
+                    }
+                }
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x1, 0x5) => {
-                trace!("{:04X}: LD DT V{:X}", self.registers.pc, x);
+            Instruction::LdDt(x) => {
                 self.timers.dt = self.registers.v[x as usize];
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x1, 0x8) => {
-                trace!("{:04X}: LD ST V{:X}", self.registers.pc, x);
+            Instruction::LdSt(x) => {
                 self.timers.st = self.registers.v[x as usize];
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x1, 0xE) => {
-                trace!("{:04X}: ADD I V{:X}", self.registers.pc, x);
-                self.registers.i += self.registers.v[x as usize] as u16;
+            Instruction::AddI(x) => {
+                let sum = self
+                    .registers
+                    .i
+                    .wrapping_add(self.registers.v[x as usize] as u16);
+                self.registers.i = self.wrapped_i(sum);
+                if self.quirks.index_overflow_sets_vf {
+                    self.registers.v[0xF] = if sum > 0x0FFF { 0x01 } else { 0x00 };
+                }
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x2, 0x9) => {
-                trace!("{:04X}: LD F V{:X}", self.registers.pc, x);
-                let font = self.registers.v[x as usize];
+            Instruction::LdF(x) => {
+                let font = self.nibble_of(x);
                 self.registers.i = Memory::font_addr(font);
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x3, 0x3) => {
-                trace!("{:04X}: LD B V{:X}", self.registers.pc, x);
+            Instruction::LdHf(x) => {
+                let digit = self.nibble_of(x);
+                self.registers.i = Memory::big_font_addr(digit);
+                self.registers.pc += 2;
+            }
+
+            Instruction::LdB(x) => {
+                self.check_i_range(3)?;
                 let from = self.registers.i;
                 let value = self.registers.v[x as usize];
                 self.memory.store(from, value / 100);
-                self.memory.store(from + 1, (value / 10) % 10);
-                self.memory.store(from + 2, value % 10);
+                self.memory.store(from.wrapping_add(1), (value / 10) % 10);
+                self.memory.store(from.wrapping_add(2), value % 10);
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x5, 0x5) => {
-                trace!("{:04X}: LD [I] V{:X}", self.registers.pc, x);
+            Instruction::LdIVx(x) => {
+                self.check_i_range(x as u16 + 1)?;
                 let from = self.registers.i;
                 for offset in 0..=x {
                     let value = self.registers.v[offset as usize];
-                    self.memory.store(from + offset as u16, value);
+                    self.memory.store(from.wrapping_add(offset as u16), value);
                 }
+                let advanced = self.registers.i.wrapping_add(self.i_increment(x));
+                self.registers.i = self.wrapped_i(advanced);
                 self.registers.pc += 2;
             }
 
-            (0xF, x, 0x6, 0x5) => {
-                trace!("{:04X}: LD V{:X} [I]", self.registers.pc, x);
+            Instruction::LdVxI(x) => {
+                self.check_i_range(x as u16 + 1)?;
                 let from = self.registers.i;
                 for offset in 0..=x {
-                    let value = self.memory.load(from + offset as u16);
+                    let value = self.memory.load(from.wrapping_add(offset as u16));
                     self.registers.v[offset as usize] = value;
                 }
+                let advanced = self.registers.i.wrapping_add(self.i_increment(x));
+                self.registers.i = self.wrapped_i(advanced);
                 self.registers.pc += 2;
             }
 
-            _ => {
-                panic!("UNSUPPORTED INST: {:X}{:X}{:X}{:X}", h1, h2, h3, h4);
+            Instruction::Exit => {
+                info!("Program exited via 00FD at 0x{:04X}", self.registers.pc);
+                self.exit();
             }
-        }
-    }
-}
 
-fn value_of(n1: u8, n2: u8) -> u8 {
-    n1 * 0x10 + n2
-}
+            Instruction::Low => {
+                self.display.set_hires(false);
+                self.registers.pc += 2;
+            }
+
+            Instruction::High => {
+                self.display.set_hires(true);
+                self.registers.pc += 2;
+            }
+
+            Instruction::Scd(n) => {
+                let n = self.scroll_distance(n);
+                self.display.scroll_down(n);
+                self.registers.pc += 2;
+            }
 
-fn address_of(n1: u8, n2: u8, n3: u8) -> u16 {
-    n1 as u16 * 0x100 + n2 as u16 * 0x010 + n3 as u16
+            Instruction::Scu(n) => {
+                let n = self.scroll_distance(n);
+                self.display.scroll_up(n);
+                self.registers.pc += 2;
+            }
+
+            Instruction::Scr => {
+                let n = self.scroll_distance(4);
+                self.display.scroll_right(n);
+                self.registers.pc += 2;
+            }
+
+            Instruction::Scl => {
+                let n = self.scroll_distance(4);
+                self.display.scroll_left(n);
+                self.registers.pc += 2;
+            }
+
+            // The HP-48 had only 8 user flags, so V8 and above are dropped.
+            Instruction::LdRVx(x) => {
+                let count = (x as usize + 1).min(RPL_FLAGS);
+                self.rpl_flags.store(&self.registers.v[..count]);
+                self.registers.pc += 2;
+            }
+
+            Instruction::LdVxR(x) => {
+                let flags = self.rpl_flags.load(x as usize + 1);
+                self.registers.v[..flags.len()].copy_from_slice(flags);
+                self.registers.pc += 2;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl ScriptHost for Chip8 {
+    fn peek(&self, addr: u16) -> Result<u8, String> {
+        check_address(addr)?;
+        Ok(self.memory.load(addr))
+    }
+
+    fn poke(&mut self, addr: u16, value: u8) -> Result<(), String> {
+        check_address(addr)?;
+        self.memory.store(addr, value);
+        Ok(())
+    }
+
+    fn get_reg(&self, x: u8) -> Result<u8, String> {
+        check_nibble(x, "register")?;
+        Ok(self.registers.v[x as usize])
+    }
+
+    fn set_reg(&mut self, x: u8, value: u8) -> Result<(), String> {
+        check_nibble(x, "register")?;
+        self.registers.v[x as usize] = value;
+        Ok(())
+    }
+
+    fn press(&mut self, key: u8) -> Result<(), String> {
+        check_nibble(key, "key")?;
+        self.on_keyboard(KeyboardMessage::Press(key));
+        Ok(())
+    }
+
+    fn release(&mut self, key: u8) -> Result<(), String> {
+        check_nibble(key, "key")?;
+        self.on_keyboard(KeyboardMessage::Release(key));
+        Ok(())
+    }
+
+    fn screenshot(&self, path: &str) -> Result<(), String> {
+        write_png(
+            Path::new(path),
+            FRAME_WIDTH,
+            FRAME_HEIGHT,
+            &self.display.to_rgba(),
+        )
+        .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn check_address(addr: u16) -> Result<(), String> {
+    if MEMORY_SIZE <= addr as usize {
+        return Err(format!("Address out of memory: {:04X}", addr));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "scripting")]
+fn check_nibble(value: u8, what: &str) -> Result<(), String> {
+    if 0xF < value {
+        return Err(format!("No such {}: {}", what, value));
+    }
+    Ok(())
+}
+
+// The length of the instruction after PC, which is four bytes for XO-CHIP's
+// F000 NNNN and two for everything else.
+fn skip_distance(memory: &Memory, pc: u16) -> u16 {
+    let next = pc.wrapping_add(2);
+    if memory.load(next) == 0xF0 && memory.load(next.wrapping_add(1)) == 0x00 {
+        4
+    } else {
+        2
+    }
+}
+
+fn period_of(frequency: f64) -> Duration {
+    Duration::from_secs_f64(1.0 / frequency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buzzer::ToneChange;
+    use crate::keyboard::KEY_MAP;
+    use proptest::prelude::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    fn flags_with_rom(rom: Vec<u8>) -> Flags {
+        Flags {
+            rom,
+            clock_speed: 500.0,
+            timer_hz: 60,
+            key_buffer: Duration::from_millis(100),
+            max_catch_up: Duration::from_millis(100),
+            display_color: Color::WHITE,
+            renderer: Renderer::Canvas,
+            pixel_shape: PixelShape::Square,
+            scale_factor: 1.0,
+            cpu_profile: false,
+            profile_opcodes: false,
+            profile_opcodes_out: None,
+            coverage: false,
+            profile_calls: None,
+            rip_sprites: None,
+            checksum_every: None,
+            max_cycles: None,
+            break_opcode: None,
+            paranoid: true,
+            strict_memory: false,
+            strict_alignment: false,
+            lint_vf: false,
+            trace_format: TraceFormat::Full,
+            quirks: Quirks::default(),
+            exit_on_terminal_loop: false,
+            fast_boot: None,
+            #[cfg(feature = "scripting")]
+            script: None,
+            pipe_frames: false,
+            ram_init: InitPattern::Zero,
+            reg_init: InitPattern::Zero,
+            seed: None,
+            cheats: vec![],
+            rpl_flags: None,
+        }
+    }
+
+    fn chip8_with_rom(rom: Vec<u8>) -> Chip8 {
+        Chip8::with_buzzer(flags_with_rom(rom), Buzzer::silent())
+    }
+
+    #[test]
+    fn registers_filled_with_pattern() {
+        let flags = Flags {
+            reg_init: InitPattern::Ff,
+            ..flags_with_rom(vec![])
+        };
+        let chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        assert_eq!(chip8.registers.v, [0xFF; 16]);
+        assert_eq!(chip8.memory.load(0x200), 0x00);
+    }
+
+    #[test]
+    fn random_fill_follows_seed() {
+        let flags = || Flags {
+            ram_init: InitPattern::Random,
+            reg_init: InitPattern::Random,
+            seed: Some(42),
+            ..flags_with_rom(vec![])
+        };
+        let chip8 = Chip8::with_buzzer(flags(), Buzzer::silent());
+        let again = Chip8::with_buzzer(flags(), Buzzer::silent());
+
+        assert_eq!(chip8.registers.v, again.registers.v);
+        assert_eq!(
+            chip8.memory.load_sprite(0x200, 0xFF),
+            again.memory.load_sprite(0x200, 0xFF)
+        );
+    }
+
+    #[test]
+    fn ff_fill_spares_font_and_rom() {
+        let chip8 = Chip8::with_buzzer(
+            Flags {
+                ram_init: InitPattern::Ff,
+                ..flags_with_rom(vec![0x12, 0x00])
+            },
+            Buzzer::silent(),
+        );
+
+        assert_eq!(chip8.memory.load(Memory::font_addr(0)), 0xF0);
+        assert_eq!(chip8.memory.load(Memory::font_addr(1)), 0x20);
+        assert_eq!(chip8.memory.load(0x1FF), 0xFF);
+        assert_eq!(chip8.memory.load_sprite(0x200, 3), vec![0x12, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn profiled_execution_counts_families() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01, 0x61, 0x02, 0x80, 0x14]);
+        chip8.execute_with = Chip8::execute_profiled;
+        chip8.cpu_profile = Some(CpuProfile::new());
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        let report = chip8.cpu_profile.unwrap().to_string();
+        assert!(report.contains("6XKK"));
+        assert!(report.contains("8XY4"));
+        assert_eq!(chip8.registers.v[0x0], 0x03);
+    }
+
+    #[test]
+    fn period_of_fractional_frequencies() {
+        assert_eq!(period_of(500.0), Duration::from_millis(2));
+        assert_eq!(period_of(0.5), Duration::from_secs(2));
+        assert_eq!(period_of(0.1), Duration::from_secs(10));
+        assert_eq!(period_of(300.0).as_micros(), 3333);
+        assert_eq!(period_of(50.0), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn cheats_pin_memory_after_each_instruction() {
+        let flags = Flags {
+            cheats: vec![crate::cheats::parse_poke("0x300=0x05").unwrap()],
+            ..flags_with_rom(vec![0xA3, 0x00, 0x60, 0x09, 0xF0, 0x55])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.memory.load(0x300), 0x05);
+
+        chip8.cheats.toggle(0);
+        chip8.registers.pc = 0x204;
+        chip8.step();
+        assert_eq!(chip8.memory.load(0x300), 0x09);
+    }
+
+    #[test]
+    fn demo_maze_reaches_final_loop() {
+        let mut chip8 = chip8_with_rom(crate::demo::find("maze").unwrap().to_vec());
+
+        for _ in 0..2000 {
+            chip8.step();
+        }
+        assert_eq!(chip8.registers.pc, 0x21C);
+    }
+
+    #[test]
+    fn title_shows_key_wait() {
+        let mut chip8 = chip8_with_rom(vec![0xF3, 0x0A]);
+
+        assert_eq!(chip8.title(), "CHIP-8 Emulator");
+        chip8.step();
+        assert_eq!(chip8.title(), "CHIP-8 Emulator [waiting for key...]");
+        chip8.waiting_key = None;
+        assert_eq!(chip8.title(), "CHIP-8 Emulator");
+    }
+
+    #[test]
+    fn identical_runs_have_identical_checksums() {
+        let checksums_of = |seed| {
+            let mut flags = flags_with_rom(crate::demo::find("maze").unwrap().to_vec());
+            flags.seed = Some(seed);
+            let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+            (0..10)
+                .map(|_| {
+                    (0..100).for_each(|_| chip8.step());
+                    chip8.checksum()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(checksums_of(1), checksums_of(1));
+        assert_ne!(checksums_of(1), checksums_of(2));
+    }
+
+    #[test]
+    fn buffered_press_satisfies_key_wait() {
+        let mut chip8 = chip8_with_rom(vec![0xF3, 0x0A, 0xF4, 0x0A]);
+
+        chip8.keyboard.buffer_press(0x5, Instant::now());
+        chip8.step();
+        assert_eq!(chip8.waiting_key, None);
+        assert_eq!(chip8.registers.v[3], 0x5);
+        chip8.step();
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Press(4)));
+    }
+
+    #[test]
+    fn keys_and_fonts_use_the_low_nibble() {
+        // 0200: LD F VA, 0202: SKP VA, 0204: (skipped), 0206: SKNP VA
+        let mut chip8 = chip8_with_rom(vec![0xFA, 0x29, 0xEA, 0x9E, 0x00, 0x00, 0xEA, 0xA1]);
+        chip8.registers.v[0xA] = 0xAB;
+        chip8.on_keyboard(KeyboardMessage::Press(0xB));
+
+        chip8.step();
+        assert_eq!(chip8.registers.i, Memory::font_addr(0xB));
+        chip8.step();
+        assert_eq!(chip8.registers.pc, 0x206);
+        chip8.step();
+        assert_eq!(chip8.registers.pc, 0x208);
+        assert_eq!(chip8.wide_nibbles, HashSet::from([0x200, 0x202, 0x206]));
+    }
+
+    #[test]
+    fn lowest_simultaneous_key_wins() {
+        for presses in [[0xA, 0x3], [0x3, 0xA]] {
+            let mut chip8 = chip8_with_rom(vec![0xF3, 0x0A]);
+
+            chip8.step();
+            for value in presses {
+                chip8.on_keyboard(KeyboardMessage::Press(value));
+            }
+            assert_eq!(chip8.waiting_key, Some(KeyWait::Press(3)));
+            chip8.resolve_key_wait();
+            assert_eq!(chip8.waiting_key, Some(KeyWait::Release(3, 0x3)));
+            for value in presses {
+                chip8.on_keyboard(KeyboardMessage::Release(value));
+            }
+            assert_eq!(chip8.waiting_key, None);
+            assert_eq!(chip8.registers.v[3], 0x3);
+        }
+    }
+
+    #[test]
+    fn ticks_keep_instruction_and_timer_rates() {
+        let mut chip8 = chip8_with_rom(vec![0x70, 0x01, 0x12, 0x00]);
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        for _ in 0..500 {
+            for millis in [5, 10, 5] {
+                now += Duration::from_millis(millis);
+                chip8.tick(now);
+            }
+        }
+        assert_eq!(chip8.cycles, 5000);
+        assert_eq!(chip8.timer_ticks, 600);
+        now += Duration::from_millis(1);
+        chip8.tick(now);
+        assert_eq!(chip8.timer_ticks, 600);
+    }
+
+    #[test]
+    fn one_tick_runs_every_instruction_due() {
+        let mut chip8 = chip8_with_rom(vec![0x70, 0x01, 0x12, 0x00]);
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        now += Duration::from_millis(100);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 50);
+        // A suspended window catches up on at most max_catch_up.
+        now += Duration::from_secs(60);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 100);
+    }
+
+    #[test]
+    fn catch_up_is_bounded_by_the_budget() {
+        // 0200: LD V0 60, 0202: LD DT V0, 0204: ADD V1 1, 0206: JP 0204
+        let flags = Flags {
+            max_catch_up: Duration::from_millis(250),
+            ..flags_with_rom(vec![0x60, 0x3C, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        now += Duration::from_millis(200);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 100);
+        assert_eq!(chip8.timer_ticks, 12);
+        now += Duration::from_secs(600);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 225);
+        assert_eq!(chip8.timer_ticks, 27);
+        assert_eq!(chip8.timers.dt, 45);
+    }
+
+    #[test]
+    fn ticks_sustain_high_clock_speeds() {
+        let flags = Flags {
+            clock_speed: 2000.0,
+            ..flags_with_rom(vec![0x70, 0x01, 0x12, 0x00])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        now += Duration::from_millis(5);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 10);
+        for _ in 1..200 {
+            now += Duration::from_millis(5);
+            chip8.tick(now);
+        }
+        assert_eq!(chip8.cycles, 2000);
+    }
+
+    #[test]
+    fn timers_count_whole_periods_and_carry_the_rest() {
+        // 0200: LD V0 10, 0202: LD DT V0, 0204: LD ST V0, 0206: JP 0208, 0208: JP 0206
+        let mut chip8 = chip8_with_rom(vec![
+            0x60, 0x0A, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x08, 0x12, 0x06,
+        ]);
+        for _ in 0..3 {
+            chip8.step();
+        }
+        let mut now = Instant::now();
+        chip8.last_tick = Some(now);
+
+        now += Duration::from_millis(50);
+        chip8.tick(now);
+        assert_eq!((chip8.timers.dt, chip8.timers.st), (7, 7));
+        now += Duration::from_millis(16);
+        chip8.tick(now);
+        assert_eq!((chip8.timers.dt, chip8.timers.st), (7, 7));
+        now += Duration::from_millis(1);
+        chip8.tick(now);
+        assert_eq!((chip8.timers.dt, chip8.timers.st), (6, 6));
+    }
+
+    #[test]
+    fn ticker_is_exact_for_any_split() {
+        let mut ticker = Ticker::new(60.0);
+        assert_eq!(ticker.advance(Duration::from_millis(50)), 3);
+        assert_eq!(ticker.advance(Duration::from_nanos(16_666_666)), 0);
+        assert_eq!(ticker.advance(Duration::from_nanos(1)), 1);
+        assert_eq!(ticker.time_of(3), Duration::from_millis(50));
+
+        let mut ticker = Ticker::new(0.5);
+        assert_eq!(ticker.advance(Duration::from_millis(1999)), 0);
+        assert_eq!(ticker.advance(Duration::from_millis(1)), 1);
+    }
+
+    #[test]
+    fn cycles_stand_still_while_waiting_for_key() {
+        // 0200: LD V0 K, 0202: LD V1 0x01, 0204: JP 0202
+        let mut chip8 = chip8_with_rom(vec![0xF0, 0x0A, 0x61, 0x01, 0x12, 0x02]);
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        for _ in 0..10 {
+            now += Duration::from_millis(10);
+            chip8.tick(now);
+        }
+        assert_eq!(chip8.cycles, 1);
+
+        chip8.on_keyboard(KeyboardMessage::Press(0x5));
+        chip8.on_keyboard(KeyboardMessage::Release(0x5));
+        now += Duration::from_millis(10);
+        chip8.tick(now);
+        assert_eq!(chip8.registers.v[0], 0x5);
+        assert_eq!(chip8.cycles, 1 + 5);
+    }
+
+    #[test]
+    fn history_oldest_first() {
+        let mut history = History::new();
+
+        for offset in 0..3 {
+            history.push(0x200 + offset * 2, 0x6000 + offset, None);
+        }
+        let entries: Vec<_> = history
+            .iter()
+            .map(|&(pc, opcode, _)| (pc, opcode))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(0x200, 0x6000), (0x202, 0x6001), (0x204, 0x6002)]
+        );
+    }
+
+    #[test]
+    fn history_keeps_latest_entries() {
+        let mut history = History::new();
+
+        for offset in 0..HISTORY_SIZE as u16 + 10 {
+            history.push(0x200 + offset * 2, offset, None);
+        }
+        let entries: Vec<_> = history
+            .iter()
+            .map(|&(pc, opcode, _)| (pc, opcode))
+            .collect();
+        assert_eq!(entries.len(), HISTORY_SIZE);
+        assert_eq!(entries.first(), Some(&(0x200 + 10 * 2, 10)));
+        assert_eq!(
+            entries.last(),
+            Some(&(
+                0x200 + (HISTORY_SIZE as u16 + 9) * 2,
+                HISTORY_SIZE as u16 + 9
+            ))
+        );
+    }
+
+    #[test]
+    fn history_before_bad_opcode() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01, 0x61, 0x02, 0x80, 0x14, 0xFF, 0xFF]);
+
+        for _ in 0..4 {
+            chip8.step();
+        }
+        assert!(chip8.halted.is_some());
+        let entries: Vec<_> = chip8
+            .history
+            .iter()
+            .map(|&(pc, opcode, _)| (pc, opcode))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (0x200, 0x6001),
+                (0x202, 0x6102),
+                (0x204, 0x8014),
+                (0x206, 0xFFFF)
+            ]
+        );
+        assert_eq!(
+            chip8.history.to_string(),
+            "0200: 6001  LD V0 1\n0202: 6102  LD V1 2\n0204: 8014  ADD V0 V1\n0206: FFFF  ???\n"
+        );
+    }
+
+    #[test]
+    fn history_decodes_under_the_quirks() {
+        // 0200: HIGH, 0202: LD I 0300 (long), 0206: PLANE 3, 0208: FFFF
+        let rom = vec![0x00, 0xFF, 0xF0, 0x00, 0x03, 0x00, 0xF3, 0x01, 0xFF, 0xFF];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("xochip").unwrap());
+
+        for _ in 0..4 {
+            chip8.step();
+        }
+        assert!(chip8.halted.is_some());
+        assert_eq!(
+            chip8.history.to_string(),
+            "0200: 00FF  HIGH\n0202: F000  LD I LONG 0300\n0206: F301  PLANE 3\n0208: FFFF  ???\n"
+        );
+    }
+
+    #[test]
+    fn validate_catches_pc_out_of_memory() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01]);
+
+        chip8.registers.pc = 0xFFF;
+        assert_eq!(chip8.validate(), Err("PC out of memory: 0FFF".to_string()));
+    }
+
+    #[test]
+    fn halt_on_jump_out_of_range() {
+        // 0200: JP 0x0FFF
+        let mut chip8 = chip8_with_rom(vec![0x1F, 0xFF]);
+
+        chip8.step();
+        assert_eq!(
+            chip8.halted,
+            Some(Chip8Error::JumpOutOfRange {
+                pc: 0x200,
+                target: 0xFFF
+            })
+        );
+        assert_eq!(chip8.registers.pc, 0x200);
+    }
+
+    #[test]
+    fn halt_when_pc_walks_off_memory() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01]);
+        // 0FFC: LD V0 0x01, 0FFE: LD V1 0x02
+        chip8.memory.store(0xFFC, 0x60);
+        chip8.memory.store(0xFFD, 0x01);
+        chip8.memory.store(0xFFE, 0x61);
+        chip8.memory.store(0xFFF, 0x02);
+        chip8.registers.pc = 0xFFC;
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.halted, Some(Chip8Error::PcOutOfRange { pc: 0x1000 }));
+        assert_eq!(chip8.registers.v[0x1], 0x02);
+        assert_eq!(chip8.cycles, 2);
+    }
+
+    #[test]
+    fn validate_catches_i_out_of_memory() {
+        let quirks = Quirks {
+            memory_increments_i: true,
+            ..Quirks::default()
+        };
+        // 0200: LD I 0xFF0, 0202: LD [I] VF, 0204: LD I 0xFFF, 0206: LD V0 [I]
+        let rom = vec![0xAF, 0xF0, 0xFF, 0x55, 0xAF, 0xFF, 0xF0, 0x65];
+        let mut chip8 = chip8_with_quirks(rom, quirks);
+
+        // Advancing past the end wraps I like FX1E does.
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.registers.i, 0x000);
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.registers.i, 0x000);
+        assert_eq!(chip8.validate(), Ok(()));
+
+        chip8.registers.i = 0x1000;
+        assert_eq!(chip8.validate(), Err("I out of memory: 1000".to_string()));
+    }
+
+    #[test]
+    fn validate_catches_sp_and_key_register() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01]);
+
+        assert_eq!(chip8.validate(), Ok(()));
+        chip8.registers.sp = 17;
+        assert!(catch_unwind(AssertUnwindSafe(|| chip8.step())).is_err());
+        chip8.registers.sp = 0;
+        chip8.waiting_key = Some(KeyWait::Press(16));
+        assert!(chip8.validate().is_err());
+    }
+
+    #[test]
+    fn fast_boot_stops_at_first_draw() {
+        let mut chip8 = chip8_with_rom(vec![
+            0x6F, 0x3C, 0xFF, 0x15, 0x60, 0x00, 0x70, 0x01, 0x30, 0x64, 0x12, 0x06, 0xD0, 0x05,
+            0x12, 0x0E,
+        ]);
+
+        chip8.fast_boot(1_000_000);
+        assert_eq!(chip8.registers.pc, 0x20E);
+        assert_eq!(chip8.cycles, 3 + 100 * 3);
+        // 303 instructions at 500 Hz span 606 ms, i.e. 36 timer ticks at 60 Hz.
+        assert_eq!(chip8.timers.dt, 0x3C - 36);
+    }
+
+    #[test]
+    fn fast_boot_ends_on_immediate_draw_or_cap() {
+        let mut drawing = chip8_with_rom(vec![0xD0, 0x05, 0x12, 0x02]);
+        drawing.fast_boot(1_000_000);
+        assert_eq!(drawing.cycles, 1);
+
+        let mut looping = chip8_with_rom(vec![0x70, 0x01, 0x12, 0x00]);
+        looping.fast_boot(50);
+        assert_eq!(looping.cycles, 50);
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn sample_script_presses_keys() {
+        let mut flags = flags_with_rom(crate::demo::find("keypad").unwrap().to_vec());
+        flags.script = Some(String::from("scripts/autopress.lua"));
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        chip8.step();
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Press(0)));
+        for _ in 0..30 {
+            chip8.tick_timers();
+        }
+        chip8.resolve_key_wait();
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Release(0, 0x1)));
+        for _ in 0..30 {
+            chip8.tick_timers();
+        }
+        assert_eq!(chip8.waiting_key, None);
+        assert_eq!(chip8.registers.v[0], 0x1);
+        assert!(chip8.script.is_some());
+    }
+
+    fn run_to_terminal_loop(rom: Vec<u8>, steps: u64) -> Chip8 {
+        let mut flags = flags_with_rom(rom);
+        flags.exit_on_terminal_loop = true;
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        for _ in 0..steps {
+            chip8.step();
+        }
+        chip8
+    }
+
+    #[test]
+    fn exit_on_jump_to_self() {
+        // 0200: CLS, 0202: JP 0202
+        let rom = vec![0x00, 0xE0, 0x12, 0x02];
+
+        assert!(!run_to_terminal_loop(rom.clone(), 1).exited);
+        assert!(run_to_terminal_loop(rom.clone(), 2).exited);
+
+        let mut chip8 = chip8_with_rom(rom);
+        for _ in 0..2 {
+            chip8.step();
+        }
+        assert!(chip8.finished);
+        assert!(!chip8.exited);
+    }
+
+    #[test]
+    fn exit_on_stable_loop() {
+        // 0200: CLS, 0202: LD V0 1, 0204: SE V0 0, 0206: JP 0202
+        let rom = vec![0x00, 0xE0, 0x60, 0x01, 0x30, 0x00, 0x12, 0x02];
+
+        assert!(!run_to_terminal_loop(rom.clone(), 12).exited);
+        assert!(run_to_terminal_loop(rom, 13).exited);
+    }
+
+    #[test]
+    fn changing_loops_are_not_terminal() {
+        // 0200: ADD V0 1, 0202: JP 0200
+        let counter = vec![0x70, 0x01, 0x12, 0x00];
+        // 0200: LD I 0300, 0202: LD V0 [I], 0204: ADD V0 1, 0206: LD [I] V0,
+        // 0208: LD V0 0, 020A: JP 0200
+        let store = vec![
+            0xA3, 0x00, 0xF0, 0x65, 0x70, 0x01, 0xF0, 0x55, 0x60, 0x00, 0x12, 0x00,
+        ];
+
+        assert!(!run_to_terminal_loop(counter, 10_000).exited);
+        assert!(!run_to_terminal_loop(store, 10_000).exited);
+    }
+
+    #[test]
+    fn polling_loops_are_not_terminal() {
+        // 0200: LD V0 0, 0202: SE V0 0, 0204: JP 0200, 0206: JP 0200
+        let stable = vec![0x60, 0x00, 0x30, 0x00, 0x12, 0x00, 0x12, 0x00];
+        // The same loop, reading V0 from DT instead.
+        let dt_poll = vec![0xF0, 0x07, 0x30, 0x00, 0x12, 0x00, 0x12, 0x00];
+        // 0200: SKNP V0, 0202: JP 0200, 0204: JP 0200
+        let key_poll = vec![0xE0, 0xA1, 0x12, 0x00, 0x12, 0x00];
+
+        assert!(run_to_terminal_loop(stable, 10_000).exited);
+        assert!(!run_to_terminal_loop(dt_poll, 10_000).exited);
+        assert!(!run_to_terminal_loop(key_poll, 10_000).exited);
+    }
+
+    #[test]
+    fn halt_on_stack_overflow() {
+        // 0200: CALL 0202, 0202: CALL 0204, ...
+        let rom = (0..17u16)
+            .flat_map(|n| (0x2202 + n * 2).to_be_bytes())
+            .collect();
+        let mut chip8 = chip8_with_rom(rom);
+
+        for _ in 0..17 {
+            chip8.step();
+        }
+        assert_eq!(chip8.halted, Some(Chip8Error::StackOverflow { pc: 0x220 }));
+        assert_eq!(chip8.registers.pc, 0x220);
+        assert_eq!(chip8.registers.sp, 16);
+        let expected: Vec<u16> = (0..16).map(|n| 0x200 + n * 2).collect();
+        assert_eq!(chip8.registers.stack.to_vec(), expected);
+        assert_eq!(chip8.cycles, 16);
+    }
+
+    #[test]
+    fn halt_on_stack_underflow() {
+        // 0200: LD V0 0x01, 0202: RET
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x01, 0x00, 0xEE]);
+
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.halted, Some(Chip8Error::StackUnderflow { pc: 0x202 }));
+        assert_eq!(chip8.registers.pc, 0x202);
+        assert_eq!(chip8.registers.sp, 0);
+    }
+
+    #[test]
+    fn halt_on_bad_opcode() {
+        // 0200: LD V0 0x05, 0202: (bad) 8009
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x05, 0x80, 0x09]);
+        let mut now = Instant::now();
+
+        chip8.step();
+        chip8.step();
+        assert_eq!(
+            chip8.halted,
+            Some(Chip8Error::UnknownOpcode {
+                pc: 0x202,
+                word: 0x8009
+            })
+        );
+        assert_eq!(chip8.registers.pc, 0x202);
+        assert_eq!(chip8.registers.v[0], 0x05);
+        assert_eq!(chip8.cycles, 1);
+
+        chip8.tick(now);
+        for _ in 0..10 {
+            now += Duration::from_millis(10);
+            chip8.tick(now);
+        }
+        assert_eq!(chip8.registers.pc, 0x202);
+        assert_eq!(chip8.cycles, 1);
+        assert_eq!(chip8.history.to_string().matches("8009").count(), 1);
+    }
+
+    #[test]
+    fn reset_after_halt() {
+        let mut chip8 = chip8_with_rom(vec![0x60, 0x05, 0x80, 0x09]);
+
+        chip8.step();
+        chip8.step();
+        chip8.reset();
+        assert_eq!(chip8.halted, None);
+        assert_eq!(chip8.registers.pc, 0x200);
+        assert_eq!(chip8.registers.v[0], 0x00);
+        assert_eq!(chip8.cycles, 0);
+
+        chip8.step();
+        assert_eq!(chip8.registers.v[0], 0x05);
+    }
+
+    fn chip8_with_quirks(rom: Vec<u8>, quirks: Quirks) -> Chip8 {
+        let flags = Flags {
+            quirks,
+            ..flags_with_rom(rom)
+        };
+        Chip8::with_buzzer(flags, Buzzer::silent())
+    }
+
+    #[test]
+    fn shift_in_place() {
+        // 8126: SHR V1 {V2}, 834E: SHL V3 {V4}
+        let mut chip8 = chip8_with_quirks(vec![0x81, 0x26, 0x83, 0x4E], Quirks::default());
+        chip8.registers.v[1..=4].copy_from_slice(&[0x05, 0xF0, 0x81, 0x0F]);
+
+        chip8.step();
+        assert_eq!(chip8.registers.v[1], 0x02);
+        assert_eq!(chip8.registers.v[0xF], 0x01);
+        chip8.step();
+        assert_eq!(chip8.registers.v[3], 0x02);
+        assert_eq!(chip8.registers.v[0xF], 0x01);
+        assert_eq!(chip8.registers.v[2], 0xF0);
+        assert_eq!(chip8.registers.v[4], 0x0F);
+    }
+
+    #[test]
+    fn shift_from_vy() {
+        let quirks = Quirks {
+            shift_from_vy: true,
+            ..Quirks::default()
+        };
+        // 8126: SHR V1 V2, 834E: SHL V3 V4
+        let mut chip8 = chip8_with_quirks(vec![0x81, 0x26, 0x83, 0x4E], quirks);
+        chip8.registers.v[1..=4].copy_from_slice(&[0x05, 0xF1, 0x81, 0x8F]);
+
+        chip8.step();
+        assert_eq!(chip8.registers.v[1], 0x78);
+        assert_eq!(chip8.registers.v[0xF], 0x01);
+        chip8.step();
+        assert_eq!(chip8.registers.v[3], 0x1E);
+        assert_eq!(chip8.registers.v[0xF], 0x01);
+        assert_eq!(chip8.registers.v[2], 0xF1);
+        assert_eq!(chip8.registers.v[4], 0x8F);
+    }
+
+    #[test]
+    fn shift_into_vf_keeps_the_flag() {
+        for shift_from_vy in [false, true] {
+            let quirks = Quirks {
+                shift_from_vy,
+                ..Quirks::default()
+            };
+            // 8F16: SHR VF V1, 8F1E: SHL VF V1
+            let mut chip8 = chip8_with_quirks(vec![0x8F, 0x16, 0x8F, 0x1E], quirks);
+            chip8.registers.v[0x1] = 0x03;
+            chip8.registers.v[0xF] = 0x03;
+
+            chip8.step();
+            assert_eq!(chip8.registers.v[0xF], 0x01);
+            chip8.registers.v[0xF] = 0x80;
+            chip8.registers.v[0x1] = 0x80;
+            chip8.step();
+            assert_eq!(chip8.registers.v[0xF], 0x01);
+        }
+    }
+
+    #[test]
+    fn load_store_leave_or_increment_i() {
+        for memory_increments_i in [false, true] {
+            let quirks = Quirks {
+                memory_increments_i,
+                ..Quirks::default()
+            };
+            for x in [0x0u16, 0x7, 0xF] {
+                // FX55: LD [I] VX, FX65: LD VX [I]
+                for opcode in [0xF055 | (x << 8), 0xF065 | (x << 8)] {
+                    let mut chip8 = chip8_with_quirks(opcode.to_be_bytes().to_vec(), quirks);
+                    chip8.registers.i = 0x300;
+
+                    chip8.step();
+                    let expected = if memory_increments_i {
+                        0x300 + x + 1
+                    } else {
+                        0x300
+                    };
+                    assert_eq!(chip8.registers.i, expected, "{:04X}", opcode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_store_advance_i_per_profile() {
+        for (profile, expected) in [("chip8", 0x304), ("chip48", 0x303), ("superchip", 0x300)] {
+            let quirks = Quirks::of_profile(profile).unwrap();
+            // 0200: LD [I] V3, 0202: LD I 0300, 0204: LD V3 [I]
+            let rom = vec![0xF3, 0x55, 0xA3, 0x00, 0xF3, 0x65];
+            let mut chip8 = chip8_with_quirks(rom, quirks);
+            chip8.registers.i = 0x300;
+
+            chip8.step();
+            assert_eq!(chip8.registers.i, expected, "FX55 on {}", profile);
+            chip8.step();
+            chip8.step();
+            assert_eq!(chip8.registers.i, expected, "FX65 on {}", profile);
+        }
+    }
+
+    #[test]
+    fn jump_with_v0_or_vx() {
+        for (jump_with_vx, expected) in [(false, 0x212), (true, 0x214)] {
+            let quirks = Quirks {
+                jump_with_vx,
+                ..Quirks::default()
+            };
+            // B210: JP V0 0x210 / JP V2 0x210
+            let mut chip8 = chip8_with_quirks(vec![0xB2, 0x10], quirks);
+            chip8.registers.v[0x0] = 0x02;
+            chip8.registers.v[0x2] = 0x04;
+
+            chip8.step();
+            assert_eq!(chip8.registers.pc, expected);
+        }
+    }
+
+    #[test]
+    fn logic_ops_reset_vf_or_not() {
+        for logic_resets_vf in [false, true] {
+            let quirks = Quirks {
+                logic_resets_vf,
+                ..Quirks::default()
+            };
+            // 8121: OR V1 V2, 8122: AND V1 V2, 8123: XOR V1 V2
+            for (opcode, result) in [(0x8121u16, 0x3C), (0x8122, 0x08), (0x8123, 0x34)] {
+                let mut chip8 = chip8_with_quirks(opcode.to_be_bytes().to_vec(), quirks);
+                chip8.registers.v[0x1] = 0x0C;
+                chip8.registers.v[0x2] = 0x38;
+                chip8.registers.v[0xF] = 0x07;
+
+                chip8.step();
+                assert_eq!(chip8.registers.v[0x1], result, "{:04X}", opcode);
+                let vf = if logic_resets_vf { 0x00 } else { 0x07 };
+                assert_eq!(chip8.registers.v[0xF], vf, "{:04X}", opcode);
+            }
+
+            // 8F21: OR VF V2
+            let mut chip8 = chip8_with_quirks(vec![0x8F, 0x21], quirks);
+            chip8.registers.v[0x2] = 0x38;
+            chip8.step();
+            let vf = if logic_resets_vf { 0x00 } else { 0x38 };
+            assert_eq!(chip8.registers.v[0xF], vf);
+        }
+    }
+
+    #[test]
+    fn display_wait_holds_draws_until_timer_tick() {
+        for (display_wait, expected) in [(false, 3), (true, 1)] {
+            let quirks = Quirks {
+                display_wait,
+                ..Quirks::default()
+            };
+            // 0200: DRW V0 V0 5, 0202: DRW V0 V0 5, 0204: JP 0204
+            let mut chip8 = chip8_with_quirks(vec![0xD0, 0x05, 0xD0, 0x05, 0x12, 0x04], quirks);
+            let mut now = Instant::now();
+
+            chip8.tick(now);
+            now += Duration::from_millis(10);
+            chip8.tick(now);
+            assert_eq!(chip8.cycles, expected);
+            assert_eq!(chip8.registers.pc, if display_wait { 0x202 } else { 0x204 });
+
+            now += Duration::from_millis(8);
+            chip8.tick(now);
+            assert_eq!(chip8.timer_ticks, 1);
+            assert_eq!(chip8.registers.pc, 0x204);
+        }
+    }
+
+    #[test]
+    fn add_to_i_near_the_end_of_memory() {
+        for index_overflow_sets_vf in [false, true] {
+            let quirks = Quirks {
+                index_overflow_sets_vf,
+                ..Quirks::default()
+            };
+            for (vx, i, vf) in [(0x01, 0xFFF, 0x00), (0x03, 0x001, 0x01)] {
+                // F11E: ADD I V1
+                let mut chip8 = chip8_with_quirks(vec![0xF1, 0x1E], quirks);
+                chip8.registers.i = 0xFFE;
+                chip8.registers.v[0x1] = vx;
+                chip8.registers.v[0xF] = 0x07;
+
+                chip8.step();
+                assert_eq!(chip8.registers.i, i);
+                let vf = if index_overflow_sets_vf { vf } else { 0x07 };
+                assert_eq!(chip8.registers.v[0xF], vf);
+            }
+        }
+    }
+
+    fn random_bytes(seed: u64, count: usize) -> Vec<u8> {
+        // 0200: RND V0 0xFF, 0202: JP 0200
+        let flags = Flags {
+            seed: Some(seed),
+            ..flags_with_rom(vec![0xC0, 0xFF, 0x12, 0x00])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        (0..count)
+            .map(|_| {
+                chip8.step();
+                chip8.step();
+                chip8.registers.v[0x0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeded_random_bytes() {
+        assert_eq!(
+            random_bytes(7, 8),
+            vec![0xBE, 0xE9, 0x86, 0xE8, 0xCD, 0x46, 0x99, 0x05]
+        );
+        assert_ne!(random_bytes(8, 8), random_bytes(7, 8));
+    }
+
+    #[test]
+    fn random_covers_full_byte_range() {
+        assert!(random_bytes(7, 4096).contains(&0xFF));
+    }
+
+    #[test]
+    fn key_wait_completes_on_release() {
+        // 0200: LD V3 K, 0202: LD V4 0x01
+        let mut chip8 = chip8_with_rom(vec![0xF3, 0x0A, 0x64, 0x01]);
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        chip8.step();
+        chip8.on_keyboard(KeyboardMessage::Press(0x7));
+        now += Duration::from_millis(10);
+        chip8.tick(now);
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Release(3, 0x7)));
+        assert_eq!(chip8.registers.v[4], 0x00);
+
+        chip8.on_keyboard(KeyboardMessage::Release(0x2));
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Release(3, 0x7)));
+        chip8.on_keyboard(KeyboardMessage::Release(0x7));
+        assert_eq!(chip8.waiting_key, None);
+        assert_eq!(chip8.registers.v[3], 0x7);
+        now += Duration::from_millis(10);
+        chip8.tick(now);
+        assert_eq!(chip8.registers.v[4], 0x01);
+    }
+
+    #[test]
+    fn held_key_does_not_satisfy_key_wait() {
+        let mut chip8 = chip8_with_rom(vec![0xF3, 0x0A]);
+
+        chip8.on_keyboard(KeyboardMessage::Press(0x7));
+        chip8.step();
+        assert_eq!(chip8.waiting_key, Some(KeyWait::Release(3, 0x7)));
+        chip8.on_keyboard(KeyboardMessage::Release(0x7));
+        assert_eq!(chip8.registers.v[3], 0x7);
+    }
+
+    #[test]
+    fn sys_is_ignored() {
+        // 0200: SYS 0x0234, 0202: SYS 0x0000, 0204: SYS 0x0FFF, 0206: JP 0200
+        let mut chip8 = chip8_with_rom(vec![0x02, 0x34, 0x00, 0x00, 0x0F, 0xFF, 0x12, 0x00]);
+        chip8.registers.v[0x1] = 0x42;
+
+        for _ in 0..8 {
+            chip8.step();
+        }
+        assert_eq!(chip8.halted, None);
+        assert_eq!(chip8.registers.pc, 0x200);
+        assert_eq!(chip8.registers.v[0x1], 0x42);
+        assert_eq!(chip8.ignored_sys, HashSet::from([0x0234, 0x0000, 0x0FFF]));
+    }
+
+    #[test]
+    fn jump_to_self_finishes_the_rom() {
+        let mut chip8 = chip8_with_rom(vec![0x12, 0x00]);
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        for _ in 0..3 {
+            now += Duration::from_millis(10);
+            chip8.tick(now);
+        }
+        assert!(chip8.finished);
+        assert_eq!(chip8.halted, None);
+        assert_eq!(chip8.cycles, 1);
+        assert_eq!(chip8.title(), "CHIP-8 Emulator [finished]");
+    }
+
+    #[test]
+    fn opcode_profile_counts_executed_instructions() {
+        // 0200: LD V0 0x01, 0202: ADD V0 0x01, 0204: JP 0202
+        let flags = Flags {
+            profile_opcodes: true,
+            ..flags_with_rom(vec![0x60, 0x01, 0x70, 0x01, 0x12, 0x02])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        for _ in 0..7 {
+            chip8.step();
+        }
+        let report = chip8.opcode_profile.as_ref().unwrap().to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[1], "1NNN               3    42.86%");
+        assert_eq!(lines[2], "7XKK               3    42.86%");
+        assert_eq!(lines[3], "6XKK               1    14.29%");
+        assert_eq!(lines[6], "0202               3    42.86%");
+    }
+
+    #[test]
+    fn execute_returns_the_error_and_step_halts() {
+        let mut chip8 = chip8_with_rom(vec![0x00, 0xEE]);
+
+        assert_eq!(
+            chip8.execute(Instruction::Ret),
+            Err(Chip8Error::StackUnderflow { pc: 0x200 })
+        );
+        assert_eq!(
+            chip8.execute(Instruction::Call(0xFFF)),
+            Err(Chip8Error::JumpOutOfRange {
+                pc: 0x200,
+                target: 0xFFF
+            })
+        );
+        assert_eq!(chip8.halted, None);
+
+        chip8.step();
+        assert_eq!(chip8.halted, Some(Chip8Error::StackUnderflow { pc: 0x200 }));
+        assert!(chip8
+            .title()
+            .contains("[HALTED: stack underflow at PC=0x0200]"));
+    }
+
+    fn delta_of(rom: Vec<u8>, steps: usize) -> String {
+        let mut chip8 = chip8_with_rom(rom);
+        for _ in 1..steps {
+            chip8.step();
+        }
+        let pc = chip8.registers.pc;
+        let opcode = u16::from_be_bytes([chip8.memory.load(pc), chip8.memory.load(pc + 1)]);
+        chip8.apply_with_delta(decode(opcode).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn delta_trace_shows_changes_only() {
+        // 0200: LD V3 0x10, 0202: ADD V3 7
+        assert_eq!(
+            delta_of(vec![0x63, 0x10, 0x73, 0x07], 2),
+            "0202: ADD V3 7  ; V3: 0x10 -> 0x17"
+        );
+        // 0200: LD V0 0xFF, 0202: LD V1 0x01, 0204: ADD V0 V1
+        assert_eq!(
+            delta_of(vec![0x60, 0xFF, 0x61, 0x01, 0x80, 0x14], 3),
+            "0204: ADD V0 V1  ; V0: 0xFF -> 0x00, VF: 0x00 -> 0x01"
+        );
+        // 0200: LD I 0x300
+        assert_eq!(
+            delta_of(vec![0xA3, 0x00], 1),
+            "0200: LD I 0300  ; I: 0x0000 -> 0x0300"
+        );
+        // 0200: CALL 0204, 0202: (unused), 0204: LD V5 0x3C, 0206: LD DT V5
+        assert_eq!(
+            delta_of(vec![0x22, 0x04], 1),
+            "0200: CALL 0204  ; SP: 0 -> 1"
+        );
+        assert_eq!(
+            delta_of(vec![0x22, 0x04, 0x00, 0x00, 0x65, 0x3C, 0xF5, 0x15], 3),
+            "0206: LD DT V5  ; DT: 0x00 -> 0x3C"
+        );
+        // 0200: JP 0200
+        assert_eq!(delta_of(vec![0x12, 0x00], 1), "0200: JP 0200");
+    }
+
+    proptest! {
+        #[test]
+        fn i_relative_access_near_the_end(
+            i in 0xFF0u16..=0xFFF,
+            opcode in prop::sample::select(vec![0xF033u16, 0xFF55, 0xF755, 0xFF65]),
+            strict: bool,
+        ) {
+            let [i1, i2] = (0xA000 | i).to_be_bytes();
+            let [o1, o2] = opcode.to_be_bytes();
+            let flags = Flags {
+                strict_memory: strict,
+                ..flags_with_rom(vec![i1, i2, o1, o2])
+            };
+            let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+            chip8.step();
+            chip8.step();
+            let len = match decode(opcode).unwrap() {
+                Instruction::LdB(_) => 3,
+                Instruction::LdIVx(x) | Instruction::LdVxI(x) => x as usize + 1,
+                _ => unreachable!(),
+            };
+            let overruns = MEMORY_SIZE < i as usize + len;
+            let expected = (strict && overruns).then_some(Chip8Error::MemoryOutOfRange {
+                pc: 0x202,
+                addr: i,
+                len: len as u16,
+            });
+            prop_assert_eq!(chip8.halted, expected);
+        }
+    }
+
+    #[test]
+    fn odd_jump_target_warns_or_halts() {
+        // 0200: JP 0203, 0203: JP 0207, 0207: JP 0203
+        let rom = vec![0x12, 0x03, 0x00, 0x12, 0x07, 0x00, 0x00, 0x12, 0x03];
+        let mut chip8 = chip8_with_rom(rom.clone());
+        for _ in 0..6 {
+            chip8.step();
+        }
+        assert!(chip8.halted.is_none());
+        assert_eq!(chip8.registers.pc, 0x207);
+        assert_eq!(chip8.misaligned_targets, HashSet::from([0x203, 0x207]));
+
+        let flags = Flags {
+            strict_alignment: true,
+            ..flags_with_rom(rom)
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        chip8.step();
+        assert_eq!(
+            chip8.halted,
+            Some(Chip8Error::MisalignedJump {
+                pc: 0x200,
+                target: 0x203
+            })
+        );
+        assert_eq!(chip8.registers.pc, 0x200);
+    }
+
+    #[test]
+    fn tall_sprite_near_the_end_of_memory() {
+        for strict_memory in [false, true] {
+            // 0200: LD I 0FFA, 0202: DRW V0 V0 15
+            let flags = Flags {
+                strict_memory,
+                ..flags_with_rom(vec![0xAF, 0xFA, 0xD0, 0x0F])
+            };
+            let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+            for addr in 0xFFA..=0xFFF {
+                chip8.memory.store(addr, 0x80);
+            }
+
+            chip8.step();
+            chip8.step();
+            assert_eq!(chip8.halted, None);
+            assert_eq!(chip8.registers.pc, 0x204);
+            assert_eq!(chip8.stray_sprites, HashSet::from([0x202]));
+            assert!((0..6).all(|y| chip8.display.pixel(0, y)));
+            // The rest wraps around to the font, which starts with 0xF0.
+            assert_eq!(chip8.display.pixel(0, 6), !strict_memory);
+        }
+    }
+
+    #[test]
+    fn add_keeps_the_high_bits_of_a_long_index() {
+        // 0200: LD I LONG 2000, 0204: LD V0 0x34, 0206: ADD I V0
+        let rom = vec![0xF0, 0x00, 0x20, 0x00, 0x60, 0x34, 0xF0, 0x1E];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("xochip").unwrap());
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.registers.i, 0x2034);
+
+        chip8.registers.i = 0xFFF0;
+        chip8.registers.pc = 0x206;
+        chip8.step();
+        assert_eq!(chip8.registers.i, 0x0024);
+    }
+
+    #[test]
+    fn strict_memory_draws_nothing_from_a_long_index_past_memory() {
+        // 0200: LD I LONG 2000, 0204: DRW V0 V0 5
+        let flags = Flags {
+            strict_memory: true,
+            quirks: Quirks::of_profile("xochip").unwrap(),
+            ..flags_with_rom(vec![0xF0, 0x00, 0x20, 0x00, 0xD0, 0x05])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        chip8.step();
+        chip8.step();
+        assert_eq!(chip8.halted, None);
+        assert_eq!(chip8.registers.pc, 0x206);
+        assert!(chip8.display.packed().iter().all(|&b| b == 0));
+        assert_eq!(chip8.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn lint_vf_reports_flags_overwriting_unread_values() {
+        // 0200: LD VF 5, 0202: LD V0 1, 0204: ADD V0 V0, 0206: LD VF 7,
+        // 0208: SE VF 7, 020A: (skipped), 020C: ADD V0 V0, 020E: JP 020E
+        let rom = vec![
+            0x6F, 0x05, 0x60, 0x01, 0x80, 0x04, 0x6F, 0x07, 0x3F, 0x07, 0x00, 0x00, 0x80, 0x04,
+            0x12, 0x0E,
+        ];
+        let flags = Flags {
+            lint_vf: true,
+            ..flags_with_rom(rom)
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        for _ in 0..6 {
+            chip8.step();
+        }
+        assert_eq!(chip8.registers.pc, 0x20E);
+        assert_eq!(chip8.vf_clobbers, HashSet::from([(0x200, 0x204)]));
+    }
+
+    #[test]
+    fn exit_stops_schip_programs_only() {
+        // 0200: LD V0 1, 0202: EXIT, 0204: LD V0 2
+        let rom = vec![0x60, 0x01, 0x00, 0xFD, 0x60, 0x02];
+        let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile("superchip").unwrap());
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert!(chip8.exited && chip8.should_exit());
+        assert_eq!((chip8.registers.v[0], chip8.registers.pc), (0x01, 0x202));
+        assert_eq!(chip8.cycles, 2);
+
+        let mut chip8 = chip8_with_rom(rom);
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert!(!chip8.exited);
+        assert_eq!(chip8.registers.v[0], 0x02);
+    }
+
+    #[test]
+    fn high_and_low_switch_the_resolution() {
+        // 0200: HIGH, 0202: LD V0 127, 0204: DRW V0 V0 1, 0206: LOW
+        let rom = vec![0x00, 0xFF, 0x60, 0x7F, 0xD0, 0x01, 0x00, 0xFE];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("superchip").unwrap());
+        chip8.registers.i = Memory::font_addr(0);
+
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.display.width(), 128);
+        assert!(chip8.display.pixel(127, 127 % 64) && !chip8.display.pixel(0, 63));
+        chip8.step();
+        assert_eq!(chip8.display.width(), 64);
+        assert!(chip8.display.packed().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn big_sprites_clip_and_count_collided_rows() {
+        // 0200: HIGH, 0202: LD V0 120, 0204: DRW V0 V0 0, 0206: DRW V0 V0 0
+        let rom = vec![0x00, 0xFF, 0x60, 0x78, 0xD0, 0x00, 0xD0, 0x00];
+        for (profile, collided) in [("superchip", 8), ("xochip", 1)] {
+            let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile(profile).unwrap());
+            chip8.registers.i = 0x300;
+            for offset in 0..32 {
+                chip8.memory.store(0x300 + offset, 0xFF);
+            }
+
+            for _ in 0..3 {
+                chip8.step();
+            }
+            assert_eq!(chip8.registers.v[0xF], 0);
+            if profile == "superchip" {
+                assert!(chip8.display.pixel(127, 63) && chip8.display.pixel(120, 56));
+                assert!(!chip8.display.pixel(0, 56) && !chip8.display.pixel(120, 0));
+            }
+            chip8.step();
+            assert_eq!(chip8.registers.v[0xF], collided);
+        }
+    }
+
+    #[test]
+    fn scroll_down_by_half_in_lores_per_profile() {
+        // 0200: DRW V0 V0 1, 0202: SCD 4, 0204: HIGH, 0206: DRW V0 V0 1, 0208: SCD 4
+        let rom = vec![0xD0, 0x01, 0x00, 0xC4, 0x00, 0xFF, 0xD0, 0x01, 0x00, 0xC4];
+        for (profile, lores_rows) in [("superchip", 2), ("xochip", 4)] {
+            let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile(profile).unwrap());
+            chip8.registers.i = Memory::font_addr(0);
+
+            chip8.step();
+            chip8.step();
+            assert!(chip8.display.pixel(0, lores_rows) && !chip8.display.pixel(0, 0));
+            for _ in 0..3 {
+                chip8.step();
+            }
+            assert!(chip8.display.pixel(0, 4) && !chip8.display.pixel(0, 0));
+        }
+    }
+
+    #[test]
+    fn scroll_sideways_by_half_in_lores_per_profile() {
+        // 0200: DRW V0 V0 1, 0202: SCR, 0204: SCL, 0206: SCL
+        let rom = vec![0xD0, 0x01, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC];
+        for (profile, lores_columns) in [("superchip", 2), ("xochip", 4)] {
+            let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile(profile).unwrap());
+            chip8.registers.i = Memory::font_addr(0);
+
+            chip8.step();
+            chip8.step();
+            assert!(chip8.display.pixel(lores_columns, 0) && !chip8.display.pixel(0, 0));
+            chip8.step();
+            chip8.step();
+            // The top row of the glyph is four pixels wide.
+            let lit = (0..64).filter(|&x| chip8.display.pixel(x, 0)).count();
+            assert_eq!(lit, 4 - lores_columns);
+            assert!(!chip8.display.pixel(63, 0));
+        }
+    }
+
+    #[test]
+    fn rpl_flags_keep_only_eight_registers() {
+        // 0200: LD R VF, 0202: LD VF R
+        let rom = vec![0xFF, 0x75, 0xFF, 0x85];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("superchip").unwrap());
+        for (index, v) in chip8.registers.v.iter_mut().enumerate() {
+            *v = index as u8 + 1;
+        }
+
+        chip8.step();
+        chip8.registers.v = [0; 16];
+        chip8.step();
+        assert_eq!(chip8.registers.v[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(chip8.registers.v[8..], [0; 8]);
+        assert_eq!(chip8.registers.pc, 0x204);
+    }
+
+    #[test]
+    fn big_font_digits_are_drawn_in_hires() {
+        // 0200: HIGH, 0202: LD V0 8, 0204: LD HF V0, 0206: DRW V1 V1 A
+        let rom = vec![0x00, 0xFF, 0x60, 0x08, 0xF0, 0x30, 0xD1, 0x1A];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("superchip").unwrap());
+
+        for _ in 0..4 {
+            chip8.step();
+        }
+        assert_eq!(chip8.registers.i, Memory::big_font_addr(8));
+        let rows: Vec<_> = chip8
+            .display
+            .packed()
+            .chunks(16)
+            .map(|row| row[0])
+            .collect();
+        assert_eq!(
+            rows[..10],
+            [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn planes_take_consecutive_sprites() {
+        // 0200: PLANE 3, 0202: DRW V0 V0 1, 0204: PLANE 2, 0206: CLS
+        let rom = vec![0xF3, 0x01, 0xD0, 0x01, 0xF2, 0x01, 0x00, 0xE0];
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("xochip").unwrap());
+        chip8.registers.i = 0x300;
+        chip8.memory.store(0x300, 0xC0);
+        chip8.memory.store(0x301, 0x60);
+
+        chip8.step();
+        chip8.step();
+        let indices: Vec<_> = (0..4).map(|x| chip8.display.color_index(x, 0)).collect();
+        assert_eq!(indices, vec![1, 3, 2, 0]);
+        chip8.step();
+        chip8.step();
+        let indices: Vec<_> = (0..4).map(|x| chip8.display.color_index(x, 0)).collect();
+        assert_eq!(indices, vec![1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch_go_to_the_buzzer() {
+        // 0200: AUDIO, 0202: PITCH V3
+        let flags = Flags {
+            quirks: Quirks::of_profile("xochip").unwrap(),
+            ..flags_with_rom(vec![0xF0, 0x02, 0xF3, 0x3A])
+        };
+        let (buzzer, _, tones) = Buzzer::connected();
+        let mut chip8 = Chip8::with_buzzer(flags, buzzer);
+        chip8.registers.i = 0x300;
+        for offset in 0..16 {
+            chip8.memory.store(0x300 + offset, offset as u8);
+        }
+        chip8.registers.v[3] = 112;
+
+        chip8.step();
+        chip8.step();
+        let pattern: Vec<_> = (0..16).collect();
+        assert_eq!(
+            tones.try_recv(),
+            Ok(ToneChange::Pattern(pattern.try_into().unwrap()))
+        );
+        assert_eq!(tones.try_recv(), Ok(ToneChange::Pitch(112)));
+        assert_eq!(chip8.registers.pc, 0x204);
+    }
+
+    #[test]
+    fn scroll_up_only_on_xochip() {
+        // 0200: DRW V0 V1 1, 0202: SCU 4
+        let rom = vec![0xD0, 0x11, 0x00, 0xD4];
+        for (profile, row) in [("xochip", 6), ("superchip", 10), ("chip8", 10)] {
+            let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile(profile).unwrap());
+            chip8.registers.i = Memory::font_addr(0);
+            chip8.registers.v[1] = 10;
+
+            chip8.step();
+            chip8.step();
+            assert!(chip8.display.pixel(0, row), "{}", profile);
+            assert_eq!(chip8.halted, None);
+        }
+    }
+
+    #[test]
+    fn stop_exactly_at_max_cycles() {
+        // 0200: ADD V0 1, 0202: JP 0200
+        let flags = Flags {
+            max_cycles: Some(10),
+            ..flags_with_rom(vec![0x70, 0x01, 0x12, 0x00])
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        now += Duration::from_millis(100);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 10);
+        assert!(chip8.exited);
+        assert_eq!(
+            chip8.summary(),
+            "Stopped after 10 instructions: PC=0200, I=0000, V=[05, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00], display D80AC658736BB725"
+        );
+    }
+
+    #[test]
+    fn fields_edited_only_while_paused_and_in_bounds() {
+        let mut chip8 = chip8_with_rom(vec![0x12, 0x00]);
+
+        assert!(chip8.set_field(Field::V(3), 0x20).is_err());
+        chip8.paused = true;
+        chip8.set_field(Field::V(3), 0x20).unwrap();
+        chip8.set_field(Field::I, 0xFFF).unwrap();
+        chip8.set_field(Field::Pc, 0xFFE).unwrap();
+        chip8.set_field(Field::Sp, 16).unwrap();
+        chip8.set_field(Field::St, 0xFF).unwrap();
+        assert_eq!(chip8.registers.v[3], 0x20);
+        assert_eq!((chip8.registers.i, chip8.registers.pc), (0xFFF, 0xFFE));
+        assert_eq!((chip8.registers.sp, chip8.timers.st), (16, 0xFF));
+
+        for (field, value) in [
+            (Field::V(3), 0x100),
+            (Field::I, 0x1000),
+            (Field::Pc, 0xFFF),
+            (Field::Sp, 17),
+            (Field::Dt, 0x100),
+        ] {
+            assert_eq!(
+                chip8.set_field(field, value),
+                Err(format!(
+                    "{} out of range: 0x{:X} (at most 0x{:X})",
+                    field,
+                    value,
+                    value - 1
+                ))
+            );
+        }
+        assert_eq!(chip8.field_value(Field::V(3)), 0x20);
+        assert!(chip8.validate().is_ok());
+
+        let mut chip8 = chip8_with_quirks(vec![0x12, 0x00], Quirks::of_profile("xochip").unwrap());
+        chip8.paused = true;
+        chip8.set_field(Field::I, 0xFFFF).unwrap();
+        assert_eq!(chip8.registers.i, 0xFFFF);
+        assert!(chip8.validate().is_ok());
+    }
+
+    #[test]
+    fn fields_in_tab_order() {
+        assert_eq!(Field::at(0x3), Field::V(0x3));
+        assert_eq!(Field::at(16), Field::I);
+        assert_eq!(Field::at(20).to_string(), "ST");
+        assert_eq!(Field::at(FIELD_COUNT + 17).to_string(), "PC");
+    }
+
+    #[test]
+    fn break_opcode_pauses_instead_of_halting() {
+        // 0200: LD V0 1, 0202: (sentinel) FFFF, 0204: LD V1 2, 0206: ADD V2 1, 0208: JP 0206
+        let rom = vec![0x60, 0x01, 0xFF, 0xFF, 0x61, 0x02, 0x72, 0x01, 0x12, 0x06];
+        let flags = Flags {
+            break_opcode: Some(0xFFFF),
+            ..flags_with_rom(rom.clone())
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+        let mut now = Instant::now();
+
+        chip8.tick(now);
+        now += Duration::from_millis(100);
+        chip8.tick(now);
+        assert!(chip8.paused);
+        assert!(chip8.halted.is_none());
+        assert_eq!((chip8.cycles, chip8.registers.pc), (1, 0x204));
+        assert_eq!(chip8.title(), "CHIP-8 Emulator [paused] [V0=0x1]");
+        now += Duration::from_millis(100);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 1);
+
+        chip8.step();
+        assert_eq!(chip8.registers.v[0x1], 0x02);
+        chip8.paused = false;
+        now += Duration::from_millis(100);
+        chip8.tick(now);
+        assert_eq!(chip8.cycles, 52);
+
+        let mut chip8 = chip8_with_rom(rom);
+        chip8.step();
+        chip8.step();
+        assert!(chip8.halted.is_some());
+    }
+
+    #[test]
+    fn coverage_of_a_short_program() {
+        // 0200: LD V0 2, 0202: ADD V0 -1, 0204: SE V0 0, 0206: JP 0202,
+        // 0208: JP 020A, 020A: JP 0208, 020C: CLS
+        let rom = vec![
+            0x60, 0x02, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x02, 0x12, 0x0A, 0x12, 0x08, 0x00, 0xE0,
+        ];
+        let flags = Flags {
+            coverage: true,
+            ..flags_with_rom(rom)
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        for _ in 0..9 {
+            chip8.step();
+        }
+        assert_eq!(
+            chip8.coverage.as_ref().unwrap().to_string(),
+            "00E0 0\n1NNN 4\n3XKK 2\n6XKK 1\n7XKK 2\n"
+        );
+    }
+
+    #[test]
+    fn call_profile_of_nested_calls() {
+        // 0200: CALL 0208, 0202: CALL 0208, 0204: JP 0206, 0206: JP 0204,
+        // 0208: CALL 020E, 020A: LD V0 1, 020C: RET, 020E: ADD V1 1, 0210: RET
+        let rom = vec![
+            0x22, 0x08, 0x22, 0x08, 0x12, 0x06, 0x12, 0x04, 0x22, 0x0E, 0x60, 0x01, 0x00, 0xEE,
+            0x71, 0x01, 0x00, 0xEE,
+        ];
+        let flags = Flags {
+            profile_calls: Some(CallReport::Text),
+            ..flags_with_rom(rom)
+        };
+        let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+
+        for _ in 0..12 {
+            chip8.step();
+        }
+        let report = chip8.call_profile.as_ref().unwrap().to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[1], "0200             0           2          12");
+        assert_eq!(lines[2], "0208             2           6          10");
+        assert_eq!(lines[3], "020E             2           4           4");
+        assert_eq!(lines[6], "0200    0208             2");
+        assert_eq!(lines[7], "0208    020E             2");
+    }
+
+    #[test]
+    fn delay_timer_runs_out_at_the_timer_frequency() {
+        for (timer_hz, millis) in [(50, 1200), (60, 1000)] {
+            // 0200: LD V0 60, 0202: LD DT V0, 0204: JP 0206, 0206: JP 0204
+            let flags = Flags {
+                timer_hz,
+                ..flags_with_rom(vec![0x60, 0x3C, 0xF0, 0x15, 0x12, 0x06, 0x12, 0x04])
+            };
+            let mut chip8 = Chip8::with_buzzer(flags, Buzzer::silent());
+            chip8.step();
+            chip8.step();
+            let mut now = Instant::now();
+
+            chip8.tick(now);
+            for _ in 1..millis {
+                now += Duration::from_millis(1);
+                chip8.tick(now);
+            }
+            assert_eq!(chip8.timers.dt, 1, "{} Hz", timer_hz);
+            now += Duration::from_millis(1);
+            chip8.tick(now);
+            assert_eq!(chip8.timers.dt, 0, "{} Hz", timer_hz);
+        }
+    }
+
+    #[test]
+    fn skips_step_over_long_instructions() {
+        for long_skips in [false, true] {
+            let quirks = Quirks {
+                long_skips,
+                ..Quirks::default()
+            };
+            // 0200: SE V0 0, 0202: F000 0300, 0206: SNE V0 0, 0208: F000 0300
+            let rom = vec![
+                0x30, 0x00, 0xF0, 0x00, 0x03, 0x00, 0x40, 0x00, 0xF0, 0x00, 0x03, 0x00,
+            ];
+            let mut chip8 = chip8_with_quirks(rom, quirks);
+
+            chip8.step();
+            assert_eq!(chip8.registers.pc, if long_skips { 0x206 } else { 0x204 });
+            if long_skips {
+                // Not taken, so SNE moves on by one instruction as usual.
+                chip8.step();
+                assert_eq!(chip8.registers.pc, 0x208);
+            }
+        }
+    }
+
+    #[test]
+    fn long_index_loads_the_following_word() {
+        // 0200: SE V0 0, 0202: LD I LONG 1234, 0206: SNE V0 0, 0208: LD I LONG 0300
+        let rom = vec![
+            0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x40, 0x00, 0xF0, 0x00, 0x03, 0x00,
+        ];
+        let mut chip8 = chip8_with_quirks(rom.clone(), Quirks::of_profile("xochip").unwrap());
+
+        chip8.step();
+        assert_eq!(chip8.registers.pc, 0x206);
+        chip8.step();
+        chip8.step();
+        assert_eq!((chip8.registers.pc, chip8.registers.i), (0x20C, 0x0300));
+
+        chip8.registers.pc = 0x202;
+        chip8.step();
+        assert_eq!((chip8.registers.pc, chip8.registers.i), (0x206, 0x1234));
+
+        let mut chip8 = chip8_with_quirks(rom, Quirks::of_profile("superchip").unwrap());
+        chip8.registers.pc = 0x202;
+        chip8.step();
+        assert!(matches!(
+            chip8.halted,
+            Some(Chip8Error::UnknownOpcode { word: 0xF000, .. })
+        ));
+    }
+
+    #[test]
+    fn hotkeys_stay_off_the_keypad() {
+        for (key_code, _) in KEY_MAP {
+            assert!(hotkey(key_code, false).is_none(), "{:?}", key_code);
+            assert!(hotkey(key_code, true).is_none(), "{:?}", key_code);
+        }
+    }
 }
@@ -0,0 +1,45 @@
+// The demo ROMs were written for this project and are distributed under
+// the same MIT license as the rest of the repository.
+//
+// maze.ch8 draws a random maze of 4x4 diagonal tiles:
+//
+//   0200: LD V0 0        020E: ADD V0 4
+//   0202: LD V1 0        0210: SE V0 64
+//   0204: LD I 021E      0212: JP 0204
+//   0206: RND V2 1       0214: LD V0 0
+//   0208: SE V2 1        0216: ADD V1 4
+//   020A: LD I 0222      0218: SE V1 32
+//   020C: DRW V0 V1 4    021A: JP 0204
+//                        021C: JP 021C
+//   021E: sprites "\" and "/"
+//
+// keypad.ch8 shows the hex digit of the last key pressed:
+//
+//   0200: LD V0 K        0208: LD V2 13
+//   0202: CLS            020A: DRW V1 V2 5
+//   0204: LD F V0        020C: JP 0200
+//   0206: LD V1 28
+pub const DEMOS: [(&str, &[u8]); 2] = [
+    ("maze", include_bytes!("../demos/maze.ch8")),
+    ("keypad", include_bytes!("../demos/keypad.ch8")),
+];
+
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    DEMOS
+        .iter()
+        .find(|(demo_name, _)| *demo_name == name)
+        .map(|(_, rom)| *rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_by_name() {
+        for (name, rom) in DEMOS {
+            assert_eq!(find(name), Some(rom));
+        }
+        assert_eq!(find("pong"), None);
+    }
+}
@@ -1,23 +1,145 @@
-use iced::widget::canvas::{Canvas, Cursor, Frame, Geometry, Program};
+use crate::spans::span;
+
+use iced::widget::canvas::path::Builder;
+use iced::widget::canvas::{Canvas, Cursor, Frame, Geometry, Path, Program};
+use iced::widget::image::{Handle, Image};
 use iced::{Color, Element, Length, Point, Rectangle, Size};
+use log::{debug, trace};
 
 pub const WIDTH: usize = PIXEL_SIZE * DISPLAY_WIDTH + DISPLAY_FRAME * 2;
 pub const HEIGHT: usize = PIXEL_SIZE * DISPLAY_HEIGHT + DISPLAY_FRAME * 2;
+pub const FRAME_WIDTH: usize = PIXEL_SIZE * DISPLAY_WIDTH;
+pub const FRAME_HEIGHT: usize = PIXEL_SIZE * DISPLAY_HEIGHT;
 
 const DISPLAY_WIDTH: usize = 64;
 const DISPLAY_HEIGHT: usize = 32;
+const HIRES_WIDTH: usize = 128;
+const HIRES_HEIGHT: usize = 64;
 const DISPLAY_FRAME: usize = 5;
 const PIXEL_SIZE: usize = 10;
+const HIRES_PIXEL_SIZE: usize = PIXEL_SIZE / 2;
 const PIXEL_GAP: usize = 1;
+const DOT_SIZE: usize = 5;
+const PLANES: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Renderer {
+    Canvas,
+    Image,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PixelShape {
+    Square,
+    Circle,
+    Dot,
+}
+
+impl PixelShape {
+    fn add_to(&self, builder: &mut Builder, origin: Point, lit_size: f32) {
+        match self {
+            PixelShape::Square => builder.rectangle(origin, Size::new(lit_size, lit_size)),
+            PixelShape::Circle => builder.circle(
+                Point::new(origin.x + lit_size / 2.0, origin.y + lit_size / 2.0),
+                lit_size / 2.0,
+            ),
+            PixelShape::Dot => {
+                let dot_size = lit_size * DOT_SIZE as f32 / (PIXEL_SIZE - PIXEL_GAP) as f32;
+                let offset = (lit_size - dot_size) / 2.0;
+                builder.rectangle(
+                    Point::new(origin.x + offset, origin.y + offset),
+                    Size::new(dot_size, dot_size),
+                )
+            }
+        }
+    }
+
+    // Whether the shape covers the given offset within a pixel cell,
+    // sampled at the center of the window pixel.
+    fn covers(&self, lx: usize, ly: usize, pixel_size: usize) -> bool {
+        let lit_size = pixel_size - PIXEL_GAP;
+        match self {
+            PixelShape::Square => lx < lit_size && ly < lit_size,
+            PixelShape::Circle => {
+                let radius = lit_size as f32 / 2.0;
+                let (dx, dy) = (lx as f32 + 0.5 - radius, ly as f32 + 0.5 - radius);
+                dx * dx + dy * dy <= radius * radius
+            }
+            PixelShape::Dot => {
+                let dot_size = DOT_SIZE * pixel_size / PIXEL_SIZE;
+                let offset = (lit_size - dot_size) / 2;
+                (offset..offset + dot_size).contains(&lx)
+                    && (offset..offset + dot_size).contains(&ly)
+            }
+        }
+    }
+}
+
+// Cell geometry snapped to whole device pixels, so that every emulated pixel
+// and every gap keeps the same physical size under fractional scale factors.
+// The grid is centered inside the frame when the cells no longer fill it.
+#[derive(Debug, Clone, Copy)]
+struct CellLayout {
+    scale_factor: f64,
+    origin_x: u32,
+    origin_y: u32,
+    pitch: u32,
+    lit: u32,
+}
+
+impl CellLayout {
+    fn new(scale_factor: f64, pixel_size: usize) -> Self {
+        let device = |logical: usize| (logical as f64 * scale_factor).floor() as u32;
+        let pitch = device(pixel_size).max(2);
+        let gap = ((PIXEL_GAP as f64 * scale_factor).round() as u32).clamp(1, pitch - 1);
+        let frame = (DISPLAY_FRAME as f64 * scale_factor).round() as u32;
+        let margin =
+            |cells: usize| device(pixel_size * cells).saturating_sub(pitch * cells as u32) / 2;
+        CellLayout {
+            scale_factor,
+            origin_x: frame + margin(FRAME_WIDTH / pixel_size),
+            origin_y: frame + margin(FRAME_HEIGHT / pixel_size),
+            pitch,
+            lit: pitch - gap,
+        }
+    }
 
+    fn cell(&self, x: usize, y: usize) -> (Point, f32) {
+        let logical = |device: u32| (device as f64 / self.scale_factor) as f32;
+        (
+            Point::new(
+                logical(self.origin_x + x as u32 * self.pitch),
+                logical(self.origin_y + y as u32 * self.pitch),
+            ),
+            logical(self.lit),
+        )
+    }
+}
+
+// One row per u128 with the leftmost pixel in the highest bit of the active
+// width, so lores rows only use the low 64 bits and the top 32 rows. The
+// window keeps its size, with hires pixels drawn at half the size.
+//
+// XO-CHIP adds a second plane. The pixels of the two planes make a 2-bit
+// color index, and drawing, clearing and scrolling only touch the planes
+// selected by FN01.
 pub struct Display {
-    at: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    at: [[u128; HIRES_HEIGHT]; PLANES],
+    selected_planes: u8,
     pixel_color: iced::Color,
     background_color: iced::Color,
+    // For the pixels lit only in the second plane, then in both.
+    plane_colors: [iced::Color; 2],
+    pixel_shape: PixelShape,
+    layout: CellLayout,
+    hires_layout: CellLayout,
+    hires: bool,
+    clip_sprites: bool,
+    has_drawn: bool,
 }
 
 impl Display {
-    pub fn new(pixel_color: Color) -> Self {
+    pub fn new(pixel_color: Color, pixel_shape: PixelShape) -> Self {
         let darken = 0.1;
         let background_color = Color::new(
             darken * pixel_color.r,
@@ -25,68 +147,354 @@ impl Display {
             darken * pixel_color.b,
             1.0,
         );
+        let dim = |ratio: f32| {
+            Color::new(
+                ratio * pixel_color.r,
+                ratio * pixel_color.g,
+                ratio * pixel_color.b,
+                1.0,
+            )
+        };
         Display {
-            at: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            at: [[0; HIRES_HEIGHT]; PLANES],
+            selected_planes: 0b01,
             pixel_color,
             background_color,
+            plane_colors: [dim(0.45), dim(0.7)],
+            pixel_shape,
+            layout: CellLayout::new(1.0, PIXEL_SIZE),
+            hires_layout: CellLayout::new(1.0, HIRES_PIXEL_SIZE),
+            hires: false,
+            clip_sprites: false,
+            has_drawn: false,
+        }
+    }
+
+    pub fn set_scale_factor(&mut self, scale_factor: f64) {
+        self.layout = CellLayout::new(scale_factor, PIXEL_SIZE);
+        self.hires_layout = CellLayout::new(scale_factor, HIRES_PIXEL_SIZE);
+    }
+
+    // Switching resolution clears the screen, as SCHIP does.
+    pub fn set_hires(&mut self, hires: bool) {
+        debug!("Switching to {}", if hires { "128x64" } else { "64x32" });
+        self.hires = hires;
+        self.at = [[0; HIRES_HEIGHT]; PLANES];
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        debug!("Selecting the planes 0b{:02b}", planes & 0b11);
+        self.selected_planes = planes & 0b11;
+    }
+
+    pub fn selected_plane_count(&self) -> usize {
+        self.selected_planes.count_ones() as usize
+    }
+
+    fn selected(&self) -> impl Iterator<Item = usize> {
+        let planes = self.selected_planes;
+        (0..PLANES).filter(move |plane| planes >> plane & 1 == 1)
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            DISPLAY_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
         }
     }
 
+    fn pixel_size(&self) -> usize {
+        if self.hires {
+            HIRES_PIXEL_SIZE
+        } else {
+            PIXEL_SIZE
+        }
+    }
+
+    fn row_mask(&self) -> u128 {
+        u128::MAX >> (HIRES_WIDTH - self.width())
+    }
+
+    pub fn set_clip_sprites(&mut self, clip_sprites: bool) {
+        self.clip_sprites = clip_sprites;
+    }
+
     pub fn clear(&mut self) {
-        self.at = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        debug!("Clearing the display");
+        for plane in self.selected() {
+            self.at[plane] = [0; HIRES_HEIGHT];
+        }
     }
 
-    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
-        let mut collision = false;
+    // Both return the number of rows that hit a lit pixel. With both planes
+    // selected, the first half of the sprite goes to the first plane and the
+    // second half to the second.
+    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> usize {
+        let shift = self.width() - 8;
+        let rows = sprite.iter().map(|&line| (line as u128) << shift);
+        let collided_rows = self.draw_rows(x, y, rows);
 
-        for (offset_y, line) in sprite.iter().enumerate() {
-            let wrapped_y = (y as usize + offset_y) % DISPLAY_HEIGHT;
-            for offset_x in 0..8 {
-                let wrapped_x = (x as usize + offset_x) % DISPLAY_WIDTH;
-                let old = self.at[wrapped_y][wrapped_x];
-                let new = (line >> (7 - offset_x)) % 2 == 1;
-                self.at[wrapped_y][wrapped_x] = old ^ new;
-                if old && new {
-                    collision = true;
-                }
+        trace!(
+            "Drew a sprite of {} rows at ({}, {}), collided rows: {}",
+            sprite.len(),
+            x,
+            y,
+            collided_rows
+        );
+        collided_rows
+    }
+
+    pub fn draw_sprite16(&mut self, x: u8, y: u8, sprite: &[u16]) -> usize {
+        let shift = self.width() - 16;
+        let rows = sprite.iter().map(|&line| (line as u128) << shift);
+        let collided_rows = self.draw_rows(x, y, rows);
+
+        trace!(
+            "Drew a 16x{} sprite at ({}, {}), collided rows: {}",
+            sprite.len(),
+            x,
+            y,
+            collided_rows
+        );
+        collided_rows
+    }
+
+    // Scrolled-out rows are lost and the vacated ones are blank.
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        for plane in self.selected() {
+            self.at[plane].copy_within(0..height - n, n);
+            self.at[plane][..n].fill(0);
+        }
+        self.has_drawn = true;
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        for plane in self.selected() {
+            self.at[plane].copy_within(n..height, 0);
+            self.at[plane][height - n..height].fill(0);
+        }
+        self.has_drawn = true;
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        for plane in self.selected() {
+            for row in self.at[plane].iter_mut() {
+                *row = row.checked_shr(n as u32).unwrap_or(0);
+            }
+        }
+        self.has_drawn = true;
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        let mask = self.row_mask();
+        for plane in self.selected() {
+            for row in self.at[plane].iter_mut() {
+                *row = row.checked_shl(n as u32).unwrap_or(0) & mask;
             }
         }
+        self.has_drawn = true;
+    }
+
+    // Takes the rows aligned to the left edge of the screen and returns how
+    // many of them hit a lit pixel. The corner always wraps. When clipping,
+    // the pixels past the right and bottom edges are dropped instead of
+    // wrapping around.
+    // Sprites are drawn once per instruction, so nothing here allocates.
+    fn draw_rows(&mut self, x: u8, y: u8, mut rows: impl ExactSizeIterator<Item = u128>) -> usize {
+        let (width, height) = (self.width(), self.height());
+        let mut collided_rows = 0;
+        self.has_drawn = true;
+        let mut planes = [0; PLANES];
+        let mut plane_count = 0;
+        for plane in self.selected() {
+            planes[plane_count] = plane;
+            plane_count += 1;
+        }
+        if plane_count == 0 {
+            return 0;
+        }
 
-        collision
+        // Both resolutions are powers of two, so wrapping is a mask.
+        let (x, y) = (x as usize & (width - 1), y as usize & (height - 1));
+        let rows_per_plane = rows.len() / plane_count;
+        for &plane in &planes[..plane_count] {
+            for (offset_y, row) in rows.by_ref().take(rows_per_plane).enumerate() {
+                if self.clip_sprites && height <= y + offset_y {
+                    continue;
+                }
+                let wrapped_y = (y + offset_y) & (height - 1);
+                // A row fills a u64 in 64x32 and a u128 in 128x64, so
+                // wrapping is a rotate of that width.
+                let mask = if self.clip_sprites {
+                    row >> x
+                } else if self.hires {
+                    row.rotate_right(x as u32)
+                } else {
+                    (row as u64).rotate_right(x as u32) as u128
+                };
+                if self.at[plane][wrapped_y] & mask != 0 {
+                    collided_rows += 1;
+                }
+                self.at[plane][wrapped_y] ^= mask;
+            }
+        }
+        collided_rows
     }
 
-    pub fn view(&mut self) -> Element<()> {
+    pub fn view(&mut self) -> Element<'_, ()> {
         Canvas::new(self)
-            .width(Length::Units(
-                (PIXEL_SIZE * DISPLAY_WIDTH + DISPLAY_FRAME * 2) as u16,
-            ))
-            .height(Length::Units(
-                (PIXEL_SIZE * DISPLAY_HEIGHT + DISPLAY_FRAME * 2) as u16,
-            ))
+            .width(Length::Units(WIDTH as u16))
+            .height(Length::Units(HEIGHT as u16))
             .into()
     }
+
+    pub fn view_image(&self) -> Element<'_, ()> {
+        Image::new(Handle::from_pixels(
+            WIDTH as u32,
+            HEIGHT as u32,
+            self.to_bgra(),
+        ))
+        .width(Length::Units(WIDTH as u16))
+        .height(Length::Units(HEIGHT as u16))
+        .into()
+    }
+
+    pub fn has_drawn(&self) -> bool {
+        self.has_drawn
+    }
+
+    // Lit in any plane.
+    #[cfg(test)]
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.color_index(x, y) != 0
+    }
+
+    pub fn color_index(&self, x: usize, y: usize) -> usize {
+        let shift = self.width() - 1 - x;
+        (0..PLANES)
+            .map(|plane| ((self.at[plane][y] >> shift) & 1) as usize)
+            .enumerate()
+            .fold(0, |index, (plane, bit)| index | bit << plane)
+    }
+
+    fn color(&self, index: usize) -> Color {
+        match index {
+            0 => self.background_color,
+            1 => self.pixel_color,
+            _ => self.plane_colors[index - 2],
+        }
+    }
+
+    // Row-major, 8 pixels per byte with the leftmost pixel in the MSB, lit
+    // where any plane is.
+    pub fn packed(&self) -> Vec<u8> {
+        let bytes = self.width() / 8;
+        (0..self.height())
+            .flat_map(|y| (self.at[0][y] | self.at[1][y]).to_be_bytes()[16 - bytes..].to_vec())
+            .collect()
+    }
+
+    pub fn palette_rgba(&self) -> ([u8; 4], [u8; 4]) {
+        (rgba_of(self.pixel_color), rgba_of(self.background_color))
+    }
+
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let palette = [0, 1, 2, 3].map(|index| rgba_of(self.color(index)));
+        let pixel_size = self.pixel_size();
+        let mut pixels = Vec::with_capacity(FRAME_WIDTH * FRAME_HEIGHT * 4);
+        for py in 0..FRAME_HEIGHT {
+            for px in 0..FRAME_WIDTH {
+                let index = self.color_index(px / pixel_size, py / pixel_size);
+                pixels.extend_from_slice(&palette[index]);
+            }
+        }
+        pixels
+    }
+
+    // Rasterized at the window size, so the image is shown 1:1 and keeps
+    // the frame and the inter-pixel gap without relying on texture filtering.
+    fn to_bgra(&self) -> Vec<u8> {
+        let palette = [0, 1, 2, 3].map(|index| bgra_of(self.color(index)));
+        let mut pixels = Vec::with_capacity(WIDTH * HEIGHT * 4);
+        for py in 0..HEIGHT {
+            for px in 0..WIDTH {
+                pixels.extend_from_slice(&palette[self.color_index_at(px, py)]);
+            }
+        }
+        pixels
+    }
+
+    // The color index of a window pixel, which is 0 in the frame and gaps.
+    fn color_index_at(&self, px: usize, py: usize) -> usize {
+        if px < DISPLAY_FRAME || py < DISPLAY_FRAME {
+            return 0;
+        }
+        let pixel_size = self.pixel_size();
+        let (cx, cy) = (px - DISPLAY_FRAME, py - DISPLAY_FRAME);
+        let (x, y) = (cx / pixel_size, cy / pixel_size);
+        let covered = x < self.width()
+            && y < self.height()
+            && self
+                .pixel_shape
+                .covers(cx % pixel_size, cy % pixel_size, pixel_size);
+        if covered {
+            self.color_index(x, y)
+        } else {
+            0
+        }
+    }
+}
+
+fn rgba_of(color: Color) -> [u8; 4] {
+    let channel = |value: f32| (value * 255.0).round() as u8;
+    [
+        channel(color.r),
+        channel(color.g),
+        channel(color.b),
+        channel(color.a),
+    ]
+}
+
+fn bgra_of(color: Color) -> [u8; 4] {
+    let [r, g, b, a] = rgba_of(color);
+    [b, g, r, a]
 }
 
 impl Program<()> for Display {
     fn draw(&self, bounds: Rectangle, _cursor: Cursor) -> Vec<Geometry> {
+        span!("draw");
         let mut frame = Frame::new(bounds.size());
         frame.fill_rectangle(Point::ORIGIN, bounds.size(), self.background_color);
-        for y in 0..DISPLAY_HEIGHT {
-            for x in 0..DISPLAY_WIDTH {
-                if self.at[y][x] {
-                    frame.fill_rectangle(
-                        Point::new(
-                            (x * PIXEL_SIZE + DISPLAY_FRAME) as f32,
-                            (y * PIXEL_SIZE + DISPLAY_FRAME) as f32,
-                        ),
-                        Size::new(
-                            (PIXEL_SIZE - PIXEL_GAP) as f32,
-                            (PIXEL_SIZE - PIXEL_GAP) as f32,
-                        ),
-                        self.pixel_color,
-                    );
+        let layout = if self.hires {
+            &self.hires_layout
+        } else {
+            &self.layout
+        };
+        for index in 1..=3 {
+            let pixels = Path::new(|builder| {
+                for y in 0..self.height() {
+                    for x in 0..self.width() {
+                        if self.color_index(x, y) == index {
+                            let (origin, lit_size) = layout.cell(x, y);
+                            self.pixel_shape.add_to(builder, origin, lit_size);
+                        }
+                    }
                 }
-            }
+            });
+            frame.fill(&pixels, self.color(index));
         }
         vec![frame.into_geometry()]
     }
@@ -95,89 +503,417 @@ impl Program<()> for Display {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn clear_once() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
 
         display.clear();
-        assert_eq!(display.at, Display::new(Color::WHITE).at);
+        assert_eq!(
+            display.at,
+            Display::new(Color::WHITE, PixelShape::Square).at
+        );
     }
 
     #[test]
     fn clear_twice() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
 
         display.clear();
         display.clear();
-        assert_eq!(display.at, Display::new(Color::WHITE).at);
+        assert_eq!(
+            display.at,
+            Display::new(Color::WHITE, PixelShape::Square).at
+        );
     }
 
     #[test]
     fn clear_after_draw() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xFF; 8];
 
         display.draw_sprite(0, 0, sprite);
         display.clear();
-        assert_eq!(display.at, Display::new(Color::WHITE).at);
+        assert_eq!(
+            display.at,
+            Display::new(Color::WHITE, PixelShape::Square).at
+        );
     }
 
     #[test]
     fn draw_single_sprite_without_wrap() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xC0; 2];
 
         let collision = display.draw_sprite(0, 0, sprite);
-        assert!(display.at[0][0]);
-        assert!(display.at[0][1]);
-        assert!(display.at[1][0]);
-        assert!(display.at[1][1]);
-        assert!(!collision);
+        assert!(display.pixel(0, 0));
+        assert!(display.pixel(1, 0));
+        assert!(display.pixel(0, 1));
+        assert!(display.pixel(1, 1));
+        assert_eq!(collision, 0);
     }
 
     #[test]
     fn draw_single_sprite_with_wrap() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xC0; 2];
 
         let (max_x, max_y) = (DISPLAY_WIDTH as u8 - 1, DISPLAY_HEIGHT as u8 - 1);
         let collision = display.draw_sprite(max_x, max_y, sprite);
-        assert!(display.at[0][0]);
-        assert!(display.at[0][max_x as usize]);
-        assert!(display.at[max_y as usize][0]);
-        assert!(display.at[max_y as usize][max_x as usize]);
-        assert!(!collision);
+        assert!(display.pixel(0, 0));
+        assert!(display.pixel(max_x as usize, 0));
+        assert!(display.pixel(0, max_y as usize));
+        assert!(display.pixel(max_x as usize, max_y as usize));
+        assert_eq!(collision, 0);
+    }
+
+    #[test]
+    fn draw_single_sprite_with_clip() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_clip_sprites(true);
+        let sprite: &[u8] = &[0xC0; 2];
+
+        let (max_x, max_y) = (DISPLAY_WIDTH as u8 - 1, DISPLAY_HEIGHT as u8 - 1);
+        let collision = display.draw_sprite(max_x, max_y, sprite);
+        assert!(display.pixel(max_x as usize, max_y as usize));
+        assert!(!display.pixel(0, 0));
+        assert!(!display.pixel(max_x as usize, 0));
+        assert!(!display.pixel(0, max_y as usize));
+        assert_eq!(collision, 0);
+    }
+
+    #[test]
+    fn clipped_pixels_never_collide() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_clip_sprites(true);
+
+        display.draw_sprite(0, 0, &[0xFF]);
+        let collision = display.draw_sprite(60, 31, &[0x1F, 0xFF]);
+        assert_eq!(collision, 0);
+        assert!(!display.pixel(60, 31));
+        assert!(display.pixel(63, 31));
+        assert!(display.pixel(0, 0));
+    }
+
+    #[test]
+    fn clipped_sprite_corner_still_wraps() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_clip_sprites(true);
+
+        display.draw_sprite(DISPLAY_WIDTH as u8 + 2, DISPLAY_HEIGHT as u8 + 3, &[0x80]);
+        assert!(display.pixel(2, 3));
     }
 
     #[test]
     fn draw_single_sprite_twice() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xC0; 2];
 
         display.draw_sprite(0, 0, sprite);
         let collision = display.draw_sprite(0, 0, sprite);
-        assert_eq!(display.at, Display::new(Color::WHITE).at);
-        assert!(collision);
+        assert_eq!(
+            display.at,
+            Display::new(Color::WHITE, PixelShape::Square).at
+        );
+        assert!(0 < collision);
     }
 
     #[test]
     fn draw_sprites_without_collision() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xF0, 0xF0, 0xF0, 0xF0, 0x00, 0x00, 0x00, 0x00];
 
         display.draw_sprite(0, 0, sprite);
         let collision = display.draw_sprite(4, 4, sprite);
-        assert!(!collision);
+        assert_eq!(collision, 0);
+    }
+
+    #[test]
+    fn bgra_lit_pixel_with_gap() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        let sprite: &[u8] = &[0x80];
+
+        display.draw_sprite(0, 0, sprite);
+        let pixels = display.to_bgra();
+        let at = |px: usize, py: usize| &pixels[(py * WIDTH + px) * 4..(py * WIDTH + px + 1) * 4];
+        let (lit, unlit) = (
+            bgra_of(display.pixel_color),
+            bgra_of(display.background_color),
+        );
+        assert_eq!(pixels.len(), WIDTH * HEIGHT * 4);
+        assert_eq!(at(0, 0), unlit);
+        assert_eq!(at(DISPLAY_FRAME, DISPLAY_FRAME), lit);
+        assert_eq!(
+            at(DISPLAY_FRAME + PIXEL_SIZE - PIXEL_GAP - 1, DISPLAY_FRAME),
+            lit
+        );
+        assert_eq!(
+            at(DISPLAY_FRAME + PIXEL_SIZE - PIXEL_GAP, DISPLAY_FRAME),
+            unlit
+        );
+        assert_eq!(at(DISPLAY_FRAME + PIXEL_SIZE, DISPLAY_FRAME), unlit);
+    }
+
+    #[test]
+    fn bgra_circle_and_dot_shapes() {
+        let sprite: &[u8] = &[0x80];
+        let at = |display: &Display, px: usize, py: usize| {
+            display.color_index_at(DISPLAY_FRAME + px, DISPLAY_FRAME + py) == 1
+        };
+
+        let mut circle = Display::new(Color::WHITE, PixelShape::Circle);
+        circle.draw_sprite(0, 0, sprite);
+        assert!(!at(&circle, 0, 0));
+        assert!(at(&circle, 0, 4));
+        assert!(at(&circle, 4, 4));
+        assert!(!at(&circle, 8, 8));
+
+        let mut dot = Display::new(Color::WHITE, PixelShape::Dot);
+        dot.draw_sprite(0, 0, sprite);
+        assert!(!at(&dot, 1, 1));
+        assert!(at(&dot, 2, 2));
+        assert!(at(&dot, 6, 6));
+        assert!(!at(&dot, 7, 4));
+    }
+
+    #[test]
+    fn rgba_scaled_without_gap() {
+        let mut display = Display::new(Color::new(0.0, 0.95, 0.0, 1.0), PixelShape::Square);
+        let sprite: &[u8] = &[0x80];
+
+        display.draw_sprite(1, 0, sprite);
+        let pixels = display.to_rgba();
+        let at = |px: usize, py: usize| {
+            &pixels[(py * FRAME_WIDTH + px) * 4..(py * FRAME_WIDTH + px + 1) * 4]
+        };
+        assert_eq!(pixels.len(), FRAME_WIDTH * FRAME_HEIGHT * 4);
+        assert_eq!(at(PIXEL_SIZE - 1, 0), [0x00, 0x18, 0x00, 0xFF]);
+        assert_eq!(at(PIXEL_SIZE, 0), [0x00, 0xF2, 0x00, 0xFF]);
+        assert_eq!(
+            at(PIXEL_SIZE * 2 - 1, PIXEL_SIZE - 1),
+            [0x00, 0xF2, 0x00, 0xFF]
+        );
+        assert_eq!(at(PIXEL_SIZE * 2, 0), [0x00, 0x18, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn packed_msb_first() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        let sprite: &[u8] = &[0xA5];
+
+        display.draw_sprite(8, 1, sprite);
+        let packed = display.packed();
+        assert_eq!(packed.len(), DISPLAY_WIDTH * DISPLAY_HEIGHT / 8);
+        assert_eq!(packed[DISPLAY_WIDTH / 8 + 1], 0xA5);
+        assert_eq!(packed.iter().filter(|&&b| b != 0).count(), 1);
+    }
+
+    proptest! {
+
+        #[test]
+        fn draw_matches_pixelwise_xor(x: u8, y: u8, sprite in prop::collection::vec(any::<u8>(), 0..16)) {
+            let mut display = Display::new(Color::WHITE, PixelShape::Square);
+            let mut expected = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+
+            display.draw_sprite(0, 0, &[0xFF; 15]);
+            for row in expected.iter_mut().take(15) {
+                row[..8].fill(true);
+            }
+            let mut expected_collision = false;
+            for (offset_y, line) in sprite.iter().enumerate() {
+                for offset_x in 0..8 {
+                    let wrapped_y = (y as usize + offset_y) % DISPLAY_HEIGHT;
+                    let wrapped_x = (x as usize + offset_x) % DISPLAY_WIDTH;
+                    let new = (line >> (7 - offset_x)) % 2 == 1;
+                    expected_collision |= expected[wrapped_y][wrapped_x] && new;
+                    expected[wrapped_y][wrapped_x] ^= new;
+                }
+            }
+
+            let collision = display.draw_sprite(x, y, &sprite);
+            prop_assert_eq!(0 < collision, expected_collision);
+            for (py, row) in expected.iter().enumerate() {
+                for (px, &lit) in row.iter().enumerate() {
+                    prop_assert_eq!(display.pixel(px, py), lit);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cell_layout_unscaled() {
+        let layout = CellLayout::new(1.0, PIXEL_SIZE);
+
+        assert_eq!(layout.cell(0, 0), (Point::new(5.0, 5.0), 9.0));
+        assert_eq!(layout.cell(63, 31), (Point::new(635.0, 315.0), 9.0));
+    }
+
+    #[test]
+    fn cell_layout_snapped_to_device_pixels() {
+        for (scale_factor, pitch, lit) in [(1.25, 12, 11), (1.5, 15, 13), (1.75, 17, 15)] {
+            let layout = CellLayout::new(scale_factor, PIXEL_SIZE);
+            let device = |logical: f32| logical as f64 * scale_factor;
+
+            assert_eq!((layout.pitch, layout.lit), (pitch, lit));
+            for x in 0..DISPLAY_WIDTH {
+                let (origin, lit_size) = layout.cell(x, x % DISPLAY_HEIGHT);
+                let (next, _) = layout.cell(x + 1, x % DISPLAY_HEIGHT);
+                assert!((device(origin.x) - device(origin.x).round()).abs() < 1e-3);
+                assert!((device(next.x - origin.x) - pitch as f64).abs() < 1e-3);
+                assert!((device(lit_size) - lit as f64).abs() < 1e-3);
+            }
+            let (last, lit_size) = layout.cell(DISPLAY_WIDTH - 1, DISPLAY_HEIGHT - 1);
+            assert!(last.x + lit_size <= (WIDTH - DISPLAY_FRAME) as f32);
+            assert!(last.y + lit_size <= (HEIGHT - DISPLAY_FRAME) as f32);
+        }
+    }
+
+    #[test]
+    fn hires_doubles_the_resolution_in_the_same_window() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.draw_sprite(0, 0, &[0x80]);
+
+        display.set_hires(true);
+        assert!(!display.pixel(0, 0));
+        assert_eq!((display.width(), display.height()), (128, 64));
+        display.draw_sprite(127, 63, &[0xC0, 0xC0]);
+        assert!(display.pixel(127, 63) && display.pixel(0, 63));
+        assert!(display.pixel(127, 0) && display.pixel(0, 0));
+        assert_eq!(display.packed().len(), 128 * 64 / 8);
+        assert_eq!(display.packed()[0], 0x80);
+        assert_eq!(display.color_index_at(DISPLAY_FRAME, DISPLAY_FRAME), 1);
+        assert_eq!(
+            display.color_index_at(DISPLAY_FRAME + HIRES_PIXEL_SIZE, DISPLAY_FRAME),
+            0
+        );
+        let (last, lit_size) = display.hires_layout.cell(127, 63);
+        assert_eq!((last, lit_size), (Point::new(640.0, 320.0), 4.0));
+
+        display.set_hires(false);
+        assert_eq!(display.packed(), vec![0; 64 * 32 / 8]);
+    }
+
+    #[test]
+    fn hires_sprites_clip_at_the_hires_edges() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_clip_sprites(true);
+        display.set_hires(true);
+
+        display.draw_sprite(126, 63, &[0xFF, 0xFF]);
+        assert!(display.pixel(126, 63) && display.pixel(127, 63));
+        assert!(!display.pixel(0, 63) && !display.pixel(126, 0));
+    }
+
+    #[test]
+    fn big_sprites_wrap_at_the_right_edge() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_hires(true);
+
+        let collision = display.draw_sprite16(120, 0, &[0xFFFF, 0x8001]);
+        assert_eq!(collision, 0);
+        assert!(display.pixel(120, 0) && display.pixel(127, 0) && display.pixel(7, 0));
+        assert!(!display.pixel(8, 0) && display.pixel(7, 1) && !display.pixel(121, 1));
+        assert_eq!(display.draw_sprite16(120, 0, &[0x0001, 0x0001, 0xFFFF]), 2);
+    }
+
+    #[test]
+    fn scroll_down_blanks_the_top_rows() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.draw_sprite(0, 0, &[0x80, 0x40]);
+        display.draw_sprite(0, 31, &[0x01]);
+
+        display.scroll_down(3);
+        assert!(display.pixel(0, 3) && display.pixel(1, 4));
+        assert!(display.packed()[..3 * 8].iter().all(|&b| b == 0));
+        assert!(!display.pixel(7, 0) && !display.pixel(7, 31));
+
+        display.scroll_down(40);
+        assert!(display.packed().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn scroll_up_blanks_the_bottom_rows_of_the_selected_planes() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.draw_sprite(0, 30, &[0x80, 0x40]);
+        display.select_planes(0b10);
+        display.draw_sprite(0, 31, &[0x20]);
+
+        display.scroll_up(3);
+        assert_eq!(display.color_index(2, 28), 2);
+        assert_eq!(display.color_index(2, 31), 0);
+        assert!(display.pixel(0, 30) && display.pixel(1, 31));
+        display.select_planes(0b01);
+        display.scroll_up(3);
+        assert!(display.pixel(0, 27) && display.pixel(1, 28));
+        assert!(display.packed()[29 * 8..].iter().all(|&b| b == 0));
+
+        display.scroll_up(40);
+        assert_eq!(display.color_index(2, 28), 2);
+        assert!(!display.pixel(0, 27) && !display.pixel(1, 28));
+    }
+
+    #[test]
+    fn scroll_sideways_without_wrapping() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+        display.set_hires(true);
+        display.draw_sprite(0, 0, &[0x80]);
+        display.draw_sprite(120, 1, &[0x01]);
+
+        display.scroll_right(4);
+        assert!(display.pixel(4, 0) && !display.pixel(0, 0));
+        assert!(!display.pixel(127, 1) && !display.pixel(3, 1));
+        display.scroll_left(4);
+        display.scroll_left(4);
+        assert!(!display.pixel(0, 0) && !display.pixel(127, 0));
+        assert!(display.packed().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn planes_combine_into_color_indices() {
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
+
+        display.draw_sprite(0, 0, &[0xC0]);
+        display.select_planes(0b10);
+        display.draw_sprite(1, 0, &[0xC0]);
+        assert_eq!(
+            (0..4)
+                .map(|x| display.color_index(x, 0))
+                .collect::<Vec<_>>(),
+            vec![1, 3, 2, 0]
+        );
+        assert_eq!(display.packed()[0], 0xE0);
+
+        // Both planes take their own half of the sprite.
+        display.select_planes(0b11);
+        assert_eq!(display.selected_plane_count(), 2);
+        assert_eq!(display.draw_sprite(0, 1, &[0x80, 0x40]), 0);
+        assert_eq!(
+            (display.color_index(0, 1), display.color_index(1, 1)),
+            (1, 2)
+        );
+
+        display.select_planes(0b01);
+        display.clear();
+        assert_eq!(
+            (0..4)
+                .map(|x| display.color_index(x, 0))
+                .collect::<Vec<_>>(),
+            vec![0, 2, 2, 0]
+        );
+        let pixels = display.to_rgba();
+        let at = |x: usize| &pixels[x * PIXEL_SIZE * 4..x * PIXEL_SIZE * 4 + 4];
+        assert_eq!(at(1), rgba_of(display.plane_colors[0]));
+        assert_eq!(at(0), rgba_of(display.background_color));
     }
 
     #[test]
     fn draw_sprites_with_collision() {
-        let mut display = Display::new(Color::WHITE);
+        let mut display = Display::new(Color::WHITE, PixelShape::Square);
         let sprite: &[u8] = &[0xF0, 0xF0, 0xF0, 0xF0, 0x00, 0x00, 0x00, 0x00];
 
         display.draw_sprite(0, 0, sprite);
         let collision = display.draw_sprite(3, 3, sprite);
-        assert!(collision);
+        assert!(0 < collision);
     }
 }
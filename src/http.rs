@@ -0,0 +1,98 @@
+use crate::memory::MAX_ROM_SIZE;
+use sha1_smol::Sha1;
+use std::fs::{create_dir_all, read, write};
+use std::io::Read;
+use std::path::Path;
+
+const MAX_DOWNLOAD_SIZE: usize = MAX_ROM_SIZE;
+
+pub fn is_url(file_name: &str) -> bool {
+    file_name.starts_with("http://") || file_name.starts_with("https://")
+}
+
+pub fn fetch(url: &str, cache_dir: Option<&Path>) -> Result<Vec<u8>, String> {
+    let cached = cache_dir.map(|dir| dir.join(Sha1::from(url).digest().to_string()));
+    if let Some(rom) = cached.as_ref().and_then(|path| read(path).ok()) {
+        return Ok(rom);
+    }
+
+    let rom = download(url)?;
+    if let (Some(dir), Some(path)) = (cache_dir, cached) {
+        create_dir_all(dir)
+            .and_then(|_| write(path, &rom))
+            .map_err(|err| format!("Failed to cache {}: {}", url, err))?;
+    }
+    Ok(rom)
+}
+
+fn download(url: &str) -> Result<Vec<u8>, String> {
+    let response = ureq::get(url).call().map_err(|err| match err {
+        ureq::Error::Status(code, response) => {
+            format!(
+                "Failed to download {}: HTTP {} {}",
+                url,
+                code,
+                response.status_text()
+            )
+        }
+        ureq::Error::Transport(transport) => format!("Failed to download {}: {}", url, transport),
+    })?;
+    if response.status() != 200 {
+        return Err(format!(
+            "Failed to download {}: HTTP {} {}",
+            url,
+            response.status(),
+            response.status_text()
+        ));
+    }
+    read_capped(response.into_reader(), url)
+}
+
+fn read_capped(reader: impl Read, url: &str) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::new();
+    reader
+        .take(MAX_DOWNLOAD_SIZE as u64 + 1)
+        .read_to_end(&mut rom)
+        .map_err(|err| format!("Failed to download {}: {}", url, err))?;
+    if MAX_DOWNLOAD_SIZE < rom.len() {
+        return Err(format!(
+            "Download too large: {} (over {} bytes)",
+            url, MAX_DOWNLOAD_SIZE
+        ));
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn detect_url_schemes() {
+        assert!(is_url("https://example.com/roms/pong.ch8"));
+        assert!(is_url("http://example.com/roms/pong.ch8"));
+        assert!(!is_url("roms/pong.ch8"));
+        assert!(!is_url("ftp://example.com/roms/pong.ch8"));
+    }
+
+    #[test]
+    fn cached_download_is_reused() {
+        let dir = temp_dir().join(format!("chip8-cache-{}", std::process::id()));
+        let url = "https://example.invalid/pong.ch8";
+
+        create_dir_all(&dir).unwrap();
+        write(dir.join(Sha1::from(url).digest().to_string()), [0x00, 0xE0]).unwrap();
+        assert_eq!(fetch(url, Some(&dir)), Ok(vec![0x00, 0xE0]));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_oversized_download() {
+        let url = "https://example.invalid/huge.ch8";
+
+        assert!(read_capped(&[0x00; MAX_DOWNLOAD_SIZE][..], url).is_ok());
+        assert!(read_capped(&[0x00; MAX_DOWNLOAD_SIZE + 1][..], url).is_err());
+    }
+}
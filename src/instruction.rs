@@ -0,0 +1,440 @@
+use std::fmt;
+
+// Named after the mnemonics of Cowgod's Chip-8 Technical Reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,
+    Ret,
+    Sys(u16),
+    Jp(u16),
+    Call(u16),
+    SeVx(u8, u8),
+    SneVx(u8, u8),
+    SeVxVy(u8, u8),
+    LdVx(u8, u8),
+    AddVx(u8, u8),
+    LdVxVy(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddVxVy(u8, u8),
+    Sub(u8, u8),
+    Shr(u8, u8),
+    Subn(u8, u8),
+    Shl(u8, u8),
+    SneVxVy(u8, u8),
+    LdI(u16),
+    JpV0(u16),
+    Rnd(u8, u8),
+    Drw(u8, u8, u8),
+    Skp(u8),
+    Sknp(u8),
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDt(u8),
+    LdSt(u8),
+    AddI(u8),
+    LdF(u8),
+    LdB(u8),
+    LdIVx(u8),
+    LdVxI(u8),
+    Exit,
+    Low,
+    High,
+    Scd(u8),
+    Scr,
+    Scl,
+    LdRVx(u8),
+    LdVxR(u8),
+    LdHf(u8),
+    LdILong(u16),
+    Plane(u8),
+    Audio,
+    Pitch(u8),
+    Scu(u8),
+}
+
+// SUPER-CHIP reuses encodings that CHIP-8 treats as SYS or leaves
+// undefined, so they are only decoded when the profile asks for them.
+pub fn decode_schip(opcode: u16) -> Option<Instruction> {
+    match opcode {
+        0x00FD => Some(Instruction::Exit),
+        0x00FE => Some(Instruction::Low),
+        0x00FF => Some(Instruction::High),
+        0x00C0..=0x00CF => Some(Instruction::Scd((opcode & 0xF) as u8)),
+        0x00FB => Some(Instruction::Scr),
+        0x00FC => Some(Instruction::Scl),
+        _ if opcode & 0xF0FF == 0xF030 => Some(Instruction::LdHf((opcode >> 8 & 0xF) as u8)),
+        _ if opcode & 0xF0FF == 0xF075 => Some(Instruction::LdRVx((opcode >> 8 & 0xF) as u8)),
+        _ if opcode & 0xF0FF == 0xF085 => Some(Instruction::LdVxR((opcode >> 8 & 0xF) as u8)),
+        _ => decode(opcode),
+    }
+}
+
+// XO-CHIP builds on SUPER-CHIP. F000 NNNN is left to the caller, since its
+// operand is in the next word.
+pub fn decode_xochip(opcode: u16) -> Option<Instruction> {
+    match opcode {
+        0xF002 => Some(Instruction::Audio),
+        0x00D0..=0x00DF => Some(Instruction::Scu((opcode & 0xF) as u8)),
+        _ if opcode & 0xF0FF == 0xF001 => Some(Instruction::Plane((opcode >> 8 & 0xF) as u8)),
+        _ if opcode & 0xF0FF == 0xF03A => Some(Instruction::Pitch((opcode >> 8 & 0xF) as u8)),
+        _ => decode_schip(opcode),
+    }
+}
+
+pub fn decode(opcode: u16) -> Option<Instruction> {
+    let x = (opcode >> 8 & 0xF) as u8;
+    let y = (opcode >> 4 & 0xF) as u8;
+    let n = (opcode & 0xF) as u8;
+    let kk = (opcode & 0xFF) as u8;
+    let addr = opcode & 0x0FFF;
+    let instruction = match (opcode >> 12, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => Instruction::Cls,
+        (0x0, 0x0, 0xE, 0xE) => Instruction::Ret,
+        (0x0, _, _, _) => Instruction::Sys(addr),
+        (0x1, _, _, _) => Instruction::Jp(addr),
+        (0x2, _, _, _) => Instruction::Call(addr),
+        (0x3, _, _, _) => Instruction::SeVx(x, kk),
+        (0x4, _, _, _) => Instruction::SneVx(x, kk),
+        (0x5, _, _, 0x0) => Instruction::SeVxVy(x, y),
+        (0x6, _, _, _) => Instruction::LdVx(x, kk),
+        (0x7, _, _, _) => Instruction::AddVx(x, kk),
+        (0x8, _, _, 0x0) => Instruction::LdVxVy(x, y),
+        (0x8, _, _, 0x1) => Instruction::Or(x, y),
+        (0x8, _, _, 0x2) => Instruction::And(x, y),
+        (0x8, _, _, 0x3) => Instruction::Xor(x, y),
+        (0x8, _, _, 0x4) => Instruction::AddVxVy(x, y),
+        (0x8, _, _, 0x5) => Instruction::Sub(x, y),
+        (0x8, _, _, 0x6) => Instruction::Shr(x, y),
+        (0x8, _, _, 0x7) => Instruction::Subn(x, y),
+        (0x8, _, _, 0xE) => Instruction::Shl(x, y),
+        (0x9, _, _, 0x0) => Instruction::SneVxVy(x, y),
+        (0xA, _, _, _) => Instruction::LdI(addr),
+        (0xB, _, _, _) => Instruction::JpV0(addr),
+        (0xC, _, _, _) => Instruction::Rnd(x, kk),
+        (0xD, _, _, _) => Instruction::Drw(x, y, n),
+        (0xE, _, 0x9, 0xE) => Instruction::Skp(x),
+        (0xE, _, 0xA, 0x1) => Instruction::Sknp(x),
+        (0xF, _, 0x0, 0x7) => Instruction::LdVxDt(x),
+        (0xF, _, 0x0, 0xA) => Instruction::LdVxK(x),
+        (0xF, _, 0x1, 0x5) => Instruction::LdDt(x),
+        (0xF, _, 0x1, 0x8) => Instruction::LdSt(x),
+        (0xF, _, 0x1, 0xE) => Instruction::AddI(x),
+        (0xF, _, 0x2, 0x9) => Instruction::LdF(x),
+        (0xF, _, 0x3, 0x3) => Instruction::LdB(x),
+        (0xF, _, 0x5, 0x5) => Instruction::LdIVx(x),
+        (0xF, _, 0x6, 0x5) => Instruction::LdVxI(x),
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+impl Instruction {
+    // The opcode pattern, which groups instructions for the profilers.
+    pub fn family(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Sys(_) => "0NNN",
+            Instruction::Jp(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SeVx(_, _) => "3XKK",
+            Instruction::SneVx(_, _) => "4XKK",
+            Instruction::SeVxVy(_, _) => "5XY0",
+            Instruction::LdVx(_, _) => "6XKK",
+            Instruction::AddVx(_, _) => "7XKK",
+            Instruction::LdVxVy(_, _) => "8XY0",
+            Instruction::Or(_, _) => "8XY1",
+            Instruction::And(_, _) => "8XY2",
+            Instruction::Xor(_, _) => "8XY3",
+            Instruction::AddVxVy(_, _) => "8XY4",
+            Instruction::Sub(_, _) => "8XY5",
+            Instruction::Shr(_, _) => "8XY6",
+            Instruction::Subn(_, _) => "8XY7",
+            Instruction::Shl(_, _) => "8XYE",
+            Instruction::SneVxVy(_, _) => "9XY0",
+            Instruction::LdI(_) => "ANNN",
+            Instruction::JpV0(_) => "BNNN",
+            Instruction::Rnd(_, _) => "CXKK",
+            Instruction::Drw(_, _, _) => "DXYN",
+            Instruction::Skp(_) => "EX9E",
+            Instruction::Sknp(_) => "EXA1",
+            Instruction::LdVxDt(_) => "FX07",
+            Instruction::LdVxK(_) => "FX0A",
+            Instruction::LdDt(_) => "FX15",
+            Instruction::LdSt(_) => "FX18",
+            Instruction::AddI(_) => "FX1E",
+            Instruction::LdF(_) => "FX29",
+            Instruction::LdB(_) => "FX33",
+            Instruction::LdIVx(_) => "FX55",
+            Instruction::LdVxI(_) => "FX65",
+            Instruction::Exit => "00FD",
+            Instruction::Low => "00FE",
+            Instruction::High => "00FF",
+            Instruction::Scd(_) => "00CN",
+            Instruction::Scr => "00FB",
+            Instruction::Scl => "00FC",
+            Instruction::LdRVx(_) => "FX75",
+            Instruction::LdVxR(_) => "FX85",
+            Instruction::LdHf(_) => "FX30",
+            Instruction::LdILong(_) => "F000",
+            Instruction::Plane(_) => "FN01",
+            Instruction::Audio => "F002",
+            Instruction::Pitch(_) => "FX3A",
+            Instruction::Scu(_) => "00DN",
+        }
+    }
+
+    // Shifts count as reading both registers, since the quirks decide
+    // which one is shifted.
+    pub fn reads_v(&self, r: u8) -> bool {
+        match *self {
+            Instruction::SeVx(x, _)
+            | Instruction::SneVx(x, _)
+            | Instruction::AddVx(x, _)
+            | Instruction::Skp(x)
+            | Instruction::Sknp(x)
+            | Instruction::LdDt(x)
+            | Instruction::LdSt(x)
+            | Instruction::AddI(x)
+            | Instruction::LdF(x)
+            | Instruction::LdHf(x)
+            | Instruction::Pitch(x)
+            | Instruction::LdB(x) => x == r,
+            Instruction::SeVxVy(x, y)
+            | Instruction::Or(x, y)
+            | Instruction::And(x, y)
+            | Instruction::Xor(x, y)
+            | Instruction::AddVxVy(x, y)
+            | Instruction::Sub(x, y)
+            | Instruction::Shr(x, y)
+            | Instruction::Subn(x, y)
+            | Instruction::Shl(x, y)
+            | Instruction::SneVxVy(x, y)
+            | Instruction::Drw(x, y, _) => x == r || y == r,
+            Instruction::LdVxVy(_, y) => y == r,
+            Instruction::LdIVx(x) | Instruction::LdRVx(x) => r <= x,
+            _ => false,
+        }
+    }
+
+    pub fn writes_v(&self, r: u8) -> bool {
+        match *self {
+            Instruction::LdVx(x, _)
+            | Instruction::AddVx(x, _)
+            | Instruction::LdVxVy(x, _)
+            | Instruction::Or(x, _)
+            | Instruction::And(x, _)
+            | Instruction::Xor(x, _)
+            | Instruction::AddVxVy(x, _)
+            | Instruction::Sub(x, _)
+            | Instruction::Shr(x, _)
+            | Instruction::Subn(x, _)
+            | Instruction::Shl(x, _)
+            | Instruction::Rnd(x, _)
+            | Instruction::LdVxDt(x)
+            | Instruction::LdVxK(x) => x == r,
+            Instruction::LdVxI(x) | Instruction::LdVxR(x) => r <= x,
+            _ => false,
+        }
+    }
+
+    // The instructions that always leave a carry, borrow, shifted-out bit
+    // or collision in VF.
+    pub fn sets_flag(&self) -> bool {
+        matches!(
+            self,
+            Instruction::AddVxVy(_, _)
+                | Instruction::Sub(_, _)
+                | Instruction::Shr(_, _)
+                | Instruction::Subn(_, _)
+                | Instruction::Shl(_, _)
+                | Instruction::Drw(_, _, _)
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys(addr) => write!(f, "SYS {:04X}", addr),
+            Instruction::Jp(addr) => write!(f, "JP {:04X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL {:04X}", addr),
+            Instruction::SeVx(x, kk) => write!(f, "SE V{:X} {}", x, kk),
+            Instruction::SneVx(x, kk) => write!(f, "SNE V{:X} {}", x, kk),
+            Instruction::SeVxVy(x, y) => write!(f, "SE V{:X} V{:X}", x, y),
+            Instruction::LdVx(x, kk) => write!(f, "LD V{:X} {}", x, kk),
+            Instruction::AddVx(x, kk) => write!(f, "ADD V{:X} {}", x, kk),
+            Instruction::LdVxVy(x, y) => write!(f, "LD V{:X} V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X} V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X} V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X} V{:X}", x, y),
+            Instruction::AddVxVy(x, y) => write!(f, "ADD V{:X} V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X} V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X} {{V{:X}}}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X} V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X} {{V{:X}}}", x, y),
+            Instruction::SneVxVy(x, y) => write!(f, "SNE V{:X} V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I {:04X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0 {:04X}", addr),
+            Instruction::Rnd(x, kk) => write!(f, "RND V{:X} {}", x, kk),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X} V{:X} {:X}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X} DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X} K", x),
+            Instruction::LdDt(x) => write!(f, "LD DT V{:X}", x),
+            Instruction::LdSt(x) => write!(f, "LD ST V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I V{:X}", x),
+            Instruction::LdF(x) => write!(f, "LD F V{:X}", x),
+            Instruction::LdB(x) => write!(f, "LD B V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I] V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X} [I]", x),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Scd(n) => write!(f, "SCD {:X}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::LdRVx(x) => write!(f, "LD R V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X} R", x),
+            Instruction::LdHf(x) => write!(f, "LD HF V{:X}", x),
+            Instruction::LdILong(addr) => write!(f, "LD I LONG {:04X}", addr),
+            Instruction::Plane(n) => write!(f, "PLANE {:X}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::Scu(n) => write!(f, "SCU {:X}", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_every_family() {
+        let cases = [
+            (0x00E0, Instruction::Cls, "CLS"),
+            (0x00EE, Instruction::Ret, "RET"),
+            (0x0123, Instruction::Sys(0x123), "SYS 0123"),
+            (0x1ABC, Instruction::Jp(0xABC), "JP 0ABC"),
+            (0x2ABC, Instruction::Call(0xABC), "CALL 0ABC"),
+            (0x3A12, Instruction::SeVx(0xA, 0x12), "SE VA 18"),
+            (0x4A12, Instruction::SneVx(0xA, 0x12), "SNE VA 18"),
+            (0x5AB0, Instruction::SeVxVy(0xA, 0xB), "SE VA VB"),
+            (0x6A12, Instruction::LdVx(0xA, 0x12), "LD VA 18"),
+            (0x7A12, Instruction::AddVx(0xA, 0x12), "ADD VA 18"),
+            (0x8AB0, Instruction::LdVxVy(0xA, 0xB), "LD VA VB"),
+            (0x8AB1, Instruction::Or(0xA, 0xB), "OR VA VB"),
+            (0x8AB2, Instruction::And(0xA, 0xB), "AND VA VB"),
+            (0x8AB3, Instruction::Xor(0xA, 0xB), "XOR VA VB"),
+            (0x8AB4, Instruction::AddVxVy(0xA, 0xB), "ADD VA VB"),
+            (0x8AB5, Instruction::Sub(0xA, 0xB), "SUB VA VB"),
+            (0x8AB6, Instruction::Shr(0xA, 0xB), "SHR VA {VB}"),
+            (0x8AB7, Instruction::Subn(0xA, 0xB), "SUBN VA VB"),
+            (0x8ABE, Instruction::Shl(0xA, 0xB), "SHL VA {VB}"),
+            (0x9AB0, Instruction::SneVxVy(0xA, 0xB), "SNE VA VB"),
+            (0xAABC, Instruction::LdI(0xABC), "LD I 0ABC"),
+            (0xBABC, Instruction::JpV0(0xABC), "JP V0 0ABC"),
+            (0xCA12, Instruction::Rnd(0xA, 0x12), "RND VA 18"),
+            (0xDAB5, Instruction::Drw(0xA, 0xB, 0x5), "DRW VA VB 5"),
+            (0xEA9E, Instruction::Skp(0xA), "SKP VA"),
+            (0xEAA1, Instruction::Sknp(0xA), "SKNP VA"),
+            (0xFA07, Instruction::LdVxDt(0xA), "LD VA DT"),
+            (0xFA0A, Instruction::LdVxK(0xA), "LD VA K"),
+            (0xFA15, Instruction::LdDt(0xA), "LD DT VA"),
+            (0xFA18, Instruction::LdSt(0xA), "LD ST VA"),
+            (0xFA1E, Instruction::AddI(0xA), "ADD I VA"),
+            (0xFA29, Instruction::LdF(0xA), "LD F VA"),
+            (0xFA33, Instruction::LdB(0xA), "LD B VA"),
+            (0xFA55, Instruction::LdIVx(0xA), "LD [I] VA"),
+            (0xFA65, Instruction::LdVxI(0xA), "LD VA [I]"),
+        ];
+
+        for (opcode, instruction, mnemonic) in cases {
+            assert_eq!(decode(opcode), Some(instruction), "{:04X}", opcode);
+            assert_eq!(instruction.to_string(), mnemonic);
+        }
+    }
+
+    #[test]
+    fn decode_schip_extensions() {
+        let cases = [
+            (0x00FD, Instruction::Exit, "EXIT"),
+            (0x00FE, Instruction::Low, "LOW"),
+            (0x00FF, Instruction::High, "HIGH"),
+            (0x00C3, Instruction::Scd(3), "SCD 3"),
+            (0x00FB, Instruction::Scr, "SCR"),
+            (0x00FC, Instruction::Scl, "SCL"),
+            (0xF375, Instruction::LdRVx(3), "LD R V3"),
+            (0xFA85, Instruction::LdVxR(0xA), "LD VA R"),
+            (0xFA30, Instruction::LdHf(0xA), "LD HF VA"),
+        ];
+
+        for (opcode, instruction, mnemonic) in cases {
+            assert_eq!(decode_schip(opcode), Some(instruction), "{:04X}", opcode);
+            let plain = (opcode < 0x1000).then_some(Instruction::Sys(opcode));
+            assert_eq!(decode(opcode), plain);
+            assert_eq!(instruction.to_string(), mnemonic);
+        }
+        assert_eq!(decode_schip(0x00E0), Some(Instruction::Cls));
+    }
+
+    #[test]
+    fn decode_xochip_extensions() {
+        assert_eq!(decode_xochip(0xF201), Some(Instruction::Plane(2)));
+        assert_eq!(Instruction::Plane(2).to_string(), "PLANE 2");
+        assert_eq!(decode_schip(0xF201), None);
+        assert_eq!(decode_xochip(0xF002), Some(Instruction::Audio));
+        assert_eq!(decode_xochip(0xF53A), Some(Instruction::Pitch(5)));
+        assert_eq!(Instruction::Pitch(5).to_string(), "PITCH V5");
+        assert_eq!(decode_xochip(0x00D3), Some(Instruction::Scu(3)));
+        assert_eq!(Instruction::Scu(3).to_string(), "SCU 3");
+        assert_eq!(decode_schip(0x00D3), Some(Instruction::Sys(0x0D3)));
+        assert_eq!(decode(0x00D3), Some(Instruction::Sys(0x0D3)));
+        assert_eq!(decode_xochip(0x00FF), Some(Instruction::High));
+    }
+
+    #[test]
+    fn decode_invalid_encodings() {
+        let invalid = [
+            0x5AB1, 0x5ABF, 0x8AB8, 0x8ABD, 0x8ABF, 0x9AB1, 0xEA9F, 0xEAA2, 0xEA00, 0xFA00, 0xFA08,
+            0xFA30, 0xFA75, 0xFAFF,
+        ];
+
+        for opcode in invalid {
+            assert_eq!(decode(opcode), None, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn families_match_their_opcodes() {
+        for opcode in 0..=0xFFFF {
+            if let Some(instruction) = decode_xochip(opcode) {
+                let family = instruction.family();
+                let matches = format!("{:04X}", opcode)
+                    .chars()
+                    .zip(family.chars())
+                    .all(|(digit, pattern)| !pattern.is_ascii_hexdigit() || digit == pattern);
+                assert!(matches, "{:04X} in {}", opcode, family);
+            }
+        }
+    }
+
+    #[test]
+    fn register_operands() {
+        let store = decode(0xF355).unwrap();
+        assert!(store.reads_v(0x3) && !store.reads_v(0x4) && !store.writes_v(0x0));
+        let load = decode(0xF365).unwrap();
+        assert!(load.writes_v(0x3) && !load.writes_v(0x4) && !load.reads_v(0x0));
+        let add = decode(0x8F14).unwrap();
+        assert!(add.reads_v(0xF) && add.writes_v(0xF) && add.sets_flag());
+        let copy = decode(0x8F10).unwrap();
+        assert!(copy.reads_v(0x1) && !copy.reads_v(0xF) && copy.writes_v(0xF));
+        assert!(!copy.sets_flag());
+    }
+}
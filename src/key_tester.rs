@@ -0,0 +1,135 @@
+use crate::keyboard::{self, Keyboard, KeyboardMessage};
+
+use iced::keyboard::{Event, KeyCode};
+use iced::{
+    container, executor, Align, Application, Background, Clipboard, Color, Column, Command,
+    Container, Element, Length, Row, Subscription, Text,
+};
+use iced_native::subscription::events_with;
+use iced_native::Event as NativeEvent;
+
+pub const WIDTH: usize = CELL_SIZE as usize * 4;
+pub const HEIGHT: usize = CELL_SIZE as usize * 4;
+
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+const CELL_SIZE: u16 = 80;
+
+pub struct KeyTester {
+    keyboard: Keyboard,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Message {
+    FromKeyboard(KeyboardMessage),
+    RawKey(KeyCode, bool),
+}
+
+impl Application for KeyTester {
+    type Executor = executor::Default;
+    type Message = Message;
+    type Flags = ();
+
+    fn new(_flags: ()) -> (KeyTester, Command<Self::Message>) {
+        (
+            KeyTester {
+                keyboard: Keyboard::new(),
+            },
+            Command::none(),
+        )
+    }
+
+    fn title(&self) -> String {
+        String::from("CHIP-8 Key Tester")
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        let raw_keys = events_with(|event, _status| match event {
+            NativeEvent::Keyboard(Event::KeyPressed {
+                key_code,
+                modifiers: _,
+            }) => Some(Message::RawKey(key_code, true)),
+            NativeEvent::Keyboard(Event::KeyReleased {
+                key_code,
+                modifiers: _,
+            }) => Some(Message::RawKey(key_code, false)),
+            _ => None,
+        });
+        Subscription::batch(vec![
+            self.keyboard.subscription().map(Message::FromKeyboard),
+            raw_keys,
+        ])
+    }
+
+    fn update(
+        &mut self,
+        message: Self::Message,
+        _clipboard: &mut Clipboard,
+    ) -> Command<Self::Message> {
+        match message {
+            Message::FromKeyboard(message) => {
+                self.keyboard.update(message);
+            }
+            Message::RawKey(key_code, is_pressed) => {
+                let action = if is_pressed { "pressed" } else { "released" };
+                let held: Vec<_> = self
+                    .keyboard
+                    .pressed()
+                    .map(|value| format!("{:X}", value))
+                    .collect();
+                match keyboard::map_key(key_code) {
+                    Some(value) => print!("{:?} {} -> {:X}", key_code, action, value),
+                    None => print!("{:?} {} -> (unmapped)", key_code, action),
+                }
+                println!(" (held: {})", held.join(" "));
+            }
+        }
+        Command::none()
+    }
+
+    fn view(&mut self) -> Element<'_, Self::Message> {
+        let keyboard = &self.keyboard;
+        KEYPAD
+            .iter()
+            .fold(Column::new(), |column, keys| {
+                column.push(keys.iter().fold(Row::new(), |row, &value| {
+                    row.push(
+                        Container::new(Text::new(format!("{:X}", value)).size(40))
+                            .width(Length::Units(CELL_SIZE))
+                            .height(Length::Units(CELL_SIZE))
+                            .align_x(Align::Center)
+                            .align_y(Align::Center)
+                            .style(Cell {
+                                is_pressed: keyboard.is_pressed(value),
+                            }),
+                    )
+                }))
+            })
+            .into()
+    }
+}
+
+struct Cell {
+    is_pressed: bool,
+}
+
+impl container::StyleSheet for Cell {
+    fn style(&self) -> container::Style {
+        let (text, background) = if self.is_pressed {
+            (Color::BLACK, Color::new(0.95, 0.95, 0.95, 1.0))
+        } else {
+            (Color::new(0.95, 0.95, 0.95, 1.0), Color::BLACK)
+        };
+        container::Style {
+            text_color: Some(text),
+            background: Some(Background::Color(background)),
+            border_width: 1.0,
+            border_color: Color::new(0.5, 0.5, 0.5, 1.0),
+            ..container::Style::default()
+        }
+    }
+}
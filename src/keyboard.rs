@@ -2,9 +2,12 @@ use iced::keyboard::{Event, KeyCode};
 use iced::Subscription;
 use iced_native::subscription::events_with;
 use iced_native::Event as NativeEvent;
-use std::collections::{HashMap, HashSet};
+use log::{debug, trace};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-const KEY_MAP: [(KeyCode, u8); 16] = [
+pub const KEY_MAP: [(KeyCode, u8); 16] = [
     (KeyCode::Comma, 0x0),
     (KeyCode::Key7, 0x1),
     (KeyCode::Key8, 0x2),
@@ -22,9 +25,24 @@ const KEY_MAP: [(KeyCode, u8); 16] = [
     (KeyCode::Semicolon, 0xE),
     (KeyCode::Slash, 0xF),
 ];
+const DPAD_KEYS: [(&str, [KeyCode; 2]); 4] = [
+    ("up", [KeyCode::Up, KeyCode::W]),
+    ("down", [KeyCode::Down, KeyCode::S]),
+    ("left", [KeyCode::Left, KeyCode::A]),
+    ("right", [KeyCode::Right, KeyCode::D]),
+];
+const BUFFER_SIZE: usize = 16;
+
+// The CHIP-8 keys for up, down, left and right, in the order of DPAD_KEYS.
+pub type Dpad = [Option<u8>; 4];
+
+// events_with only takes a plain function, so the aliases are installed once
+// at startup rather than carried by the subscription.
+static DPAD: OnceLock<Dpad> = OnceLock::new();
 
 pub struct Keyboard {
     pressed_keys: HashSet<u8>,
+    buffered_presses: VecDeque<(u8, Instant)>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -37,6 +55,7 @@ impl Keyboard {
     pub fn new() -> Self {
         Keyboard {
             pressed_keys: HashSet::new(),
+            buffered_presses: VecDeque::new(),
         }
     }
 
@@ -46,15 +65,11 @@ impl Keyboard {
                 Event::KeyPressed {
                     key_code,
                     modifiers: _,
-                } => HashMap::from(KEY_MAP)
-                    .get(&key_code)
-                    .map(|value| KeyboardMessage::Press(*value)),
+                } => map_key(key_code).map(KeyboardMessage::Press),
                 Event::KeyReleased {
                     key_code,
                     modifiers: _,
-                } => HashMap::from(KEY_MAP)
-                    .get(&key_code)
-                    .map(|value| KeyboardMessage::Release(*value)),
+                } => map_key(key_code).map(KeyboardMessage::Release),
                 _ => None,
             },
             _ => None,
@@ -64,9 +79,11 @@ impl Keyboard {
     pub fn update(&mut self, message: KeyboardMessage) {
         match message {
             KeyboardMessage::Press(value) => {
+                debug!("Key {:X} pressed", value);
                 self.pressed_keys.insert(value);
             }
             KeyboardMessage::Release(value) => {
+                debug!("Key {:X} released", value);
                 self.pressed_keys.remove(&value);
             }
         }
@@ -75,6 +92,70 @@ impl Keyboard {
     pub fn is_pressed(&self, value: u8) -> bool {
         self.pressed_keys.contains(&value)
     }
+
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0x0..=0xF).filter(move |&value| self.is_pressed(value))
+    }
+
+    pub fn buffer_press(&mut self, value: u8, at: Instant) {
+        if self.buffered_presses.len() == BUFFER_SIZE {
+            self.buffered_presses.pop_front();
+        }
+        self.buffered_presses.push_back((value, at));
+    }
+
+    pub fn take_buffered_press(&mut self, now: Instant, window: Duration) -> Option<u8> {
+        self.buffered_presses
+            .retain(|&(_, at)| now.saturating_duration_since(at) <= window);
+        let (value, _) = self.buffered_presses.pop_front()?;
+        debug!("Key {:X} taken from the input buffer", value);
+        Some(value)
+    }
+}
+
+pub fn parse_dpad(spec: &str) -> Result<Dpad, String> {
+    let mut dpad = [None; 4];
+    for binding in spec.split(',') {
+        let (direction, key) = binding
+            .split_once('=')
+            .ok_or_else(|| format!("Unsupported dpad binding: {}", binding))?;
+        let index = DPAD_KEYS
+            .iter()
+            .position(|&(name, _)| name == direction.trim())
+            .ok_or_else(|| format!("Unsupported dpad direction: {}", direction))?;
+        let value = u8::from_str_radix(key.trim(), 16)
+            .ok()
+            .filter(|&value| value <= 0xF)
+            .ok_or_else(|| format!("Unsupported dpad key: {}", key))?;
+        if dpad[index].replace(value).is_some() {
+            return Err(format!("Duplicated dpad direction: {}", direction));
+        }
+    }
+    Ok(dpad)
+}
+
+pub fn install_dpad(dpad: Dpad) {
+    DPAD.set(dpad).unwrap();
+}
+
+pub fn map_key(key_code: KeyCode) -> Option<u8> {
+    let value = map_key_with(key_code, DPAD.get().unwrap_or(&[None; 4]));
+    match value {
+        Some(value) => trace!("Mapped {:?} to the key {:X}", key_code, value),
+        None => trace!("Ignored the unmapped {:?}", key_code),
+    }
+    value
+}
+
+// The arrows and WASD are aliases layered over KEY_MAP, which binds neither.
+fn map_key_with(key_code: KeyCode, dpad: &Dpad) -> Option<u8> {
+    HashMap::from(KEY_MAP).get(&key_code).copied().or_else(|| {
+        DPAD_KEYS
+            .iter()
+            .zip(dpad)
+            .find(|((_, key_codes), _)| key_codes.contains(&key_code))
+            .and_then(|(_, &value)| value)
+    })
 }
 
 #[cfg(test)]
@@ -82,6 +163,40 @@ mod tests {
     use super::*;
     use proptest::prelude::*;
 
+    #[test]
+    fn pressed_in_sorted_order() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.update(KeyboardMessage::Press(0xA));
+        keyboard.update(KeyboardMessage::Press(0x3));
+        assert_eq!(keyboard.pressed().collect::<Vec<_>>(), vec![0x3, 0xA]);
+    }
+
+    #[test]
+    fn buffered_press_taken_once() {
+        let mut keyboard = Keyboard::new();
+        let pressed_at = Instant::now();
+        let window = Duration::from_millis(100);
+
+        keyboard.buffer_press(0x5, pressed_at);
+        let now = pressed_at + Duration::from_millis(50);
+        assert_eq!(keyboard.take_buffered_press(now, window), Some(0x5));
+        assert_eq!(keyboard.take_buffered_press(now, window), None);
+    }
+
+    #[test]
+    fn buffered_press_expires() {
+        let mut keyboard = Keyboard::new();
+        let pressed_at = Instant::now();
+        let window = Duration::from_millis(100);
+
+        keyboard.buffer_press(0x5, pressed_at);
+        keyboard.buffer_press(0x6, pressed_at + Duration::from_millis(80));
+        let now = pressed_at + Duration::from_millis(150);
+        assert_eq!(keyboard.take_buffered_press(now, window), Some(0x6));
+        assert_eq!(keyboard.take_buffered_press(now, window), None);
+    }
+
     proptest! {
 
         #[test]
@@ -117,4 +232,35 @@ mod tests {
             assert!(!keyboard.is_pressed(value));
         }
     }
+
+    #[test]
+    fn dpad_aliases_merge_with_key_map() {
+        let dpad = parse_dpad("up=2,down=8,left=4").unwrap();
+
+        assert_eq!(map_key_with(KeyCode::Up, &dpad), Some(0x2));
+        assert_eq!(map_key_with(KeyCode::S, &dpad), Some(0x8));
+        assert_eq!(map_key_with(KeyCode::Left, &dpad), Some(0x4));
+        assert_eq!(map_key_with(KeyCode::Right, &dpad), None);
+        assert_eq!(map_key_with(KeyCode::K, &dpad), Some(0x8));
+    }
+
+    #[test]
+    fn dpad_keys_not_bound_by_key_map() {
+        let bound: HashMap<_, _> = HashMap::from(KEY_MAP);
+
+        for (_, key_codes) in DPAD_KEYS {
+            assert!(key_codes
+                .iter()
+                .all(|key_code| !bound.contains_key(key_code)));
+        }
+    }
+
+    #[test]
+    fn reject_malformed_dpad() {
+        assert!(parse_dpad("up=2,down=8,left=4,right=6").is_ok());
+        assert!(parse_dpad("up").is_err());
+        assert!(parse_dpad("north=2").is_err());
+        assert!(parse_dpad("up=10").is_err());
+        assert!(parse_dpad("up=2,up=8").is_err());
+    }
 }
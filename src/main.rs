@@ -1,40 +1,266 @@
+mod analysis;
+#[cfg(feature = "archives")]
+mod archive;
 mod buzzer;
+mod cheats;
+mod checksum;
 mod chip8;
+mod demo;
 mod display;
+#[cfg(feature = "http")]
+mod http;
+mod instruction;
+mod key_tester;
 mod keyboard;
 mod memory;
+mod patch;
+mod profiler;
+mod quirks;
+mod ripper;
+mod romdb;
+mod rpl;
+#[cfg(feature = "scripting")]
+mod script;
+mod shutdown;
+mod spans;
 
-use chip8::{Chip8, Flags};
+use chip8::{Chip8, Flags, TraceFormat};
+use display::{PixelShape, Renderer};
+use key_tester::KeyTester;
+use memory::InitPattern;
+use profiler::CallReport;
+use quirks::{QuirkFlags, Quirks};
+use romdb::RomDb;
 
 use chrono::Local;
-use clap::{app_from_crate, arg};
+use clap::{app_from_crate, arg, App, Arg, ArgMatches};
 use fern::Dispatch;
 use iced::{Application, Color, Settings};
-use log::LevelFilter;
-use std::fs::File;
-use std::io::{stderr, Read};
+use log::{info, warn, LevelFilter};
+use sha1_smol::Sha1;
+use std::fs::{read, read_to_string};
+use std::io::{stderr, stdin};
+#[cfg(feature = "http")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const FAST_BOOT_CAP: u64 = 1_000_000;
+const MAX_CLOCK_SPEED: f64 = 10_000.0;
 
 fn main() {
     let matches = app_from_crate!()
-        .arg(arg!([FILE] "File of the CHIP-8 ROM").required(true))
-        .arg(arg!(--clock [INT] "Change the clock speed (1-500 Hz)").default_value("500"))
+        .arg(arg!([FILE] "File of the CHIP-8 ROM"))
+        .arg(arg!(--demo [NAME] "Run an embedded demo ROM (maze/keypad)"))
+        .arg(
+            arg!(--patch <FILE> "Apply an IPS patch to the ROM")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--"patch-bytes" <PATCH> "Overwrite ROM bytes (e.g. 0x210=AB,0x211=CD)")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            arg!(--clock [HZ] "Change the clock speed (up to 10000 Hz, fractions allowed)")
+                .default_value("500")
+                .validator(clock_speed_of),
+        )
+        .arg(
+            arg!(--"timer-hz" [HZ] "Change the delay/sound timer frequency (30-120 Hz)")
+                .default_value("60")
+                .validator(timer_hz_of),
+        )
+        .arg(
+            arg!(--"max-catch-up" [MS] "Catch up on at most this much time after a stall")
+                .default_value("100"),
+        )
+        .arg(
+            arg!(--"key-buffer" [MS] "Let key presses this recent satisfy FX0A (0 to disable)")
+                .default_value("100"),
+        )
+        .arg(arg!(--profile <PLATFORM> "Preset the quirks of chip8/chip48/superchip/xochip").required(false))
+        .arg(arg!(--"rom-db" <FILE> "Add ROM hashes with their profile and clock from a TOML file").required(false))
+        .arg(arg!(--"quirk-shift" "Shift VY into VX in 8XY6/8XYE, as the original interpreter did").overrides_with("no-quirk-shift"))
+        .arg(arg!(--"quirk-memory" "Advance I past the registers in FX55/FX65, as the original interpreter did").overrides_with("no-quirk-memory"))
+        .arg(arg!(--"quirk-memory-x" "Advance I by X in FX55/FX65, as CHIP-48 did").overrides_with("no-quirk-memory-x"))
+        .arg(arg!(--"quirk-jump" "Jump to XNN + VX in BXNN, as CHIP-48 and SCHIP did").overrides_with("no-quirk-jump"))
+        .arg(arg!(--"quirk-vf-reset" "Reset VF after 8XY1/8XY2/8XY3, as the original interpreter did").overrides_with("no-quirk-vf-reset"))
+        .arg(arg!(--"quirk-clip" "Clip sprites at the right and bottom edges instead of wrapping them").overrides_with("no-quirk-clip"))
+        .arg(arg!(--"quirk-display-wait" "Draw at most one sprite per timer tick, as the original interpreter did").overrides_with("no-quirk-display-wait"))
+        .arg(arg!(--"quirk-long-skip" "Skip over F000 NNNN as a whole, as XO-CHIP does").overrides_with("no-quirk-long-skip"))
+        .arg(arg!(--"quirk-schip" "Run the SUPER-CHIP instructions instead of treating them as SYS").overrides_with("no-quirk-schip"))
+        .arg(arg!(--"quirk-xochip" "Run the XO-CHIP instructions, such as F000 NNNN").overrides_with("no-quirk-xochip"))
+        .arg(arg!(--"quirk-row-collisions" "Count the collided rows in VF in 128x64 mode, as SCHIP 1.1 did").overrides_with("no-quirk-row-collisions"))
+        .arg(arg!(--"quirk-half-scroll" "Scroll by half the distance in 64x32 mode, as SCHIP 1.1 did").overrides_with("no-quirk-half-scroll"))
+        .arg(arg!(--"quirk-index-overflow" "Set VF when FX1E carries I past 0xFFF, as the Amiga interpreter did").overrides_with("no-quirk-index-overflow"))
+        .arg(arg!(--"no-quirk-shift" "Switch off --quirk-shift when the profile switches it on").overrides_with("quirk-shift"))
+        .arg(arg!(--"no-quirk-memory" "Switch off --quirk-memory when the profile switches it on").overrides_with("quirk-memory"))
+        .arg(arg!(--"no-quirk-memory-x" "Switch off --quirk-memory-x when the profile switches it on").overrides_with("quirk-memory-x"))
+        .arg(arg!(--"no-quirk-jump" "Switch off --quirk-jump when the profile switches it on").overrides_with("quirk-jump"))
+        .arg(arg!(--"no-quirk-vf-reset" "Switch off --quirk-vf-reset when the profile switches it on").overrides_with("quirk-vf-reset"))
+        .arg(arg!(--"no-quirk-clip" "Switch off --quirk-clip when the profile switches it on").overrides_with("quirk-clip"))
+        .arg(arg!(--"no-quirk-display-wait" "Switch off --quirk-display-wait when the profile switches it on").overrides_with("quirk-display-wait"))
+        .arg(arg!(--"no-quirk-long-skip" "Switch off --quirk-long-skip when the profile switches it on").overrides_with("quirk-long-skip"))
+        .arg(arg!(--"no-quirk-schip" "Switch off --quirk-schip when the profile switches it on").overrides_with("quirk-schip"))
+        .arg(arg!(--"no-quirk-xochip" "Switch off --quirk-xochip when the profile switches it on").overrides_with("quirk-xochip"))
+        .arg(arg!(--"no-quirk-row-collisions" "Switch off --quirk-row-collisions when the profile switches it on").overrides_with("quirk-row-collisions"))
+        .arg(arg!(--"no-quirk-half-scroll" "Switch off --quirk-half-scroll when the profile switches it on").overrides_with("quirk-half-scroll"))
+        .arg(arg!(--"no-quirk-index-overflow" "Switch off --quirk-index-overflow when the profile switches it on").overrides_with("quirk-index-overflow"))
+        .arg(arg!(--dpad <MAPPING> "Bind the arrows and WASD to CHIP-8 keys, e.g. up=2,down=8,left=4,right=6").required(false))
         .arg(
             arg!(--color [STRING] "Select the display color (white/green/amber)")
                 .default_value("white"),
         )
+        .arg(
+            arg!(--renderer [STRING] "Select the rendering backend (canvas/image)")
+                .default_value("canvas"),
+        )
+        .arg(
+            arg!(--"pixel-shape" [SHAPE] "Select the shape of lit pixels (square/circle/dot)")
+                .default_value("square"),
+        )
+        .arg(
+            arg!(--"scale-factor" [FACTOR] "Scale the window and snap pixels to this factor")
+                .default_value("1.0"),
+        )
+        .arg(
+            arg!(--"ram-init" [PATTERN] "Fill uninitialized memory (zero/ff/random)")
+                .default_value("zero"),
+        )
+        .arg(
+            arg!(--"reg-init" [PATTERN] "Fill the initial V registers (zero/ff/random)")
+                .default_value("zero"),
+        )
+        .arg(arg!(--seed [INT] "Seed the random number generator"))
+        .arg(
+            arg!(--poke <POKE> "Pin a memory address to a value (e.g. 0x6E3=0x05)")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(arg!(--cheats <FILE> "Load toggleable cheats (F1-F9) from a TOML file").required(false))
         .arg(arg!(--verbose "Show the detailed execution trace"))
+        .arg(
+            arg!(--"trace-format" [FORMAT] "Select the format of the execution trace (full/delta)")
+                .default_value("full"),
+        )
+        .arg(
+            arg!(--"log-filter" <FILTER> "Set the log level of a target (e.g. chip8::keyboard=trace)")
+                .required(false)
+                .multiple_occurrences(true),
+        )
+        .arg(arg!(--"fast-boot" [CYCLES] "Run unpaced until the first draw (at most CYCLES, default 1000000)"))
+        .arg(arg!(--validate "Scan the ROM for unknown opcodes and stray jumps before running"))
+        .arg(arg!(--paranoid "Check the machine invariants after every instruction"))
+        .arg(arg!(--"strict-alignment" "Halt on jumps and calls to odd addresses instead of warning"))
+        .arg(arg!(--"strict-memory" "Halt on I-relative accesses past 0xFFF instead of wrapping (DRW clips)"))
+        .arg(arg!(--"lint-vf" "Warn when a flag overwrites a value in VF before it is read"))
+        .arg(arg!(--"exit-on-terminal-loop" "Print a state checksum and exit once the ROM loops for good"))
+        .arg(arg!(--"cpu-profile" "Print the time spent per opcode at exit"))
+        .arg(arg!(--"profile-opcodes" [OUT] "Report executions per opcode and address at exit (to stderr or OUT)"))
+        .arg(arg!(--"profile-calls" <FORMAT> "Report the subroutine calls at exit (text/dot)").required(false))
+        .arg(arg!(--coverage "Print the hits per opcode family found in or run from the ROM at exit"))
+        .arg(arg!(--"rip-sprites" <DIR> "Export the drawn sprites as PNGs at exit").required(false))
+        .arg(arg!(--"checksum-every" <N> "Print a state checksum every N instructions").required(false))
+        .arg(arg!(--"max-cycles" <N> "Exit with a summary after N instructions").required(false))
+        .arg(arg!(--"break-opcode" <HEX> "Pause when this word is fetched (e.g. 0000)").required(false))
+        .arg(arg!(--"pipe-frames" <FORMAT> "Write raw frames to stdout (rgba)").required(false))
+        .args(tracing_args())
+        .args(http_args())
+        .args(scripting_args())
+        .subcommand(App::new("keys").about("Open a window to test the key mapping"))
+        .subcommand(
+            App::new("info")
+                .about("Report the interpreter variant and quirks a ROM seems to need")
+                .arg(arg!(<FILE> "File of the CHIP-8 ROM")),
+        )
+        .subcommand(
+            App::new("validate")
+                .about("Scan every word of a ROM for unknown opcodes and stray jumps")
+                .arg(arg!(<FILE> "File of the CHIP-8 ROM")),
+        )
         .get_matches();
 
-    let file_name = matches.value_of("FILE").unwrap();
-    let mut file = File::open(file_name).unwrap();
-    let mut rom = Vec::new();
-    file.read_to_end(&mut rom).unwrap();
+    if let Some(spec) = matches.value_of("dpad") {
+        keyboard::install_dpad(keyboard::parse_dpad(spec).unwrap_or_else(|err| panic!("{}", err)));
+    }
 
-    let clock_speed: u64 = matches.value_of("clock").unwrap().parse().unwrap();
-    if 500 < clock_speed {
-        panic!("Unsupported clock speed: {} Hz", clock_speed);
+    if matches.subcommand_matches("keys").is_some() {
+        init_logger(matches.is_present("verbose"), Vec::new());
+        let mut settings = Settings::with_flags(());
+        settings.window.size = (key_tester::WIDTH as u32, key_tester::HEIGHT as u32);
+        return KeyTester::run(settings).unwrap();
     }
 
+    if let Some(matches) = matches.subcommand_matches("info") {
+        let rom = read_rom(matches.value_of("FILE").unwrap(), None);
+        let findings = analysis::analyze(&rom);
+        if findings.is_empty() {
+            println!("No variant-specific instructions found");
+        }
+        for finding in findings {
+            println!("{}", finding);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        let rom = read_rom(matches.value_of("FILE").unwrap(), None);
+        let validation = analysis::validate(&rom);
+        print!("{}", validation);
+        if validation.has_unknown() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut rom = match (matches.value_of("FILE"), matches.value_of("demo")) {
+        (Some(file_name), _) => read_rom(file_name, matches.value_of("cache-dir")),
+        (None, Some(name)) => demo_rom(name),
+        (None, None) => demo_rom(&choose_demo()),
+    };
+    for file_name in matches.values_of("patch").into_iter().flatten() {
+        let ips = read(file_name).unwrap();
+        patch::apply_ips(&mut rom, &ips).unwrap_or_else(exit_with);
+    }
+    for spec in matches.values_of("patch-bytes").into_iter().flatten() {
+        let bytes = patch::parse_bytes(spec).unwrap_or_else(exit_with);
+        patch::apply_bytes(&mut rom, &bytes).unwrap_or_else(exit_with);
+    }
+    if matches.is_present("validate") {
+        eprint!("{}", analysis::validate(&rom));
+    }
+
+    let mut rom_db = RomDb::bundled();
+    if let Some(file_name) = matches.value_of("rom-db") {
+        let content = read_to_string(file_name).unwrap();
+        rom_db
+            .extend_from_toml(&content)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    let sha1 = Sha1::from(&rom).digest().to_string();
+    let known_rom = rom_db.lookup(&sha1);
+
+    let auto_clock = known_rom
+        .and_then(|entry| entry.clock)
+        .filter(|_| matches.occurrences_of("clock") == 0);
+    let clock_speed = match auto_clock {
+        Some(clock) => clock,
+        None => clock_speed_of(matches.value_of("clock").unwrap()).unwrap(),
+    };
+
+    let timer_hz = timer_hz_of(matches.value_of("timer-hz").unwrap()).unwrap();
+
+    let max_catch_up = matches.value_of("max-catch-up").unwrap();
+    let max_catch_up = match max_catch_up.parse() {
+        Ok(millis) if millis > 0 => Duration::from_millis(millis),
+        _ => panic!("Unsupported catch-up budget: {} ms", max_catch_up),
+    };
+
+    let key_buffer =
+        Duration::from_millis(matches.value_of("key-buffer").unwrap().parse().unwrap());
+
     let color = matches.value_of("color").unwrap();
     let display_color = match color {
         "white" => Color::new(0.95, 0.95, 0.95, 1.0),
@@ -43,21 +269,330 @@ fn main() {
         _ => panic!("Unsupported display color: {}", color),
     };
 
+    let renderer = match matches.value_of("renderer").unwrap() {
+        "canvas" => Renderer::Canvas,
+        "image" => Renderer::Image,
+        other => panic!("Unsupported renderer: {}", other),
+    };
+
+    let pixel_shape = match matches.value_of("pixel-shape").unwrap() {
+        "square" => PixelShape::Square,
+        "circle" => PixelShape::Circle,
+        "dot" => PixelShape::Dot,
+        other => panic!("Unsupported pixel shape: {}", other),
+    };
+
+    let trace_format = match matches.value_of("trace-format").unwrap() {
+        "full" => TraceFormat::Full,
+        "delta" => TraceFormat::Delta,
+        other => panic!("Unsupported trace format: {}", other),
+    };
+
+    let scale_factor: f64 = matches.value_of("scale-factor").unwrap().parse().unwrap();
+    if !(0.5..=4.0).contains(&scale_factor) {
+        panic!("Unsupported scale factor: {}", scale_factor);
+    }
+
+    let ram_init = init_pattern_of(matches.value_of("ram-init").unwrap());
+    let reg_init = init_pattern_of(matches.value_of("reg-init").unwrap());
+    let seed = matches.value_of("seed").map(|seed| seed.parse().unwrap());
+
+    let checksum_every = matches.value_of("checksum-every").map(|n| match n.parse() {
+        Ok(n) if n > 0 => n,
+        _ => panic!("Unsupported checksum interval: {}", n),
+    });
+
+    let max_cycles = matches.value_of("max-cycles").map(|n| match n.parse() {
+        Ok(n) if n > 0 => n,
+        _ => panic!("Unsupported cycle limit: {}", n),
+    });
+
+    let profile_calls = matches
+        .value_of("profile-calls")
+        .map(|format| match format {
+            "text" => CallReport::Text,
+            "dot" => CallReport::Dot,
+            other => panic!("Unsupported call report format: {}", other),
+        });
+
+    let break_opcode = matches.value_of("break-opcode").map(|hex| {
+        u16::from_str_radix(hex.trim_start_matches("0x"), 16)
+            .unwrap_or_else(|_| panic!("Unsupported break opcode: {}", hex))
+    });
+
+    let fast_boot = matches.is_present("fast-boot").then(|| {
+        matches
+            .value_of("fast-boot")
+            .map_or(FAST_BOOT_CAP, |cycles| cycles.parse().unwrap())
+    });
+
+    let profile = match matches.value_of("profile") {
+        Some(profile) => Quirks::of_profile(profile)
+            .unwrap_or_else(|| panic!("Unsupported quirk profile: {}", profile)),
+        None => Quirks::default(),
+    };
+    let flagged = QuirkFlags {
+        shift_from_vy: quirk_switch(&matches, "quirk-shift"),
+        memory_increments_i: quirk_switch(&matches, "quirk-memory"),
+        memory_increments_i_by_x: quirk_switch(&matches, "quirk-memory-x"),
+        jump_with_vx: quirk_switch(&matches, "quirk-jump"),
+        logic_resets_vf: quirk_switch(&matches, "quirk-vf-reset"),
+        clip_sprites: quirk_switch(&matches, "quirk-clip"),
+        display_wait: quirk_switch(&matches, "quirk-display-wait"),
+        index_overflow_sets_vf: quirk_switch(&matches, "quirk-index-overflow"),
+        long_skips: quirk_switch(&matches, "quirk-long-skip"),
+        schip: quirk_switch(&matches, "quirk-schip"),
+        collision_counts_rows: quirk_switch(&matches, "quirk-row-collisions"),
+        half_scroll_in_lores: quirk_switch(&matches, "quirk-half-scroll"),
+        xochip: quirk_switch(&matches, "quirk-xochip"),
+    };
+    let explicit_quirks = matches.is_present("profile") || flagged != QuirkFlags::default();
+    let auto_quirks = romdb::auto_quirks(known_rom, explicit_quirks);
+    let quirks = auto_quirks.unwrap_or(profile).with(flagged);
+
+    let mut cheats = Vec::new();
+    if let Some(file_name) = matches.value_of("cheats") {
+        let content = read_to_string(file_name).unwrap();
+        cheats.extend(cheats::parse_file(&content).unwrap_or_else(|err| panic!("{}", err)));
+    }
+    for poke in matches.values_of("poke").into_iter().flatten() {
+        cheats.push(cheats::parse_poke(poke).unwrap_or_else(|err| panic!("{}", err)));
+    }
+
+    // SCHIP high scores are kept next to the ROM file. Demos and downloaded
+    // ROMs keep them in memory only.
+    let rpl_flags = matches
+        .value_of("FILE")
+        .filter(|file_name| !file_name.contains("://"))
+        .map(|file_name| PathBuf::from(format!("{}.flags", file_name)));
+
+    let pipe_frames = match matches.value_of("pipe-frames") {
+        None => false,
+        Some("rgba") => {
+            eprintln!(
+                "Piping {}x{} RGBA frames at 60 Hz to stdout",
+                display::FRAME_WIDTH,
+                display::FRAME_HEIGHT
+            );
+            true
+        }
+        Some(format) => panic!("Unsupported frame format: {}", format),
+    };
+
     let is_verbose = matches.is_present("verbose");
-    init_logger(is_verbose);
+    let log_filters = matches
+        .values_of("log-filter")
+        .map(|filters| filters.map(log_filter_of).collect())
+        .unwrap_or_default();
+    init_logger(is_verbose, log_filters);
+    shutdown::install_handler();
+    #[cfg(feature = "tracing")]
+    if let Some(file_name) = matches.value_of("tracing-out") {
+        spans::start(file_name);
+    }
+    info!("ROM SHA-1: {}", sha1);
+    if let Some(entry) = known_rom {
+        match auto_quirks {
+            Some(_) => info!(
+                "Auto-selected the {} profile, as the ROM database lists this ROM as {}",
+                entry.profile, entry.title
+            ),
+            None => info!(
+                "Keeping the quirks from the command line over the {} profile the ROM database lists for {}",
+                entry.profile, entry.title
+            ),
+        }
+        if let Some(clock) = auto_clock {
+            info!(
+                "Auto-selected {} Hz, as the ROM database lists for {}",
+                clock, entry.title
+            );
+        }
+    }
+    for finding in analysis::analyze(&rom) {
+        warn!("The ROM {}", finding);
+    }
+    info!(
+        "Clock speed: {} Hz, timer frequency: {} Hz",
+        clock_speed, timer_hz
+    );
 
     let flags = Flags {
         rom,
         clock_speed,
+        timer_hz,
+        key_buffer,
+        max_catch_up,
         display_color,
+        renderer,
+        pixel_shape,
+        scale_factor,
+        cpu_profile: matches.is_present("cpu-profile"),
+        profile_opcodes: matches.is_present("profile-opcodes"),
+        profile_opcodes_out: matches.value_of("profile-opcodes").map(PathBuf::from),
+        coverage: matches.is_present("coverage"),
+        profile_calls,
+        rip_sprites: matches.value_of("rip-sprites").map(PathBuf::from),
+        checksum_every,
+        max_cycles,
+        break_opcode,
+        paranoid: matches.is_present("paranoid"),
+        strict_memory: matches.is_present("strict-memory"),
+        strict_alignment: matches.is_present("strict-alignment"),
+        lint_vf: matches.is_present("lint-vf"),
+        trace_format,
+        quirks,
+        exit_on_terminal_loop: matches.is_present("exit-on-terminal-loop"),
+        fast_boot,
+        #[cfg(feature = "scripting")]
+        script: matches.value_of("script").map(String::from),
+        pipe_frames,
+        ram_init,
+        reg_init,
+        seed,
+        cheats,
+        rpl_flags,
     };
     let mut settings = Settings::with_flags(flags);
-    settings.window.size = (display::WIDTH as u32, display::HEIGHT as u32);
+    settings.window.size = (
+        (display::WIDTH as f64 * scale_factor).round() as u32,
+        (display::HEIGHT as f64 * scale_factor).round() as u32,
+    );
+    settings.exit_on_close_request = false;
     Chip8::run(settings).unwrap()
 }
 
-fn init_logger(is_verbose: bool) {
-    Dispatch::new()
+#[cfg(feature = "tracing")]
+fn tracing_args() -> Vec<Arg<'static>> {
+    vec![arg!(--"tracing-out" <FILE> "Write a Chrome trace of the event loop").required(false)]
+}
+
+#[cfg(not(feature = "tracing"))]
+fn tracing_args() -> Vec<Arg<'static>> {
+    Vec::new()
+}
+
+#[cfg(feature = "http")]
+fn http_args() -> Vec<Arg<'static>> {
+    vec![arg!(--"cache-dir" <DIR> "Keep downloaded ROMs in a directory").required(false)]
+}
+
+#[cfg(not(feature = "http"))]
+fn http_args() -> Vec<Arg<'static>> {
+    Vec::new()
+}
+
+// The --no-quirk-* flags switch off what the profile switched on. Of a
+// pair, the last one given wins.
+fn quirk_switch(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.is_present(name) {
+        Some(true)
+    } else if matches.is_present(format!("no-{}", name)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn scripting_args() -> Vec<Arg<'static>> {
+    vec![arg!(--script <FILE> "Run a Lua script alongside the ROM").required(false)]
+}
+
+#[cfg(not(feature = "scripting"))]
+fn scripting_args() -> Vec<Arg<'static>> {
+    Vec::new()
+}
+
+fn read_rom(file_name: &str, cache_dir: Option<&str>) -> Vec<u8> {
+    let rom = fetch_or_read(file_name, cache_dir);
+    #[cfg(feature = "archives")]
+    let rom = archive::extract(file_name, rom).unwrap_or_else(exit_with);
+    memory::check_rom_size(file_name, &rom).unwrap_or_else(exit_with);
+    rom
+}
+
+#[cfg(feature = "http")]
+fn fetch_or_read(file_name: &str, cache_dir: Option<&str>) -> Vec<u8> {
+    if http::is_url(file_name) {
+        http::fetch(file_name, cache_dir.map(Path::new)).unwrap_or_else(exit_with)
+    } else {
+        read(file_name).unwrap()
+    }
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch_or_read(file_name: &str, _cache_dir: Option<&str>) -> Vec<u8> {
+    read(file_name).unwrap()
+}
+
+fn exit_with<T>(err: String) -> T {
+    eprintln!("{}", err);
+    std::process::exit(1)
+}
+
+fn demo_rom(name: &str) -> Vec<u8> {
+    match demo::find(name) {
+        Some(rom) => rom.to_vec(),
+        None => panic!("Unsupported demo: {}", name),
+    }
+}
+
+// The menu goes to stderr, as stdout may carry the frames of --pipe-frames.
+fn choose_demo() -> String {
+    eprintln!("No ROM file given. Select a demo:");
+    for (index, (name, _)) in demo::DEMOS.iter().enumerate() {
+        eprintln!("  {}) {}", index + 1, name);
+    }
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).unwrap();
+    let answer = answer.trim();
+    match answer.parse::<usize>() {
+        Ok(index) if 1 <= index && index <= demo::DEMOS.len() => {
+            demo::DEMOS[index - 1].0.to_string()
+        }
+        _ => answer.to_string(),
+    }
+}
+
+fn clock_speed_of(hz: &str) -> Result<f64, String> {
+    match hz.parse::<f64>() {
+        Ok(speed) if 0.0 < speed && speed <= MAX_CLOCK_SPEED => Ok(speed),
+        Ok(_) => Err(format!(
+            "{} Hz is out of the range (0, {}]",
+            hz, MAX_CLOCK_SPEED
+        )),
+        Err(_) => Err(format!("{} is not a number", hz)),
+    }
+}
+
+fn timer_hz_of(hz: &str) -> Result<u32, String> {
+    match hz.parse::<u32>() {
+        Ok(hz) if (30..=120).contains(&hz) => Ok(hz),
+        Ok(_) => Err(format!("{} Hz is out of the range 30-120", hz)),
+        Err(_) => Err(format!("{} is not a whole number", hz)),
+    }
+}
+
+fn init_pattern_of(pattern: &str) -> InitPattern {
+    match pattern {
+        "zero" => InitPattern::Zero,
+        "ff" => InitPattern::Ff,
+        "random" => InitPattern::Random,
+        _ => panic!("Unsupported fill pattern: {}", pattern),
+    }
+}
+
+fn log_filter_of(filter: &str) -> (String, LevelFilter) {
+    match filter.split_once('=') {
+        Some((target, level)) => (target.to_string(), level.parse().unwrap()),
+        None => panic!("Unsupported log filter: {}", filter),
+    }
+}
+
+fn init_logger(is_verbose: bool, log_filters: Vec<(String, LevelFilter)>) {
+    let mut dispatch = Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{}[{}][{}] {}",
@@ -76,7 +611,59 @@ fn init_logger(is_verbose: bool) {
                 LevelFilter::Error
             },
         )
-        .chain(stderr())
-        .apply()
-        .unwrap();
+        .level_for(chip8::REPORT_TARGET, LevelFilter::Info);
+    for (target, level) in log_filters {
+        dispatch = dispatch.level_for(target, level);
+    }
+    dispatch.chain(stderr()).apply().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_speed_within_range() {
+        assert_eq!(clock_speed_of("0.5"), Ok(0.5));
+        assert_eq!(clock_speed_of("2000"), Ok(2000.0));
+        assert_eq!(clock_speed_of("10000"), Ok(10000.0));
+        assert!(clock_speed_of("0").is_err());
+        assert!(clock_speed_of("-1").is_err());
+        assert!(clock_speed_of("10001").is_err());
+        assert!(clock_speed_of("NaN").is_err());
+        assert!(clock_speed_of("fast").is_err());
+    }
+
+    #[test]
+    fn timer_hz_within_range() {
+        assert_eq!(timer_hz_of("30"), Ok(30));
+        assert_eq!(timer_hz_of("50"), Ok(50));
+        assert_eq!(timer_hz_of("120"), Ok(120));
+        assert!(timer_hz_of("29").is_err());
+        assert!(timer_hz_of("121").is_err());
+        assert!(timer_hz_of("59.94").is_err());
+    }
+
+    #[test]
+    fn last_quirk_switch_wins() {
+        let switch = |args: &[&str]| {
+            let matches = App::new("chip8")
+                .arg(arg!(--"quirk-clip" "").overrides_with("no-quirk-clip"))
+                .arg(arg!(--"no-quirk-clip" "").overrides_with("quirk-clip"))
+                .get_matches_from(args);
+            quirk_switch(&matches, "quirk-clip")
+        };
+
+        assert_eq!(switch(&["chip8"]), None);
+        assert_eq!(switch(&["chip8", "--quirk-clip"]), Some(true));
+        assert_eq!(switch(&["chip8", "--no-quirk-clip"]), Some(false));
+        assert_eq!(
+            switch(&["chip8", "--no-quirk-clip", "--quirk-clip"]),
+            Some(true)
+        );
+        assert_eq!(
+            switch(&["chip8", "--quirk-clip", "--no-quirk-clip"]),
+            Some(false)
+        );
+    }
 }
@@ -1,6 +1,8 @@
-use log::debug;
+use log::{debug, trace};
+use rand::Rng;
 
-const MEMORY_SIZE: usize = 4096;
+pub const MEMORY_SIZE: usize = 4096;
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - 0x200;
 const FONT_SIZE: u8 = 5;
 
 const FONT: [u8; FONT_SIZE as usize * 16] = [
@@ -22,19 +24,66 @@ const FONT: [u8; FONT_SIZE as usize * 16] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// The 8x10 SCHIP digits for 128x64 mode, in the shapes of Octo's SCHIP font,
+// stored right after the small font.
+const BIG_FONT_SIZE: u8 = 10;
+const BIG_FONT_FROM: u16 = FONT.len() as u16;
+
+const BIG_FONT: [u8; BIG_FONT_SIZE as usize * 16] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+const FONTS_END: u16 = BIG_FONT_FROM + BIG_FONT.len() as u16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum InitPattern {
+    Zero,
+    Ff,
+    Random,
+}
+
+impl InitPattern {
+    pub fn byte(&self, rng: &mut impl Rng) -> u8 {
+        match self {
+            InitPattern::Zero => 0x00,
+            InitPattern::Ff => 0xFF,
+            InitPattern::Random => rng.gen(),
+        }
+    }
+}
+
 pub struct Memory {
     at: [u8; MEMORY_SIZE],
 }
 
 impl Memory {
-    pub fn with_rom(rom: Vec<u8>) -> Self {
-        debug!("Loading ROM: {:?}", rom);
+    pub fn with_rom(rom: Vec<u8>, mut fill: impl FnMut() -> u8) -> Self {
+        trace!("Loading ROM: {:?}", rom);
 
         let mut memory = Memory {
             at: [0x00; MEMORY_SIZE],
         };
 
-        for (font_addr, &b) in FONT.iter().enumerate() {
+        for b in memory.at.iter_mut() {
+            *b = fill();
+        }
+
+        for (font_addr, &b) in FONT.iter().chain(BIG_FONT.iter()).enumerate() {
             memory.at[font_addr] = b;
         }
 
@@ -42,27 +91,74 @@ impl Memory {
         for (offset, &b) in rom.iter().enumerate() {
             memory.at[rom_from + offset] = b;
         }
+        debug!(
+            "Loaded {} bytes of ROM into {:04X}-{:04X}",
+            rom.len(),
+            rom_from,
+            rom_from + rom.len()
+        );
 
         memory
     }
 
+    // Addresses past the end wrap around, as on interpreters that mask them
+    // to 12 bits.
     pub fn load(&self, addr: u16) -> u8 {
-        self.at[addr as usize]
+        self.at[addr as usize % MEMORY_SIZE]
     }
 
     pub fn store(&mut self, addr: u16, value: u8) {
-        self.at[addr as usize] = value;
+        if addr < 0x200 {
+            debug!(
+                "Storing {:02X} into the interpreter area at {:04X}",
+                value, addr
+            );
+        }
+        self.at[addr as usize % MEMORY_SIZE] = value;
+    }
+
+    pub fn load_sprite(&self, from: u16, size: u8) -> Vec<u8> {
+        (0..size as u16)
+            .map(|offset| self.load(from.wrapping_add(offset)))
+            .collect()
+    }
+
+    // SCHIP 16x16 sprites are stored as two bytes per row.
+    pub fn load_sprite16(&self, from: u16, rows: u8) -> Vec<u16> {
+        (0..rows as u16)
+            .map(|row| {
+                let addr = from.wrapping_add(row * 2);
+                u16::from_be_bytes([self.load(addr), self.load(addr.wrapping_add(1))])
+            })
+            .collect()
     }
 
-    pub fn load_sprite(&self, from: u16, size: u8) -> &[u8] {
-        let from = from as usize;
-        let size = size as usize;
-        &self.at[from..from + size]
+    // Between the fonts and the ROM, where the original interpreter kept
+    // its own data.
+    pub fn overlaps_interpreter_area(from: u16, len: u16) -> bool {
+        from < 0x200 && FONTS_END < from.saturating_add(len)
     }
 
     pub fn font_addr(font: u8) -> u16 {
-        (font * FONT_SIZE) as u16
+        font as u16 * FONT_SIZE as u16
+    }
+
+    pub fn big_font_addr(digit: u8) -> u16 {
+        BIG_FONT_FROM + digit as u16 * BIG_FONT_SIZE as u16
+    }
+}
+
+// ROMs load at 0x200 and must fit in the rest of the memory.
+pub fn check_rom_size(file_name: &str, rom: &[u8]) -> Result<(), String> {
+    if MAX_ROM_SIZE < rom.len() {
+        return Err(format!(
+            "ROM too large: {} ({} bytes, at most {} fit in memory)",
+            file_name,
+            rom.len(),
+            MAX_ROM_SIZE
+        ));
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -74,7 +170,7 @@ mod tests {
 
         #[test]
         fn load_stored_value(addr in 0x200u16..MEMORY_SIZE as u16 - 1, value: u8) {
-            let mut memory = Memory::with_rom(vec![]);
+            let mut memory = Memory::with_rom(vec![], || 0x00);
 
             memory.store(addr, value);
             let result = memory.load(addr);
@@ -83,7 +179,7 @@ mod tests {
 
         #[test]
         fn load_overwritten_value(addr in 0x200u16..MEMORY_SIZE as u16 - 1, old: u8, new: u8) {
-            let mut memory = Memory::with_rom(vec![]);
+            let mut memory = Memory::with_rom(vec![], || 0x00);
 
             memory.store(addr, old);
             memory.store(addr, new);
@@ -93,7 +189,7 @@ mod tests {
 
         #[test]
         fn load_stored_sprite(from in 0x200u16..MEMORY_SIZE as u16 - 9, value: u8) {
-            let mut memory = Memory::with_rom(vec![]);
+            let mut memory = Memory::with_rom(vec![], || 0x00);
             let sprite = &[value; 8];
 
             for offset in 0..8 {
@@ -103,14 +199,87 @@ mod tests {
             assert_eq!(result, sprite);
         }
 
+        #[test]
+        fn load_big_sprite_big_endian(from in 0x200u16..MEMORY_SIZE as u16, high: u8, low: u8) {
+            let mut memory = Memory::with_rom(vec![], || 0x00);
+
+            for row in 0..16 {
+                memory.store(from.wrapping_add(row * 2), high);
+                memory.store(from.wrapping_add(row * 2 + 1), low);
+            }
+            let result = memory.load_sprite16(from, 16);
+            assert_eq!(result, vec![u16::from_be_bytes([high, low]); 16]);
+        }
+
+        #[test]
+        fn accesses_wrap_at_the_end(offset in 0u16..0x10, value: u8) {
+            let mut memory = Memory::with_rom(vec![], || 0x00);
+
+            memory.store(MEMORY_SIZE as u16 + offset, value);
+            assert_eq!(memory.load(offset), value);
+            let sprite = memory.load_sprite(MEMORY_SIZE as u16 - 1, offset as u8 + 2);
+            assert_eq!(sprite[1 + offset as usize], value);
+        }
+
+        #[test]
+        fn fill_outside_font_and_rom(value: u8) {
+            let memory = Memory::with_rom(vec![0x12, 0x34], || value);
+
+            assert_eq!(memory.load_sprite(0x000, FONT.len() as u8), &FONT[..]);
+            assert_eq!(memory.load(0x200), 0x12);
+            assert_eq!(memory.load(0x201), 0x34);
+            assert_eq!(memory.load(FONTS_END), value);
+            assert_eq!(memory.load(0x1FF), value);
+            assert_eq!(memory.load(0x202), value);
+            assert_eq!(memory.load(MEMORY_SIZE as u16 - 1), value);
+        }
+
         #[test]
         fn load_font_sprite(font in 0x0u8..0xFu8) {
-            let memory = Memory::with_rom(vec![]);
+            let memory = Memory::with_rom(vec![], || 0x00);
             let from = font as usize * FONT_SIZE as usize;
             let sprite = &FONT[from..from + FONT_SIZE as usize];
 
             let result = memory.load_sprite(Memory::font_addr(font), FONT_SIZE);
             assert_eq!(result, sprite);
         }
+
+        #[test]
+        fn load_big_font_sprite(digit in 0x0u8..=0xFu8) {
+            let memory = Memory::with_rom(vec![], || 0xFF);
+            let from = digit as usize * BIG_FONT_SIZE as usize;
+            let sprite = &BIG_FONT[from..from + BIG_FONT_SIZE as usize];
+
+            let addr = Memory::big_font_addr(digit);
+            assert!(Memory::font_addr(0xF) + FONT_SIZE as u16 <= addr);
+            assert_eq!(memory.load_sprite(addr, BIG_FONT_SIZE), sprite);
+        }
+    }
+
+    #[test]
+    fn interpreter_area_lies_between_font_and_rom() {
+        assert!(!Memory::overlaps_interpreter_area(
+            Memory::font_addr(0xF),
+            FONT_SIZE as u16
+        ));
+        assert!(!Memory::overlaps_interpreter_area(
+            Memory::big_font_addr(0xF),
+            BIG_FONT_SIZE as u16
+        ));
+        assert!(Memory::overlaps_interpreter_area(
+            Memory::big_font_addr(0xF),
+            15
+        ));
+        assert!(Memory::overlaps_interpreter_area(0x1FF, 1));
+        assert!(!Memory::overlaps_interpreter_area(0x200, 15));
+    }
+
+    #[test]
+    fn rom_must_fit_after_the_interpreter_area() {
+        assert!(check_rom_size("full.ch8", &[0x00; MAX_ROM_SIZE]).is_ok());
+        assert_eq!(
+            check_rom_size("huge.ch8", &[0x00; MAX_ROM_SIZE + 1]),
+            Err("ROM too large: huge.ch8 (3585 bytes, at most 3584 fit in memory)".to_string())
+        );
     }
 }
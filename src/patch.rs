@@ -0,0 +1,176 @@
+use crate::memory::MAX_ROM_SIZE;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+
+pub fn apply_ips(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), String> {
+    let mut rest = patch
+        .strip_prefix(IPS_HEADER)
+        .ok_or("Malformed IPS patch: missing PATCH header")?;
+
+    loop {
+        if let Some(truncate) = rest.strip_prefix(IPS_FOOTER) {
+            if truncate.len() == 3 {
+                rom.truncate(be_of(truncate));
+            }
+            return Ok(());
+        }
+
+        let offset = be_of(take(&mut rest, 3)?);
+        let size = be_of(take(&mut rest, 2)?);
+        let data = if size == 0 {
+            let run = be_of(take(&mut rest, 2)?);
+            let value = take(&mut rest, 1)?[0];
+            vec![value; run]
+        } else {
+            take(&mut rest, size)?.to_vec()
+        };
+
+        let end = offset + data.len();
+        if MAX_ROM_SIZE < end {
+            return Err(format!(
+                "IPS record out of the program area: {:#05X}-{:#05X} (at most {} bytes fit in memory)",
+                offset,
+                end - 1,
+                MAX_ROM_SIZE
+            ));
+        }
+        if rom.len() < end {
+            rom.resize(end, 0x00);
+        }
+        rom[offset..end].copy_from_slice(&data);
+    }
+}
+
+pub fn parse_bytes(spec: &str) -> Result<Vec<(usize, u8)>, String> {
+    spec.split(',')
+        .map(|pair| {
+            let (offset, value) = pair
+                .split_once('=')
+                .ok_or(format!("Malformed patch (expected OFFSET=BYTE): {}", pair))?;
+            let offset = match offset.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16).ok(),
+                None => offset.parse().ok(),
+            }
+            .ok_or(format!("Malformed offset: {}", offset))?;
+            let value = u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("Malformed byte: {}", value))?;
+            Ok((offset, value))
+        })
+        .collect()
+}
+
+pub fn apply_bytes(rom: &mut [u8], bytes: &[(usize, u8)]) -> Result<(), String> {
+    for &(offset, value) in bytes {
+        match rom.get_mut(offset) {
+            Some(byte) => *byte = value,
+            None => {
+                return Err(format!(
+                    "Patch offset out of ROM: {:#05X} (ROM is {} bytes)",
+                    offset,
+                    rom.len()
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if rest.len() < len {
+        return Err("Malformed IPS patch: truncated record".to_string());
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Ok(taken)
+}
+
+fn be_of(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |acc, &byte| (acc << 8) | byte as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips_overwrites_record() {
+        let mut rom = vec![0x00; 4];
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAB\xCDEOF";
+
+        apply_ips(&mut rom, patch).unwrap();
+        assert_eq!(rom, vec![0x00, 0xAB, 0xCD, 0x00]);
+    }
+
+    #[test]
+    fn ips_expands_rle_record() {
+        let mut rom = vec![0x00; 4];
+        let patch = b"PATCH\x00\x00\x00\x00\x00\x00\x03\x12EOF";
+
+        apply_ips(&mut rom, patch).unwrap();
+        assert_eq!(rom, vec![0x12, 0x12, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn reject_ips_record_past_program_area() {
+        let mut rom = vec![0x00; 2];
+        let patch = b"PATCH\xFF\xFF\xFF\x00\x00\xFF\xFF\x00EOF";
+
+        assert_eq!(
+            apply_ips(&mut rom, patch),
+            Err("IPS record out of the program area: 0xFFFFFF-0x100FFFD (at most 3584 bytes fit in memory)".to_string())
+        );
+        assert_eq!(rom, vec![0x00; 2]);
+
+        let patch = b"PATCH\x00\x0D\xFF\x00\x01\xABEOF";
+        assert!(apply_ips(&mut rom, patch).is_ok());
+        assert_eq!(rom.len(), MAX_ROM_SIZE);
+    }
+
+    #[test]
+    fn ips_extends_rom() {
+        let mut rom = vec![0x00; 2];
+        let patch = b"PATCH\x00\x00\x03\x00\x01\xFFEOF";
+
+        apply_ips(&mut rom, patch).unwrap();
+        assert_eq!(rom, vec![0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn ips_truncates_rom() {
+        let mut rom = vec![0x00; 4];
+        let patch = b"PATCHEOF\x00\x00\x02";
+
+        apply_ips(&mut rom, patch).unwrap();
+        assert_eq!(rom, vec![0x00; 2]);
+    }
+
+    #[test]
+    fn reject_malformed_ips() {
+        let mut rom = vec![0x00; 4];
+
+        assert!(apply_ips(&mut rom, b"PTCH\x00\x00\x01\x00\x01\xABEOF").is_err());
+        assert!(apply_ips(&mut rom, b"PATCH\x00\x00\x01\x00\x02\xAB").is_err());
+        assert!(apply_ips(&mut rom, b"PATCH").is_err());
+    }
+
+    #[test]
+    fn patch_bytes_within_rom() {
+        let mut rom = vec![0x00; 0x20];
+        let bytes = parse_bytes("0x10=AB,17=CD").unwrap();
+
+        apply_bytes(&mut rom, &bytes).unwrap();
+        assert_eq!(rom[0x10], 0xAB);
+        assert_eq!(rom[0x11], 0xCD);
+    }
+
+    #[test]
+    fn reject_malformed_patch_bytes() {
+        assert!(parse_bytes("0x10").is_err());
+        assert!(parse_bytes("0x10=100").is_err());
+        assert!(parse_bytes("lives=AB").is_err());
+        assert!(apply_bytes(&mut [0x00; 4], &[(4, 0xAB)]).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use crate::instruction::decode;
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+const TOP_ADDRESSES: usize = 20;
+const ENTRY_POINT: u16 = 0x200;
+
+#[derive(Default)]
+struct Entry {
+    count: u64,
+    total: Duration,
+}
+
+pub struct CpuProfile {
+    entries: HashMap<&'static str, Entry>,
+}
+
+impl CpuProfile {
+    pub fn new() -> Self {
+        CpuProfile {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, family: &'static str, elapsed: Duration) {
+        let entry = self.entries.entry(family).or_default();
+        entry.count += 1;
+        entry.total += elapsed;
+    }
+}
+
+impl fmt::Display for CpuProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| Reverse(entry.total));
+
+        writeln!(
+            f,
+            "{:<8}{:>12}{:>14}{:>12}",
+            "OPCODE", "CALLS", "TOTAL (us)", "AVG (ns)"
+        )?;
+        for (family, entry) in entries {
+            writeln!(
+                f,
+                "{:<8}{:>12}{:>14}{:>12}",
+                family,
+                entry.count,
+                entry.total.as_micros(),
+                entry.total.as_nanos() / entry.count as u128
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct OpcodeProfile {
+    families: HashMap<&'static str, u64>,
+    addresses: HashMap<u16, u64>,
+    total: u64,
+}
+
+impl OpcodeProfile {
+    pub fn new() -> Self {
+        OpcodeProfile {
+            families: HashMap::new(),
+            addresses: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, family: &'static str, pc: u16) {
+        *self.families.entry(family).or_default() += 1;
+        *self.addresses.entry(pc).or_default() += 1;
+        self.total += 1;
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total as f64
+    }
+}
+
+// Most frequent first, ties broken by key so that reports are stable.
+fn by_count<K: Copy + Ord>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut counts: Vec<_> = counts.iter().map(|(&key, &count)| (key, count)).collect();
+    counts.sort_by_key(|&(key, count)| (Reverse(count), key));
+    counts
+}
+
+impl fmt::Display for OpcodeProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<8}{:>12}{:>10}", "OPCODE", "COUNT", "PERCENT")?;
+        for (family, count) in by_count(&self.families) {
+            writeln!(f, "{:<8}{:>12}{:>9.2}%", family, count, self.percent(count))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<8}{:>12}{:>10}", "PC", "COUNT", "PERCENT")?;
+        for (pc, count) in by_count(&self.addresses).into_iter().take(TOP_ADDRESSES) {
+            writeln!(
+                f,
+                "{:<8}{:>12}{:>9.2}%",
+                format!("{:04X}", pc),
+                count,
+                self.percent(count)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Executions per opcode family, starting from every family whose encoding
+// appears somewhere in the ROM image, so that unreached ones show up as 0.
+// Data words are decoded like code, since the two cannot be told apart.
+pub struct Coverage {
+    hits: BTreeMap<&'static str, u64>,
+}
+
+impl Coverage {
+    pub fn of_rom(rom: &[u8]) -> Self {
+        let hits = rom
+            .chunks_exact(2)
+            .filter_map(|word| decode(u16::from_be_bytes([word[0], word[1]])))
+            .map(|instruction| (instruction.family(), 0))
+            .collect();
+        Coverage { hits }
+    }
+
+    pub fn record(&mut self, family: &'static str) {
+        *self.hits.entry(family).or_default() += 1;
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (family, hits) in &self.hits {
+            writeln!(f, "{} {}", family, hits)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CallReport {
+    Text,
+    Dot,
+}
+
+#[derive(Default)]
+struct Subroutine {
+    calls: u64,
+    own: u64,
+    total: u64,
+}
+
+// Instructions per subroutine, keyed by the call target, with the code
+// outside any call counted under the entry point. The shadow stack is cut
+// back to SP on every CALL and RET, so returns that do not match a call
+// cannot push it out of step with the real stack.
+pub struct CallProfile {
+    report: CallReport,
+    subroutines: HashMap<u16, Subroutine>,
+    edges: HashMap<(u16, u16), u64>,
+    frames: Vec<(u16, u64)>,
+    executed: u64,
+}
+
+impl CallProfile {
+    pub fn new(report: CallReport) -> Self {
+        CallProfile {
+            report,
+            subroutines: HashMap::new(),
+            edges: HashMap::new(),
+            frames: Vec::new(),
+            executed: 0,
+        }
+    }
+
+    fn current(&self) -> u16 {
+        self.frames
+            .last()
+            .map_or(ENTRY_POINT, |&(target, _)| target)
+    }
+
+    pub fn record_instruction(&mut self) {
+        self.executed += 1;
+        self.subroutines.entry(self.current()).or_default().own += 1;
+    }
+
+    pub fn record_call(&mut self, target: u16, depth: usize) {
+        self.unwind(depth.saturating_sub(1));
+        *self.edges.entry((self.current(), target)).or_default() += 1;
+        self.subroutines.entry(target).or_default().calls += 1;
+        self.frames.push((target, self.executed));
+    }
+
+    pub fn record_return(&mut self, depth: usize) {
+        self.unwind(depth);
+    }
+
+    // A recursive subroutine adds to its total only when its outermost
+    // activation returns, so that nested activations are not counted twice.
+    fn unwind(&mut self, depth: usize) {
+        while depth < self.frames.len() {
+            let (target, entered) = self.frames.pop().unwrap();
+            if self.frames.iter().all(|&(other, _)| other != target) {
+                self.subroutines.entry(target).or_default().total += self.executed - entered;
+            }
+        }
+    }
+
+    // Totals as if every open call returned now.
+    fn totals(&self) -> HashMap<u16, u64> {
+        let mut totals: HashMap<_, _> = self
+            .subroutines
+            .iter()
+            .map(|(&target, subroutine)| (target, subroutine.total))
+            .collect();
+        for (depth, &(target, entered)) in self.frames.iter().enumerate() {
+            if self.frames[..depth]
+                .iter()
+                .all(|&(other, _)| other != target)
+            {
+                *totals.entry(target).or_default() += self.executed - entered;
+            }
+        }
+        if self.executed > 0 {
+            totals.insert(ENTRY_POINT, self.executed);
+        }
+        totals
+    }
+
+    fn write_text(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let totals = self.totals();
+        writeln!(
+            f,
+            "{:<8}{:>10}{:>12}{:>12}",
+            "SUB", "CALLS", "SELF", "TOTAL"
+        )?;
+        for (target, total) in by_count(&totals) {
+            let subroutine = &self.subroutines[&target];
+            writeln!(
+                f,
+                "{:<8}{:>10}{:>12}{:>12}",
+                format!("{:04X}", target),
+                subroutine.calls,
+                subroutine.own,
+                total
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<8}{:<8}{:>10}", "CALLER", "CALLEE", "CALLS")?;
+        for ((caller, callee), calls) in by_count(&self.edges) {
+            writeln!(
+                f,
+                "{:<8}{:<8}{:>10}",
+                format!("{:04X}", caller),
+                format!("{:04X}", callee),
+                calls
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_dot(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let totals = self.totals();
+        writeln!(f, "digraph calls {{")?;
+        for (target, total) in by_count(&totals) {
+            let subroutine = &self.subroutines[&target];
+            writeln!(
+                f,
+                "    \"{:04X}\" [label=\"{:04X}\\ncalls {}, self {}, total {}\"];",
+                target, target, subroutine.calls, subroutine.own, total
+            )?;
+        }
+        for ((caller, callee), calls) in by_count(&self.edges) {
+            writeln!(
+                f,
+                "    \"{:04X}\" -> \"{:04X}\" [label=\"{}\"];",
+                caller, callee, calls
+            )?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for CallProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.report {
+            CallReport::Text => self.write_text(f),
+            CallReport::Dot => self.write_dot(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_accumulates_per_family() {
+        let mut profile = CpuProfile::new();
+
+        profile.record("DXYN", Duration::from_nanos(300));
+        profile.record("DXYN", Duration::from_nanos(500));
+        profile.record("6XKK", Duration::from_nanos(20));
+        let dxyn = &profile.entries["DXYN"];
+        assert_eq!(dxyn.count, 2);
+        assert_eq!(dxyn.total, Duration::from_nanos(800));
+        assert_eq!(profile.entries["6XKK"].count, 1);
+    }
+
+    #[test]
+    fn report_sorted_by_total_time() {
+        let mut profile = CpuProfile::new();
+
+        profile.record("6XKK", Duration::from_micros(1));
+        profile.record("DXYN", Duration::from_micros(5));
+        let report = profile.to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("DXYN"));
+        assert!(lines[2].starts_with("6XKK"));
+    }
+
+    #[test]
+    fn opcode_report_sorted_by_count() {
+        let mut profile = OpcodeProfile::new();
+
+        profile.record("6XKK", 0x200);
+        profile.record("1NNN", 0x202);
+        profile.record("6XKK", 0x200);
+        profile.record("6XKK", 0x204);
+        let report = profile.to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[1], "6XKK               3    75.00%");
+        assert_eq!(lines[2], "1NNN               1    25.00%");
+        assert_eq!(lines[5], "0200               2    50.00%");
+        assert_eq!(lines[6], "0202               1    25.00%");
+        assert_eq!(lines[7], "0204               1    25.00%");
+    }
+
+    #[test]
+    fn coverage_lists_unreached_families() {
+        // 0200: LD V0 1, 0202: JP 0206, 0204: ADD V0 V1, 0206: JP 0206
+        let mut coverage = Coverage::of_rom(&[0x60, 0x01, 0x12, 0x06, 0x80, 0x14, 0x12, 0x06]);
+
+        coverage.record("6XKK");
+        coverage.record("1NNN");
+        coverage.record("1NNN");
+        assert_eq!(coverage.to_string(), "1NNN 2\n6XKK 1\n8XY4 0\n");
+    }
+
+    #[test]
+    fn recursion_counted_once_in_totals() {
+        let mut profile = CallProfile::new(CallReport::Text);
+
+        // 0200: CALL 0300, 0300: CALL 0300, 0300: CALL 0300, then RET x3
+        profile.record_instruction();
+        profile.record_call(0x300, 1);
+        profile.record_instruction();
+        profile.record_call(0x300, 2);
+        profile.record_instruction();
+        profile.record_call(0x300, 3);
+        for depth in (0..3).rev() {
+            profile.record_instruction();
+            profile.record_return(depth);
+        }
+        // A RET without a CALL leaves the shadow stack empty.
+        profile.record_instruction();
+        profile.record_return(0);
+        let report = profile.to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[1], "0200             0           2           7");
+        assert_eq!(lines[2], "0300             3           5           5");
+        assert_eq!(lines[5], "0300    0300             2");
+        assert_eq!(lines[6], "0200    0300             1");
+    }
+
+    #[test]
+    fn empty_call_report() {
+        let profile = CallProfile::new(CallReport::Dot);
+
+        assert_eq!(profile.to_string(), "digraph calls {\n}\n");
+    }
+
+    #[test]
+    fn dot_report_has_nodes_and_edges() {
+        let mut profile = CallProfile::new(CallReport::Dot);
+
+        profile.record_instruction();
+        profile.record_call(0x300, 1);
+        profile.record_instruction();
+        assert_eq!(
+            profile.to_string(),
+            "digraph calls {\n    \"0200\" [label=\"0200\\ncalls 0, self 1, total 2\"];\n    \"0300\" [label=\"0300\\ncalls 1, self 1, total 1\"];\n    \"0200\" -> \"0300\" [label=\"1\"];\n}\n"
+        );
+    }
+}
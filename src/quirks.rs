@@ -0,0 +1,163 @@
+// Behaviours on which CHIP-8 interpreters disagree. The defaults keep the
+// semantics this emulator has always had.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quirks {
+    pub shift_from_vy: bool,
+    pub memory_increments_i: bool,
+    pub memory_increments_i_by_x: bool,
+    pub jump_with_vx: bool,
+    pub logic_resets_vf: bool,
+    pub clip_sprites: bool,
+    pub display_wait: bool,
+    pub index_overflow_sets_vf: bool,
+    pub long_skips: bool,
+    pub schip: bool,
+    pub collision_counts_rows: bool,
+    pub half_scroll_in_lores: bool,
+    pub xochip: bool,
+}
+
+impl Quirks {
+    // The platforms as tabulated by the Timendus CHIP-8 test suite.
+    pub fn of_profile(profile: &str) -> Option<Self> {
+        let quirks = match profile {
+            "chip8" => Quirks {
+                shift_from_vy: true,
+                memory_increments_i: true,
+                logic_resets_vf: true,
+                clip_sprites: true,
+                display_wait: true,
+                ..Quirks::default()
+            },
+            "chip48" => Quirks {
+                memory_increments_i_by_x: true,
+                jump_with_vx: true,
+                clip_sprites: true,
+                ..Quirks::default()
+            },
+            "superchip" => Quirks {
+                jump_with_vx: true,
+                clip_sprites: true,
+                schip: true,
+                collision_counts_rows: true,
+                half_scroll_in_lores: true,
+                ..Quirks::default()
+            },
+            "xochip" => Quirks {
+                shift_from_vy: true,
+                memory_increments_i: true,
+                long_skips: true,
+                schip: true,
+                xochip: true,
+                ..Quirks::default()
+            },
+            _ => return None,
+        };
+        Some(quirks)
+    }
+
+    // Each quirk flag overrides the profile, which decides the quirks left
+    // off the command line.
+    pub fn with(self, flags: QuirkFlags) -> Self {
+        Quirks {
+            shift_from_vy: flags.shift_from_vy.unwrap_or(self.shift_from_vy),
+            memory_increments_i: flags
+                .memory_increments_i
+                .unwrap_or(self.memory_increments_i),
+            memory_increments_i_by_x: flags
+                .memory_increments_i_by_x
+                .unwrap_or(self.memory_increments_i_by_x),
+            jump_with_vx: flags.jump_with_vx.unwrap_or(self.jump_with_vx),
+            logic_resets_vf: flags.logic_resets_vf.unwrap_or(self.logic_resets_vf),
+            clip_sprites: flags.clip_sprites.unwrap_or(self.clip_sprites),
+            display_wait: flags.display_wait.unwrap_or(self.display_wait),
+            index_overflow_sets_vf: flags
+                .index_overflow_sets_vf
+                .unwrap_or(self.index_overflow_sets_vf),
+            long_skips: flags.long_skips.unwrap_or(self.long_skips),
+            schip: flags.schip.unwrap_or(self.schip),
+            collision_counts_rows: flags
+                .collision_counts_rows
+                .unwrap_or(self.collision_counts_rows),
+            half_scroll_in_lores: flags
+                .half_scroll_in_lores
+                .unwrap_or(self.half_scroll_in_lores),
+            xochip: flags.xochip.unwrap_or(self.xochip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuirkFlags {
+    pub shift_from_vy: Option<bool>,
+    pub memory_increments_i: Option<bool>,
+    pub memory_increments_i_by_x: Option<bool>,
+    pub jump_with_vx: Option<bool>,
+    pub logic_resets_vf: Option<bool>,
+    pub clip_sprites: Option<bool>,
+    pub display_wait: Option<bool>,
+    pub index_overflow_sets_vf: Option<bool>,
+    pub long_skips: Option<bool>,
+    pub schip: Option<bool>,
+    pub collision_counts_rows: Option<bool>,
+    pub half_scroll_in_lores: Option<bool>,
+    pub xochip: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_expand_to_their_quirks() {
+        let chip8 = Quirks::of_profile("chip8").unwrap();
+        assert!(chip8.shift_from_vy && chip8.memory_increments_i && chip8.logic_resets_vf);
+        assert!(chip8.clip_sprites && chip8.display_wait);
+        assert!(!chip8.jump_with_vx && !chip8.index_overflow_sets_vf && !chip8.long_skips);
+
+        for profile in ["chip48", "superchip"] {
+            let schip = Quirks::of_profile(profile).unwrap();
+            assert!(schip.jump_with_vx && schip.clip_sprites);
+            assert!(!schip.shift_from_vy && !schip.memory_increments_i);
+            assert!(!schip.logic_resets_vf && !schip.display_wait);
+        }
+        let chip48 = Quirks::of_profile("chip48").unwrap();
+        let schip = Quirks::of_profile("superchip").unwrap();
+        assert!(chip48.memory_increments_i_by_x && !schip.memory_increments_i_by_x);
+        assert!(!chip48.schip && schip.schip);
+        assert!(!chip48.collision_counts_rows && schip.collision_counts_rows);
+
+        let xochip = Quirks::of_profile("xochip").unwrap();
+        assert!(xochip.shift_from_vy && xochip.memory_increments_i && xochip.long_skips);
+        assert!(xochip.schip && !chip8.schip);
+        assert!(xochip.xochip && !schip.xochip);
+        assert!(!xochip.collision_counts_rows && !xochip.half_scroll_in_lores);
+        assert!(schip.half_scroll_in_lores);
+        assert!(!xochip.jump_with_vx && !xochip.logic_resets_vf);
+        assert!(!xochip.clip_sprites && !xochip.display_wait);
+
+        assert_eq!(Quirks::of_profile("chip-9"), None);
+    }
+
+    #[test]
+    fn flags_override_the_profile() {
+        let flags = QuirkFlags {
+            jump_with_vx: Some(true),
+            clip_sprites: Some(false),
+            logic_resets_vf: Some(true),
+            ..QuirkFlags::default()
+        };
+        let quirks = Quirks::of_profile("chip8").unwrap().with(flags);
+
+        assert!(quirks.jump_with_vx && !quirks.clip_sprites && quirks.logic_resets_vf);
+        assert!(quirks.shift_from_vy && quirks.display_wait);
+        assert_eq!(
+            Quirks::default().with(flags),
+            Quirks {
+                jump_with_vx: true,
+                logic_resets_vf: true,
+                ..Quirks::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use png::{BitDepth, ColorType, Encoder};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const SCALE: usize = 8;
+
+struct Sprite {
+    bytes: Vec<u8>,
+    address: u16,
+    draws: u64,
+}
+
+pub struct SpriteRipper {
+    out_dir: PathBuf,
+    lit: [u8; 4],
+    unlit: [u8; 4],
+    sprites: Vec<Sprite>,
+    index_of: HashMap<Vec<u8>, usize>,
+}
+
+impl SpriteRipper {
+    pub fn new(out_dir: PathBuf, (lit, unlit): ([u8; 4], [u8; 4])) -> Self {
+        SpriteRipper {
+            out_dir,
+            lit,
+            unlit,
+            sprites: Vec::new(),
+            index_of: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, address: u16, sprite: &[u8]) {
+        if sprite.is_empty() {
+            return;
+        }
+        match self.index_of.get(sprite) {
+            Some(&index) => self.sprites[index].draws += 1,
+            None => {
+                self.index_of.insert(sprite.to_vec(), self.sprites.len());
+                self.sprites.push(Sprite {
+                    bytes: sprite.to_vec(),
+                    address,
+                    draws: 1,
+                });
+            }
+        }
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        create_dir_all(&self.out_dir)?;
+        let mut index = File::create(self.out_dir.join("index.txt"))?;
+        writeln!(
+            index,
+            "{:<16}{:>8}{:>8}{:>8}",
+            "FILE", "ADDRESS", "HEIGHT", "DRAWS"
+        )?;
+        for (number, sprite) in self.sprites.iter().enumerate() {
+            let file_name = format!("sprite_{:03}.png", number);
+            self.write_png(&file_name, &sprite.bytes)?;
+            writeln!(
+                index,
+                "{:<16}{:>8}{:>8}{:>8}",
+                file_name,
+                format!("{:04X}", sprite.address),
+                sprite.bytes.len(),
+                sprite.draws
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_png(&self, file_name: &str, bytes: &[u8]) -> io::Result<()> {
+        let (width, height) = (8 * SCALE, bytes.len() * SCALE);
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for py in 0..height {
+            for px in 0..width {
+                let is_lit = (bytes[py / SCALE] >> (7 - px / SCALE)) % 2 == 1;
+                pixels.extend_from_slice(if is_lit { &self.lit } else { &self.unlit });
+            }
+        }
+
+        write_png(&self.out_dir.join(file_name), width, height, &pixels)
+    }
+}
+
+pub fn write_png(path: &Path, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(pixels))
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::read_to_string;
+
+    const PALETTE: ([u8; 4], [u8; 4]) = ([0xFF; 4], [0x00, 0x00, 0x00, 0xFF]);
+
+    #[test]
+    fn record_deduplicates_by_content() {
+        let mut ripper = SpriteRipper::new(temp_dir(), PALETTE);
+
+        ripper.record(0x21E, &[0x80, 0x40]);
+        ripper.record(0x300, &[0x80, 0x40]);
+        ripper.record(0x222, &[0x80]);
+        ripper.record(0x222, &[]);
+        assert_eq!(ripper.sprites.len(), 2);
+        assert_eq!(ripper.sprites[0].address, 0x21E);
+        assert_eq!(ripper.sprites[0].draws, 2);
+        assert_eq!(ripper.sprites[1].draws, 1);
+    }
+
+    #[test]
+    fn write_pngs_and_index() {
+        let out_dir = temp_dir().join(format!("chip8-rip-{}", std::process::id()));
+        let mut ripper = SpriteRipper::new(out_dir.clone(), PALETTE);
+
+        ripper.record(0x21E, &[0x80, 0x40, 0x20, 0x10]);
+        ripper.record(0x21E, &[0x80, 0x40, 0x20, 0x10]);
+        ripper.write().unwrap();
+        let index = read_to_string(out_dir.join("index.txt")).unwrap();
+        let lines: Vec<_> = index.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            vec!["sprite_000.png", "021E", "4", "2"]
+        );
+        assert!(out_dir.join("sprite_000.png").is_file());
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+}
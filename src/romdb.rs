@@ -0,0 +1,161 @@
+use crate::quirks::Quirks;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// SHA-1, title, quirk profile and clock speed of the ROMs known to run
+// best with particular settings. Entries from the community database can
+// be added to a user file in the same shape as RomFile below.
+const BUNDLED: [(&str, &str, &str, Option<f64>); 2] = [
+    (
+        "b8889ed4513108833d3f3bf31bc5ff00ba9534e4",
+        "Maze (demo)",
+        "chip8",
+        None,
+    ),
+    (
+        "2b9e168df3790305580915f8dec43e0a26c80f87",
+        "Keypad (demo)",
+        "chip8",
+        None,
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Entry {
+    pub sha1: String,
+    pub title: String,
+    pub profile: String,
+    pub clock: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RomFile {
+    rom: Vec<Entry>,
+}
+
+pub struct RomDb {
+    entries: HashMap<String, Entry>,
+}
+
+impl RomDb {
+    pub fn bundled() -> Self {
+        let entries = BUNDLED
+            .iter()
+            .map(|&(sha1, title, profile, clock)| Entry {
+                sha1: sha1.to_string(),
+                title: title.to_string(),
+                profile: profile.to_string(),
+                clock,
+            })
+            .map(|entry| (entry.sha1.clone(), entry))
+            .collect();
+        RomDb { entries }
+    }
+
+    // Entries in the file replace the bundled ones with the same hash.
+    pub fn extend_from_toml(&mut self, content: &str) -> Result<(), String> {
+        let file: RomFile = toml::from_str(content).map_err(|err| err.to_string())?;
+        for mut entry in file.rom {
+            if Quirks::of_profile(&entry.profile).is_none() {
+                return Err(format!(
+                    "Unsupported quirk profile for {}: {}",
+                    entry.title, entry.profile
+                ));
+            }
+            entry.sha1 = entry.sha1.to_lowercase();
+            self.entries.insert(entry.sha1.clone(), entry);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<&Entry> {
+        self.entries.get(sha1)
+    }
+}
+
+// The profile of a known ROM applies unless the command line picked the
+// quirks itself.
+pub fn auto_quirks(entry: Option<&Entry>, explicit: bool) -> Option<Quirks> {
+    if explicit {
+        return None;
+    }
+    entry.and_then(|entry| Quirks::of_profile(&entry.profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo::DEMOS;
+    use sha1_smol::Sha1;
+
+    #[test]
+    fn bundled_demos_are_found() {
+        let db = RomDb::bundled();
+
+        for (_, rom) in DEMOS {
+            let entry = db.lookup(&Sha1::from(rom).digest().to_string()).unwrap();
+            assert!(Quirks::of_profile(&entry.profile).is_some());
+        }
+        assert_eq!(db.lookup("0000000000000000000000000000000000000000"), None);
+    }
+
+    #[test]
+    fn user_entries_extend_and_replace() {
+        let mut db = RomDb::bundled();
+        db.extend_from_toml(
+            r#"
+            [[rom]]
+            sha1 = "B8889ED4513108833D3F3BF31BC5FF00BA9534E4"
+            title = "Faster maze"
+            profile = "superchip"
+            clock = 1000.0
+
+            [[rom]]
+            sha1 = "1234567890123456789012345678901234567890"
+            title = "Blinky"
+            profile = "chip8"
+            "#,
+        )
+        .unwrap();
+
+        let maze = db
+            .lookup("b8889ed4513108833d3f3bf31bc5ff00ba9534e4")
+            .unwrap();
+        assert_eq!(
+            (maze.profile.as_str(), maze.clock),
+            ("superchip", Some(1000.0))
+        );
+        let blinky = db
+            .lookup("1234567890123456789012345678901234567890")
+            .unwrap();
+        assert_eq!((blinky.title.as_str(), blinky.clock), ("Blinky", None));
+    }
+
+    #[test]
+    fn unknown_profiles_are_rejected() {
+        let result = RomDb::bundled().extend_from_toml(
+            r#"
+            [[rom]]
+            sha1 = "1234567890123456789012345678901234567890"
+            title = "Blinky"
+            profile = "chip-9"
+            "#,
+        );
+
+        assert_eq!(
+            result,
+            Err("Unsupported quirk profile for Blinky: chip-9".to_string())
+        );
+    }
+
+    #[test]
+    fn command_line_quirks_take_precedence() {
+        let db = RomDb::bundled();
+        let entry = db.lookup("b8889ed4513108833d3f3bf31bc5ff00ba9534e4");
+
+        assert_eq!(auto_quirks(entry, false), Quirks::of_profile("chip8"));
+        assert_eq!(auto_quirks(entry, true), None);
+        assert_eq!(auto_quirks(None, false), None);
+    }
+}
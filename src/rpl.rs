@@ -0,0 +1,98 @@
+use log::warn;
+use std::fs::{read, write};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+pub const RPL_FLAGS: usize = 8;
+
+// The HP-48 user flags that SCHIP programs save with FX75, usually high
+// scores. Each store is written through to the file, and any failure turns
+// the store into a memory-only one.
+pub struct RplFlags {
+    values: [u8; RPL_FLAGS],
+    path: Option<PathBuf>,
+}
+
+impl RplFlags {
+    pub fn in_memory() -> Self {
+        RplFlags {
+            values: [0; RPL_FLAGS],
+            path: None,
+        }
+    }
+
+    pub fn open(path: PathBuf) -> Self {
+        let mut values = [0; RPL_FLAGS];
+        match read(&path) {
+            Ok(bytes) => {
+                let len = bytes.len().min(RPL_FLAGS);
+                values[..len].copy_from_slice(&bytes[..len]);
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                warn!(
+                    "Failed to read the RPL flags from {}, keeping them in memory: {}",
+                    path.display(),
+                    err
+                );
+                return RplFlags::in_memory();
+            }
+        }
+        RplFlags {
+            values,
+            path: Some(path),
+        }
+    }
+
+    pub fn load(&self, count: usize) -> &[u8] {
+        &self.values[..count.min(RPL_FLAGS)]
+    }
+
+    pub fn store(&mut self, values: &[u8]) {
+        let len = values.len().min(RPL_FLAGS);
+        self.values[..len].copy_from_slice(&values[..len]);
+        if let Some(path) = &self.path {
+            if let Err(err) = write(path, self.values) {
+                warn!(
+                    "Failed to write the RPL flags to {}, keeping them in memory: {}",
+                    path.display(),
+                    err
+                );
+                self.path = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    #[test]
+    fn flags_survive_reopening() {
+        let path = temp_dir().join(format!("chip8-rpl-{}.flags", std::process::id()));
+
+        let mut flags = RplFlags::open(path.clone());
+        assert_eq!(flags.load(8), &[0; 8]);
+        flags.store(&[0x12, 0x34, 0x56]);
+        assert_eq!(read(&path).unwrap(), vec![0x12, 0x34, 0x56, 0, 0, 0, 0, 0]);
+
+        let reopened = RplFlags::open(path.clone());
+        assert_eq!(reopened.load(3), &[0x12, 0x34, 0x56]);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unwritable_files_fall_back_to_memory() {
+        let path = temp_dir()
+            .join(format!("chip8-rpl-missing-{}", std::process::id()))
+            .join("rom.flags");
+
+        let mut flags = RplFlags::open(path.clone());
+        flags.store(&[0xAB; 10]);
+        assert_eq!(flags.load(10), &[0xAB; 8]);
+        assert!(flags.path.is_none() && !path.exists());
+    }
+}
@@ -0,0 +1,194 @@
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue};
+use std::cell::RefCell;
+use std::fs::read_to_string;
+
+pub trait ScriptHost {
+    fn peek(&self, addr: u16) -> Result<u8, String>;
+    fn poke(&mut self, addr: u16, value: u8) -> Result<(), String>;
+    fn get_reg(&self, x: u8) -> Result<u8, String>;
+    fn set_reg(&mut self, x: u8, value: u8) -> Result<(), String>;
+    fn press(&mut self, key: u8) -> Result<(), String>;
+    fn release(&mut self, key: u8) -> Result<(), String>;
+    fn screenshot(&self, path: &str) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Hook {
+    Frame,
+    Instruction(u16, u16),
+    Draw(u8, u8, u8),
+}
+
+pub struct Script {
+    lua: Lua,
+}
+
+impl Script {
+    pub fn load(file_name: &str) -> Result<Self, String> {
+        let source = read_to_string(file_name).map_err(|err| err.to_string())?;
+        Script::from_source(&source, file_name)
+    }
+
+    fn from_source(source: &str, name: &str) -> Result<Self, String> {
+        let lua = Lua::new();
+        lua.load(source)
+            .set_name(name)
+            .exec()
+            .map_err(|err| err.to_string())?;
+        Ok(Script { lua })
+    }
+
+    // The API functions only exist while a hook runs, so that every call
+    // sees the machine in the state the hook was invoked with.
+    pub fn run(&self, host: &mut impl ScriptHost, hook: Hook) -> Result<(), String> {
+        let (name, args) = match hook {
+            Hook::Frame => ("on_frame", ().into_lua_multi(&self.lua)),
+            Hook::Instruction(pc, opcode) => {
+                ("on_instruction", (pc, opcode).into_lua_multi(&self.lua))
+            }
+            Hook::Draw(x, y, n) => ("on_draw", (x, y, n).into_lua_multi(&self.lua)),
+        };
+        let callback: Option<Function> = self
+            .lua
+            .globals()
+            .get(name)
+            .map_err(|err| err.to_string())?;
+        let callback = match callback {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        let args = args.map_err(|err| err.to_string())?;
+
+        let host = RefCell::new(host);
+        let api_error = |err: String| mlua::Error::RuntimeError(err);
+        self.lua
+            .scope(|scope| {
+                let globals = self.lua.globals();
+                globals.set(
+                    "peek",
+                    scope.create_function(|_, addr: u16| {
+                        host.borrow().peek(addr).map_err(api_error)
+                    })?,
+                )?;
+                globals.set(
+                    "poke",
+                    scope.create_function(|_, (addr, value): (u16, u8)| {
+                        host.borrow_mut().poke(addr, value).map_err(api_error)
+                    })?,
+                )?;
+                globals.set(
+                    "get_reg",
+                    scope
+                        .create_function(|_, x: u8| host.borrow().get_reg(x).map_err(api_error))?,
+                )?;
+                globals.set(
+                    "set_reg",
+                    scope.create_function(|_, (x, value): (u8, u8)| {
+                        host.borrow_mut().set_reg(x, value).map_err(api_error)
+                    })?,
+                )?;
+                globals.set(
+                    "press",
+                    scope.create_function(|_, key: u8| {
+                        host.borrow_mut().press(key).map_err(api_error)
+                    })?,
+                )?;
+                globals.set(
+                    "release",
+                    scope.create_function(|_, key: u8| {
+                        host.borrow_mut().release(key).map_err(api_error)
+                    })?,
+                )?;
+                globals.set(
+                    "screenshot",
+                    scope.create_function(|_, path: String| {
+                        host.borrow().screenshot(&path).map_err(api_error)
+                    })?,
+                )?;
+                callback.call::<MultiValue, ()>(args)
+            })
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeHost {
+        memory: Vec<u8>,
+        v: [u8; 16],
+        pressed: Vec<u8>,
+    }
+
+    impl ScriptHost for FakeHost {
+        fn peek(&self, addr: u16) -> Result<u8, String> {
+            self.memory
+                .get(addr as usize)
+                .copied()
+                .ok_or("out".to_string())
+        }
+        fn poke(&mut self, addr: u16, value: u8) -> Result<(), String> {
+            self.memory[addr as usize] = value;
+            Ok(())
+        }
+        fn get_reg(&self, x: u8) -> Result<u8, String> {
+            Ok(self.v[x as usize])
+        }
+        fn set_reg(&mut self, x: u8, value: u8) -> Result<(), String> {
+            self.v[x as usize] = value;
+            Ok(())
+        }
+        fn press(&mut self, key: u8) -> Result<(), String> {
+            self.pressed.push(key);
+            Ok(())
+        }
+        fn release(&mut self, key: u8) -> Result<(), String> {
+            self.pressed.retain(|&pressed| pressed != key);
+            Ok(())
+        }
+        fn screenshot(&self, _path: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hooks_see_the_host() {
+        let script = Script::from_source(
+            r#"
+            function on_instruction(pc, opcode)
+                set_reg(1, peek(pc) + get_reg(0))
+                poke(pc, opcode % 256)
+            end
+            function on_frame()
+                press(5)
+            end
+            "#,
+            "test",
+        )
+        .unwrap();
+        let mut host = FakeHost {
+            memory: vec![0x10, 0x20],
+            ..FakeHost::default()
+        };
+        host.v[0] = 0x02;
+
+        script.run(&mut host, Hook::Instruction(0, 0x00E0)).unwrap();
+        script.run(&mut host, Hook::Frame).unwrap();
+        script.run(&mut host, Hook::Draw(0, 0, 5)).unwrap();
+        assert_eq!(host.v[1], 0x12);
+        assert_eq!(host.memory[0], 0xE0);
+        assert_eq!(host.pressed, vec![5]);
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        assert!(Script::from_source("function on_frame(", "test").is_err());
+
+        let script = Script::from_source("function on_frame() peek(100) end", "test").unwrap();
+        let mut host = FakeHost::default();
+        let err = script.run(&mut host, Hook::Frame).unwrap_err();
+        assert!(err.contains("out"));
+    }
+}
@@ -0,0 +1,22 @@
+use log::info;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const EXIT_CODE: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install_handler() {
+    ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            eprintln!("Interrupted again, quitting immediately");
+            process::exit(EXIT_CODE);
+        }
+        info!("Interrupted, shutting down");
+    })
+    .unwrap();
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,60 @@
+#[cfg(feature = "tracing")]
+use std::sync::Mutex;
+#[cfg(feature = "tracing")]
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+#[cfg(feature = "tracing")]
+use tracing_subscriber::prelude::*;
+
+// Enters a tracing span until the end of the enclosing block, and expands to
+// nothing unless the tracing feature is enabled.
+macro_rules! span {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!($($arg)*).entered();
+    };
+}
+pub(crate) use span;
+
+// iced exits the process from inside its event loop, so the guard is kept
+// here and dropped explicitly to flush the trace file.
+#[cfg(feature = "tracing")]
+static FLUSH_GUARD: Mutex<Option<FlushGuard>> = Mutex::new(None);
+
+#[cfg(feature = "tracing")]
+pub fn start(file_name: &str) {
+    let (layer, guard) = ChromeLayerBuilder::new().file(file_name).build();
+    tracing_subscriber::registry().with(layer).init();
+    *FLUSH_GUARD.lock().unwrap() = Some(guard);
+}
+
+pub fn finish() {
+    #[cfg(feature = "tracing")]
+    FLUSH_GUARD.lock().unwrap().take();
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{read_to_string, remove_file};
+
+    #[test]
+    fn spans_written_as_chrome_trace() {
+        let file_name = temp_dir().join(format!("chip8-trace-{}.json", std::process::id()));
+        let (layer, guard) = ChromeLayerBuilder::new().file(&file_name).build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            span!("update");
+            for _ in 0..3 {
+                span!("step");
+            }
+        });
+        drop(guard);
+        let trace = read_to_string(&file_name).unwrap();
+        assert!(trace.trim_start().starts_with('['));
+        assert_eq!(trace.matches("\"name\":\"update\"").count(), 2);
+        assert_eq!(trace.matches("\"name\":\"step\"").count(), 6);
+        remove_file(file_name).unwrap();
+    }
+}